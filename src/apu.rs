@@ -9,6 +9,7 @@ use mem::Mem;
 use resampler::Resampler;
 use util::{Save, Xorshift};
 
+use std::cmp;
 use std::fs::File;
 use std::ops::{Deref, DerefMut};
 
@@ -37,6 +38,12 @@ const NOISE_PERIODS: [u16; 16] = [
     4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068
 ];
 
+/// The DMC output rates, in CPU cycles per output bit.
+// TODO: PAL
+const DMC_PERIODS: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54
+];
+
 //
 // Channel lengths
 //
@@ -334,6 +341,152 @@ impl ApuNoise {
     }
 }
 
+/// APUDMC: [0x4010, 0x4014)
+///
+/// The delta modulation channel plays 1-bit deltas fetched from CPU memory. Its memory reader
+/// can't access the bus itself; it asks the CPU for each byte with a DMA request, which stalls the
+/// CPU for a few cycles.
+#[derive(Copy, Clone)]
+struct ApuDmc {
+    /// IL--RRRR: IRQ enable, loop and rate index, as written to $4010.
+    flags: u8,
+    /// The start of the sample, as set through $4012.
+    sample_address: u16,
+    /// The length of the sample in bytes, as set through $4013.
+    sample_length: u16,
+
+    // Memory reader
+    current_address: u16,
+    bytes_remaining: u16,
+    sample_buffer: u8,
+    sample_buffer_full: bool,
+
+    // Output unit
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+    level: u8,
+    /// CPU cycles until the output unit is next clocked.
+    timer: u16,
+    /// The CPU cycle the channel has been run up to.
+    cy: u64,
+
+    irq: bool,
+}
+
+save_struct!(ApuDmc {
+    flags, sample_address, sample_length, current_address, bytes_remaining, sample_buffer,
+    sample_buffer_full, shift, bits_remaining, silence, level, timer, cy, irq
+});
+
+impl ApuDmc {
+    fn new() -> ApuDmc {
+        ApuDmc {
+            flags: 0,
+            sample_address: 0xc000,
+            sample_length: 1,
+            current_address: 0xc000,
+            bytes_remaining: 0,
+            sample_buffer: 0,
+            sample_buffer_full: false,
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+            level: 0,
+            timer: DMC_PERIODS[0],
+            cy: 0,
+            irq: false,
+        }
+    }
+
+    fn storeb(&mut self, addr: u16, val: u8) {
+        match addr & 0x3 {
+            0 => {
+                self.flags = val;
+                if !self.irq_enabled() {
+                    self.irq = false;
+                }
+            }
+            1 => self.level = val & 0x7f,
+            2 => self.sample_address = 0xc000 + (val as u16) * 64,
+            3 => self.sample_length = (val as u16) * 16 + 1,
+            _ => panic!("can't happen"),
+        }
+    }
+
+    fn irq_enabled(&self) -> bool { self.flags & 0x80 != 0 }
+    fn loops(&self) -> bool { self.flags & 0x40 != 0 }
+    fn period(&self) -> u16 { DMC_PERIODS[self.flags as usize & 0xf] }
+
+    /// Handles bit 4 of $4015: clearing it stops the sample, setting it restarts the sample only if
+    /// it has finished.
+    fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    /// The address the memory reader wants to fetch, if its buffer is empty.
+    fn dma_request(&self) -> Option<u16> {
+        if !self.sample_buffer_full && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    fn dma_complete(&mut self, val: u8) {
+        self.sample_buffer = val;
+        self.sample_buffer_full = true;
+
+        // The address wraps around to $8000, not $0000.
+        self.current_address = self.current_address.wrapping_add(1) | 0x8000;
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.loops() {
+                self.restart();
+            } else if self.irq_enabled() {
+                self.irq = true;
+            }
+        }
+    }
+
+    /// Clocks the output unit: applies one delta bit to the output level, and starts a new output
+    /// cycle from the sample buffer every 8 bits.
+    fn clock(&mut self) {
+        if !self.silence {
+            if self.shift & 1 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            self.silence = !self.sample_buffer_full;
+            self.shift = self.sample_buffer;
+            self.sample_buffer_full = false;
+        }
+    }
+
+    fn sample_volume(&self) -> i16 {
+        (self.level as i16) << 7
+    }
+}
+
 /// APUSTATUS: 0x4015
 #[derive(Copy, Clone)]
 struct ApuStatus(u8);
@@ -364,6 +517,38 @@ impl ApuStatus {
     fn noise_enabled(self) -> bool {
         self.0 & 0x08 != 0
     }
+
+    fn dmc_enabled(self) -> bool {
+        self.0 & 0x10 != 0
+    }
+}
+
+/// APUFRAMECOUNTER: 0x4017
+#[derive(Copy, Clone)]
+struct ApuFrameCounter(u8);
+
+impl Deref for ApuFrameCounter {
+    type Target = u8;
+
+    fn deref(&self) -> &u8 {
+        &self.0
+    }
+}
+
+impl DerefMut for ApuFrameCounter {
+    fn deref_mut(&mut self) -> &mut u8 {
+        &mut self.0
+    }
+}
+
+impl ApuFrameCounter {
+    fn five_step_mode(self) -> bool {
+        self.0 & 0x80 != 0
+    }
+
+    fn irq_inhibit(self) -> bool {
+        self.0 & 0x40 != 0
+    }
 }
 
 /// Audio registers
@@ -372,7 +557,9 @@ struct Regs {
     pulses: [ApuPulse; 2],
     triangle: ApuTriangle,
     noise: ApuNoise,
+    dmc: ApuDmc,
     status: ApuStatus,
+    frame_counter: ApuFrameCounter,
 }
 
 impl Save for Regs {
@@ -381,14 +568,18 @@ impl Save for Regs {
         self.pulses[1].save(fd);
         self.triangle.save(fd);
         self.noise.save(fd);
+        self.dmc.save(fd);
         self.status.save(fd);
+        self.frame_counter.save(fd);
     }
     fn load(&mut self, fd: &mut File) {
         self.pulses[0].load(fd);
         self.pulses[1].load(fd);
         self.triangle.load(fd);
         self.noise.load(fd);
+        self.dmc.load(fd);
         self.status.load(fd);
+        self.frame_counter.load(fd);
     }
 }
 
@@ -408,19 +599,32 @@ pub struct Apu {
 
     sample_buffers: Box<[SampleBuffer; 5]>,
     sample_buffer_offset: usize,
+    /// How far the DMC has filled its sample buffer. The DMC changes level on CPU cycles rather
+    /// than ticks, so it fills its buffer as it runs.
+    dmc_buffer_offset: usize,
     output_buffer: Option<*mut OutputBuffer>,
     resampler: Resampler,
 
     pub cy: u64,
     pub ticks: u64,
+
+    /// The frame interrupt flag, raised at the end of each four-step sequence.
+    frame_irq: bool,
 }
 
-save_struct!(Apu { regs, cy, ticks });
+save_struct!(Apu { regs, cy, ticks, frame_irq });
 
 impl Mem for Apu {
     fn loadb(&mut self, addr: u16) -> u8 {
         match addr {
-            0x4015 => *self.regs.status,
+            0x4015 => {
+                // Reading the status register acknowledges the frame interrupt, but not the DMC's.
+                let dmc_active = if self.regs.dmc.bytes_remaining > 0 { 0x10 } else { 0 };
+                let frame_irq = if self.frame_irq { 0x40 } else { 0 };
+                let dmc_irq = if self.regs.dmc.irq { 0x80 } else { 0 };
+                self.frame_irq = false;
+                (*self.regs.status & 0x0f) | dmc_active | frame_irq | dmc_irq
+            }
             _ => 0
         }
     }
@@ -430,7 +634,9 @@ impl Mem for Apu {
             0x4004 ... 0x4007 => self.update_pulse(addr, val, 1),
             0x4008 ... 0x400b => self.regs.triangle.storeb(addr, val),
             0x400c ... 0x400f => self.update_noise(addr, val),
+            0x4010 ... 0x4013 => self.regs.dmc.storeb(addr, val),
             0x4015 => self.update_status(val),
+            0x4017 => self.update_frame_counter(val),
             _ => {} // TODO
         }
     }
@@ -443,7 +649,9 @@ impl Apu {
                 pulses: [ ApuPulse::new(), ApuPulse::new() ],
                 triangle: ApuTriangle::new(),
                 noise: ApuNoise::new(),
+                dmc: ApuDmc::new(),
                 status: ApuStatus(0),
+                frame_counter: ApuFrameCounter(0),
             },
 
             sample_buffers: Box::new([
@@ -465,11 +673,52 @@ impl Apu {
             ]),
 
             sample_buffer_offset: 0,
+            dmc_buffer_offset: 0,
             output_buffer: output_buffer,
             resampler: Resampler::new(1, NES_SAMPLE_RATE, OUTPUT_SAMPLE_RATE, 0).unwrap(),
 
             cy: 0,
             ticks: 0,
+
+            frame_irq: false,
+        }
+    }
+
+    /// Handles the console's reset button, which silences all channels as if $4015 were cleared.
+    pub fn reset(&mut self) {
+        self.update_status(0);
+        self.frame_irq = false;
+    }
+
+    /// Returns the APU to its power-up state.
+    pub fn power_on(&mut self) {
+        *self = Apu::new(self.output_buffer);
+    }
+
+    /// Returns true while the frame counter is holding the CPU's IRQ line.
+    pub fn irq_pending(&self) -> bool {
+        self.frame_irq
+    }
+
+    /// Returns true while the DMC is holding the CPU's IRQ line.
+    pub fn dmc_irq_pending(&self) -> bool {
+        self.regs.dmc.irq
+    }
+
+    /// Returns the address of the byte the DMC wants fetched, if it is waiting for one.
+    pub fn dmc_dma_request(&self) -> Option<u16> {
+        self.regs.dmc.dma_request()
+    }
+
+    /// Hands the DMC the byte fetched for its last DMA request.
+    pub fn dmc_dma_complete(&mut self, val: u8) {
+        self.regs.dmc.dma_complete(val)
+    }
+
+    fn update_frame_counter(&mut self, val: u8) {
+        self.regs.frame_counter = ApuFrameCounter(val);
+        if self.regs.frame_counter.irq_inhibit() {
+            self.frame_irq = false;
         }
     }
 
@@ -487,6 +736,8 @@ impl Apu {
         if !self.regs.status.noise_enabled() {
             self.regs.noise.envelope.length.remaining = 0;
         }
+        let dmc_enabled = self.regs.status.dmc_enabled();
+        self.regs.dmc.set_enabled(dmc_enabled);
     }
 
     // FIXME: Refactor into a method on ApuPulse itself.
@@ -533,10 +784,44 @@ impl Apu {
                 break;
             }
 
+            self.step_dmc(next_tick_cycle);
             self.tick();
 
             self.cy = next_tick_cycle;
         }
+
+        self.step_dmc(run_to_cycle);
+    }
+
+    /// Runs the DMC output unit up to `run_to_cycle`, filling its sample buffer as the level
+    /// changes.
+    fn step_dmc(&mut self, run_to_cycle: u64) {
+        while self.regs.dmc.cy + self.regs.dmc.timer as u64 <= run_to_cycle {
+            self.regs.dmc.cy += self.regs.dmc.timer as u64;
+            self.regs.dmc.timer = self.regs.dmc.period();
+
+            let cy = self.regs.dmc.cy;
+            self.fill_dmc_samples(cy);
+            self.regs.dmc.clock();
+        }
+
+        self.regs.dmc.timer -= (run_to_cycle - self.regs.dmc.cy) as u16;
+        self.regs.dmc.cy = run_to_cycle;
+    }
+
+    /// Fills the DMC's sample buffer with the current level up to CPU cycle `cy`, without going
+    /// past the end of the current tick.
+    fn fill_dmc_samples(&mut self, cy: u64) {
+        let tick_end = self.sample_buffer_offset + NES_SAMPLES_PER_TICK as usize;
+        let end = cmp::min(self.sample_buffer_offset + (cy - self.cy) as usize, tick_end);
+        let volume = self.regs.dmc.sample_volume();
+        let samples = &mut self.sample_buffers[4].samples;
+        for i in self.dmc_buffer_offset..cmp::min(end, samples.len()) {
+            samples[i] = volume;
+        }
+        if end > self.dmc_buffer_offset {
+            self.dmc_buffer_offset = end;
+        }
     }
 
     fn tick(&mut self) {
@@ -581,9 +866,14 @@ impl Apu {
         self.play_pulse(1, 1);
         self.play_triangle(2);
         self.play_noise(3);
+        self.play_dmc();
         self.sample_buffer_offset += NES_SAMPLES_PER_TICK as usize;
 
-        // TODO: 60 Hz IRQ.
+        // 60 Hz frame interrupt, only raised in four-step mode.
+        let frame_counter = self.regs.frame_counter;
+        if self.ticks % 4 == 3 && !frame_counter.five_step_mode() && !frame_counter.irq_inhibit() {
+            self.frame_irq = true;
+        }
 
         self.ticks += 1;
     }
@@ -701,6 +991,16 @@ impl Apu {
         }
     }
 
+    fn play_dmc(&mut self) {
+        let tick_end = self.sample_buffer_offset + NES_SAMPLES_PER_TICK as usize;
+        let volume = self.regs.dmc.sample_volume();
+        let samples = &mut self.sample_buffers[4].samples;
+        for i in self.dmc_buffer_offset..cmp::min(tick_end, samples.len()) {
+            samples[i] = volume;
+        }
+        self.dmc_buffer_offset = tick_end;
+    }
+
     // Resamples and flushes channel buffers to the audio output device if necessary.
     pub fn play_channels(&mut self, mute: bool) {
         let sample_buffer_length = self.sample_buffers[0].samples.len();
@@ -708,6 +1008,7 @@ impl Apu {
             return;
         }
         self.sample_buffer_offset = 0;
+        self.dmc_buffer_offset = 0;
 
         if mute {
             let samples = &mut self.sample_buffers[0].samples;
@@ -0,0 +1,653 @@
+//! A small 6502 assembler, the inverse of `disasm`.
+//!
+//! It exists so that test programs can be written inline instead of needing an external toolchain.
+//! The syntax is a subset of ca65's: `label:`, `name = value`, `.org`, `.byte`, `.word` and every
+//! addressing mode in the form the disassembler prints it. Operands are sums and differences of
+//! numbers (`$hex`, `%binary`, decimal or `'c'`), symbols and `*`, optionally prefixed with `<` or
+//! `>` for the low or high byte. As in ca65, an address that is known to fit in a byte on the first
+//! pass uses zero page addressing unless it is prefixed with `a:`.
+
+use disasm::{AddressingMode, Disassembler, Mnemonic};
+use mem::Mem;
+use rom::RomBuilder;
+
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AsmError {
+    /// The line with this (1-based) number couldn't be parsed
+    SyntaxError(usize),
+    /// Unknown mnemonic or directive
+    UnknownInstruction(usize, String),
+    /// The instruction doesn't have the addressing mode the operand asks for
+    InvalidAddressingMode(usize),
+    UndefinedSymbol(usize, String),
+    DuplicateSymbol(usize, String),
+    /// A value doesn't fit in its operand, or a branch target is too far away
+    OutOfRange(usize),
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            AsmError::SyntaxError(line) => write!(f, "line {}: syntax error", line),
+            AsmError::UnknownInstruction(line, ref name) => {
+                write!(f, "line {}: unknown instruction {}", line, name)
+            }
+            AsmError::InvalidAddressingMode(line) => {
+                write!(f, "line {}: addressing mode not supported by the instruction", line)
+            }
+            AsmError::UndefinedSymbol(line, ref name) => {
+                write!(f, "line {}: undefined symbol {}", line, name)
+            }
+            AsmError::DuplicateSymbol(line, ref name) => {
+                write!(f, "line {}: {} is already defined", line, name)
+            }
+            AsmError::OutOfRange(line) => write!(f, "line {}: value out of range", line),
+        }
+    }
+}
+
+/// The output of the assembler: runs of bytes, one for each `.org`, and the symbols defined.
+pub struct Program {
+    pub chunks: Vec<(u16, Vec<u8>)>,
+    pub symbols: HashMap<String, u16>,
+}
+
+impl Program {
+    /// Returns the value of a label or equate.
+    pub fn symbol(&self, name: &str) -> Option<u16> {
+        self.symbols.get(name).cloned()
+    }
+
+    /// Lays out the bytes that fall within `len` bytes starting at `start`. Gaps are filled with
+    /// `fill`.
+    pub fn image(&self, start: u16, len: usize, fill: u8) -> Vec<u8> {
+        let mut image = vec![fill; len];
+        for &(addr, ref bytes) in &self.chunks {
+            for (i, &byte) in bytes.iter().enumerate() {
+                let offset = (addr as usize + i).wrapping_sub(start as usize);
+                if offset < len {
+                    image[offset] = byte;
+                }
+            }
+        }
+        image
+    }
+
+    /// Returns an NROM-256 cartridge with the program in its 32K of PRG-ROM, at $8000-$FFFF, and
+    /// blank CHR-ROM.
+    pub fn to_nrom(&self) -> RomBuilder {
+        RomBuilder::new(self.image(0x8000, 0x8000, 0xff))
+    }
+}
+
+/// Assembles a program.
+pub fn assemble(source: &str) -> Result<Program, AsmError> {
+    let opcodes = opcode_table();
+    let mut statements = vec![];
+    for (index, line) in source.lines().enumerate() {
+        try!(parse_line(line, index + 1, &opcodes, &mut statements));
+    }
+
+    let mut assembler = Assembler {
+        opcodes: &opcodes,
+        symbols: HashMap::new(),
+        modes: vec![],
+    };
+    try!(assembler.pass(&statements, false));
+    assembler.pass(&statements, true)
+}
+
+//
+// Parsing
+//
+
+#[derive(Clone, Debug)]
+enum Term {
+    Number(i32),
+    Symbol(String),
+    Pc,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Part {
+    Whole,
+    Low,
+    High,
+}
+
+#[derive(Clone, Debug)]
+struct Expr {
+    /// Each term with its sign.
+    terms: Vec<(i32, Term)>,
+    part: Part,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Index {
+    None,
+    X,
+    Y,
+}
+
+#[derive(Clone, Debug)]
+enum Operand {
+    None,
+    Accumulator,
+    Immediate(Expr),
+    /// An address, optionally indexed. `force_absolute` is set by the `a:` prefix.
+    Address { expr: Expr, index: Index, force_absolute: bool },
+    Indirect(Expr),
+    IndexedIndirectX(Expr),
+    IndirectIndexedY(Expr),
+}
+
+#[derive(Clone, Debug)]
+enum Data {
+    Expr(Expr),
+    Text(Vec<u8>),
+}
+
+#[derive(Clone, Debug)]
+enum Statement {
+    Label(String),
+    Equate(String, Expr),
+    Org(Expr),
+    Bytes(Vec<Data>),
+    Words(Vec<Expr>),
+    Instruction(Mnemonic, Operand),
+}
+
+fn parse_line(line: &str,
+              number: usize,
+              opcodes: &[Opcode],
+              statements: &mut Vec<(usize, Statement)>)
+              -> Result<(), AsmError> {
+    let mut rest = strip_comment(line).trim();
+
+    // Any number of labels may come before the statement.
+    while let Some(colon) = rest.find(':') {
+        let name = rest[..colon].trim();
+        if !is_symbol(name) {
+            break;
+        }
+        statements.push((number, Statement::Label(name.to_owned())));
+        rest = rest[colon + 1..].trim();
+    }
+    if rest.len() == 0 {
+        return Ok(());
+    }
+
+    if let Some(equals) = rest.find('=') {
+        let name = rest[..equals].trim();
+        if is_symbol(name) {
+            let expr = try!(parse_expr(&rest[equals + 1..], number));
+            statements.push((number, Statement::Equate(name.to_owned(), expr)));
+            return Ok(());
+        }
+    }
+
+    let (word, args) = match rest.find(char::is_whitespace) {
+        Some(space) => (&rest[..space], rest[space..].trim()),
+        None => (rest, ""),
+    };
+    let statement = match &*word.to_lowercase() {
+        ".org" => Statement::Org(try!(parse_expr(args, number))),
+        ".byte" | ".db" => {
+            let mut data = vec![];
+            for arg in try!(split_args(args, number)) {
+                if arg.starts_with('"') {
+                    if arg.len() < 2 || !arg.ends_with('"') {
+                        return Err(AsmError::SyntaxError(number));
+                    }
+                    data.push(Data::Text(arg[1..arg.len() - 1].as_bytes().to_vec()));
+                } else {
+                    data.push(Data::Expr(try!(parse_expr(arg, number))));
+                }
+            }
+            Statement::Bytes(data)
+        }
+        ".word" | ".addr" | ".dw" => {
+            let mut exprs = vec![];
+            for arg in try!(split_args(args, number)) {
+                exprs.push(try!(parse_expr(arg, number)));
+            }
+            Statement::Words(exprs)
+        }
+        _ if word.starts_with('.') => {
+            return Err(AsmError::UnknownInstruction(number, word.to_owned()))
+        }
+        _ => {
+            let mnemonic = match mnemonic_named(opcodes, word) {
+                Some(mnemonic) => mnemonic,
+                None => return Err(AsmError::UnknownInstruction(number, word.to_owned())),
+            };
+            Statement::Instruction(mnemonic, try!(parse_operand(args, number)))
+        }
+    };
+    statements.push((number, statement));
+    Ok(())
+}
+
+/// Removes a `;` comment, unless the semicolon is quoted.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match (c, quote) {
+            (';', None) => return &line[..i],
+            ('"', None) | ('\'', None) => quote = Some(c),
+            (_, Some(q)) if c == q => quote = None,
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Splits a comma-separated list, keeping commas inside quotes.
+fn split_args(args: &str, number: usize) -> Result<Vec<&str>, AsmError> {
+    let mut parts = vec![];
+    let mut start = 0;
+    let mut quote = None;
+    for (i, c) in args.char_indices() {
+        match (c, quote) {
+            (',', None) => {
+                parts.push(args[start..i].trim());
+                start = i + 1;
+            }
+            ('"', None) | ('\'', None) => quote = Some(c),
+            (_, Some(q)) if c == q => quote = None,
+            _ => {}
+        }
+    }
+    parts.push(args[start..].trim());
+    if parts.iter().any(|part| part.len() == 0) {
+        return Err(AsmError::SyntaxError(number));
+    }
+    Ok(parts)
+}
+
+fn parse_operand(text: &str, number: usize) -> Result<Operand, AsmError> {
+    let text: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    let upper = text.to_uppercase();
+
+    if text.len() == 0 {
+        return Ok(Operand::None);
+    }
+    if upper == "A" {
+        return Ok(Operand::Accumulator);
+    }
+    if text.starts_with('#') {
+        return Ok(Operand::Immediate(try!(parse_expr(&text[1..], number))));
+    }
+    if text.starts_with('(') {
+        return if upper.ends_with(",X)") {
+            Ok(Operand::IndexedIndirectX(try!(parse_expr(&text[1..text.len() - 3], number))))
+        } else if upper.ends_with("),Y") {
+            Ok(Operand::IndirectIndexedY(try!(parse_expr(&text[1..text.len() - 3], number))))
+        } else if text.ends_with(')') {
+            Ok(Operand::Indirect(try!(parse_expr(&text[1..text.len() - 1], number))))
+        } else {
+            Err(AsmError::SyntaxError(number))
+        };
+    }
+
+    let (text, index) = if upper.ends_with(",X") {
+        (&text[..text.len() - 2], Index::X)
+    } else if upper.ends_with(",Y") {
+        (&text[..text.len() - 2], Index::Y)
+    } else {
+        (&text[..], Index::None)
+    };
+    let (text, force_absolute) = if text.starts_with("a:") {
+        (&text[2..], true)
+    } else {
+        (text, false)
+    };
+    Ok(Operand::Address {
+        expr: try!(parse_expr(text, number)),
+        index: index,
+        force_absolute: force_absolute,
+    })
+}
+
+fn parse_expr(text: &str, number: usize) -> Result<Expr, AsmError> {
+    let text = text.trim();
+    let (text, part) = if text.starts_with('<') {
+        (&text[1..], Part::Low)
+    } else if text.starts_with('>') {
+        (&text[1..], Part::High)
+    } else {
+        (text, Part::Whole)
+    };
+
+    let mut terms = vec![];
+    let mut sign = 1;
+    let mut start = 0;
+    let mut quoted = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '\'' => quoted = !quoted,
+            // A sign at the start of a term belongs to it.
+            '+' | '-' if !quoted && text[start..i].trim().len() > 0 => {
+                terms.push((sign, try!(parse_term(&text[start..i], number))));
+                sign = if c == '-' { -1 } else { 1 };
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    terms.push((sign, try!(parse_term(&text[start..], number))));
+    Ok(Expr { terms: terms, part: part })
+}
+
+fn parse_term(text: &str, number: usize) -> Result<Term, AsmError> {
+    let text = text.trim();
+    let value = if text == "*" {
+        return Ok(Term::Pc);
+    } else if text.starts_with('$') {
+        i32::from_str_radix(&text[1..], 16).ok()
+    } else if text.starts_with('%') {
+        i32::from_str_radix(&text[1..], 2).ok()
+    } else if text.len() == 3 && text.starts_with('\'') && text.ends_with('\'') {
+        Some(text.as_bytes()[1] as i32)
+    } else if text.starts_with('-') {
+        return parse_term(&text[1..], number).and_then(|term| match term {
+            Term::Number(n) => Ok(Term::Number(-n)),
+            _ => Err(AsmError::SyntaxError(number)),
+        });
+    } else if text.len() > 0 && text.as_bytes()[0].is_ascii_digit() {
+        text.parse().ok()
+    } else if is_symbol(text) {
+        return Ok(Term::Symbol(text.to_owned()));
+    } else {
+        None
+    };
+    value.map(Term::Number).ok_or(AsmError::SyntaxError(number))
+}
+
+fn is_symbol(text: &str) -> bool {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '@' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+//
+// Encoding
+//
+
+/// One opcode the assembler can emit.
+struct Opcode {
+    mnemonic: Mnemonic,
+    mode: AddressingMode,
+    opcode: u8,
+}
+
+/// Serves a single opcode to the disassembler.
+struct OpcodeMem(u8);
+
+impl Mem for OpcodeMem {
+    fn loadb(&mut self, addr: u16) -> u8 { if addr == 0 { self.0 } else { 0 } }
+    fn storeb(&mut self, _: u16, _: u8) {}
+}
+
+/// Builds the encoding table by disassembling every opcode, so that the assembler always agrees
+/// with the disassembler. Where several opcodes decode the same way, the official one is used, or
+/// else the lowest.
+fn opcode_table() -> Vec<Opcode> {
+    let mut table: Vec<Opcode> = vec![];
+    let mut official: Vec<bool> = vec![];
+    for opcode in 0..256 {
+        let instruction = Disassembler { pc: 0, mem: &mut OpcodeMem(opcode as u8) }.disassemble();
+        match table.iter().position(|entry| {
+            entry.mnemonic == instruction.mnemonic && entry.mode == instruction.mode
+        }) {
+            Some(i) if !official[i] && instruction.is_official() => {
+                table[i].opcode = opcode as u8;
+                official[i] = true;
+            }
+            Some(_) => {}
+            None => {
+                table.push(Opcode {
+                    mnemonic: instruction.mnemonic,
+                    mode: instruction.mode,
+                    opcode: opcode as u8,
+                });
+                official.push(instruction.is_official());
+            }
+        }
+    }
+    table
+}
+
+/// Finds the mnemonic with the given name. "ISB" is accepted as another name for ISC.
+fn mnemonic_named(opcodes: &[Opcode], name: &str) -> Option<Mnemonic> {
+    let name = name.to_uppercase();
+    if name == "ISB" {
+        return Some(Mnemonic::Isc);
+    }
+    opcodes.iter().map(|entry| entry.mnemonic).find(|mnemonic| mnemonic.name() == name)
+}
+
+struct Assembler<'a> {
+    opcodes: &'a [Opcode],
+    symbols: HashMap<String, u16>,
+    /// The addressing mode picked for each instruction on the first pass, so that the second pass
+    /// lays the program out the same way.
+    modes: Vec<AddressingMode>,
+}
+
+impl<'a> Assembler<'a> {
+    /// Runs one pass over the program. On the first pass, symbols that aren't defined yet are
+    /// assumed to be 16-bit addresses; on the final pass they are errors.
+    fn pass(&mut self, statements: &[(usize, Statement)], last: bool)
+            -> Result<Program, AsmError> {
+        let mut program = Program { chunks: vec![], symbols: HashMap::new() };
+        let mut pc: u32 = 0;
+        let mut instruction_index = 0;
+
+        for &(line, ref statement) in statements {
+            let mut bytes = vec![];
+            match *statement {
+                Statement::Label(ref name) => {
+                    try!(self.define(name, pc as u16, line, last));
+                }
+                Statement::Equate(ref name, ref expr) => {
+                    match try!(self.eval(expr, pc, line, last)) {
+                        Some(value) => try!(self.define(name, value as u16, line, last)),
+                        None => {}
+                    }
+                }
+                Statement::Org(ref expr) => {
+                    pc = match try!(self.eval(expr, pc, line, true)) {
+                        Some(value) if value >= 0 && value <= 0xffff => value as u32,
+                        _ => return Err(AsmError::OutOfRange(line)),
+                    };
+                    program.chunks.push((pc as u16, vec![]));
+                }
+                Statement::Bytes(ref data) => {
+                    for item in data {
+                        match *item {
+                            Data::Text(ref text) => bytes.extend_from_slice(text),
+                            Data::Expr(ref expr) => {
+                                let value = try!(self.eval(expr, pc, line, last)).unwrap_or(0);
+                                bytes.push(try!(fit_byte(value, line)));
+                            }
+                        }
+                    }
+                }
+                Statement::Words(ref exprs) => {
+                    for expr in exprs {
+                        let value = try!(self.eval(expr, pc, line, last)).unwrap_or(0);
+                        let value = try!(fit_word(value, line));
+                        bytes.push(value as u8);
+                        bytes.push((value >> 8) as u8);
+                    }
+                }
+                Statement::Instruction(mnemonic, ref operand) => {
+                    bytes = try!(self.encode(mnemonic, operand, pc, line, instruction_index, last));
+                    instruction_index += 1;
+                }
+            }
+
+            if bytes.len() > 0 {
+                if pc + bytes.len() as u32 > 0x10000 {
+                    return Err(AsmError::OutOfRange(line));
+                }
+                if program.chunks.is_empty() {
+                    program.chunks.push((pc as u16, vec![]));
+                }
+                program.chunks.last_mut().unwrap().1.extend(bytes.iter().cloned());
+                pc += bytes.len() as u32;
+            }
+        }
+
+        program.symbols = self.symbols.clone();
+        Ok(program)
+    }
+
+    fn define(&mut self, name: &str, value: u16, line: usize, last: bool)
+              -> Result<(), AsmError> {
+        // Everything is defined again on the second pass, with the same values.
+        if !last && self.symbols.contains_key(name) {
+            return Err(AsmError::DuplicateSymbol(line, name.to_owned()));
+        }
+        self.symbols.insert(name.to_owned(), value);
+        Ok(())
+    }
+
+    /// Evaluates an expression. Returns None if it uses a symbol that isn't defined yet, which is
+    /// only allowed before the last pass.
+    fn eval(&self, expr: &Expr, pc: u32, line: usize, last: bool)
+            -> Result<Option<i32>, AsmError> {
+        let mut value = 0;
+        for &(sign, ref term) in &expr.terms {
+            let term = match *term {
+                Term::Number(n) => n,
+                Term::Pc => pc as i32,
+                Term::Symbol(ref name) => match self.symbols.get(name) {
+                    Some(&value) => value as i32,
+                    None if last => return Err(AsmError::UndefinedSymbol(line, name.clone())),
+                    None => return Ok(None),
+                },
+            };
+            value += sign * term;
+        }
+        Ok(Some(match expr.part {
+            Part::Whole => value,
+            Part::Low => value & 0xff,
+            Part::High => (value >> 8) & 0xff,
+        }))
+    }
+
+    fn opcode(&self, mnemonic: Mnemonic, mode: AddressingMode) -> Option<u8> {
+        self.opcodes.iter()
+                    .find(|entry| entry.mnemonic == mnemonic && entry.mode == mode)
+                    .map(|entry| entry.opcode)
+    }
+
+    fn has_mode(&self, mnemonic: Mnemonic, mode: AddressingMode) -> bool {
+        self.opcode(mnemonic, mode).is_some()
+    }
+
+    fn encode(&mut self,
+              mnemonic: Mnemonic,
+              operand: &Operand,
+              pc: u32,
+              line: usize,
+              instruction_index: usize,
+              last: bool)
+              -> Result<Vec<u8>, AsmError> {
+        let (mode, expr) = match *operand {
+            // `ASL` is accepted for `ASL A`.
+            Operand::None if self.has_mode(mnemonic, AddressingMode::Accumulator) => {
+                (AddressingMode::Accumulator, None)
+            }
+            Operand::None => (AddressingMode::Implied, None),
+            Operand::Accumulator => (AddressingMode::Accumulator, None),
+            Operand::Immediate(ref expr) => (AddressingMode::Immediate, Some(expr)),
+            Operand::Indirect(ref expr) => (AddressingMode::Indirect, Some(expr)),
+            Operand::IndexedIndirectX(ref expr) => (AddressingMode::IndexedIndirectX, Some(expr)),
+            Operand::IndirectIndexedY(ref expr) => (AddressingMode::IndirectIndexedY, Some(expr)),
+            Operand::Address { ref expr, index: Index::None, .. }
+                    if self.has_mode(mnemonic, AddressingMode::Relative) => {
+                (AddressingMode::Relative, Some(expr))
+            }
+            Operand::Address { ref expr, index, force_absolute } => {
+                let mode = if last {
+                    self.modes[instruction_index]
+                } else {
+                    let (zero_page, absolute) = match index {
+                        Index::None => (AddressingMode::ZeroPage, AddressingMode::Absolute),
+                        Index::X => (AddressingMode::ZeroPageX, AddressingMode::AbsoluteX),
+                        Index::Y => (AddressingMode::ZeroPageY, AddressingMode::AbsoluteY),
+                    };
+                    let value = try!(self.eval(expr, pc, line, false));
+                    let fits = match value {
+                        Some(value) => value >= 0 && value < 0x100,
+                        None => false,
+                    };
+                    if !force_absolute && fits && self.has_mode(mnemonic, zero_page) ||
+                            !self.has_mode(mnemonic, absolute) {
+                        zero_page
+                    } else {
+                        absolute
+                    }
+                };
+                (mode, Some(expr))
+            }
+        };
+        if !last {
+            self.modes.push(mode);
+        }
+
+        let opcode = match self.opcode(mnemonic, mode) {
+            Some(opcode) => opcode,
+            None => return Err(AsmError::InvalidAddressingMode(line)),
+        };
+        let mut bytes = vec![opcode];
+        let value = match expr {
+            Some(expr) => try!(self.eval(expr, pc, line, last)).unwrap_or(0),
+            None => return Ok(bytes),
+        };
+
+        match mode {
+            AddressingMode::Relative => {
+                let offset = if last { value - (pc as i32 + 2) } else { 0 };
+                if offset < -128 || offset > 127 {
+                    return Err(AsmError::OutOfRange(line));
+                }
+                bytes.push(offset as u8);
+            }
+            AddressingMode::Absolute | AddressingMode::AbsoluteX | AddressingMode::AbsoluteY |
+            AddressingMode::Indirect => {
+                let value = try!(fit_word(value, line));
+                bytes.push(value as u8);
+                bytes.push((value >> 8) as u8);
+            }
+            _ => bytes.push(try!(fit_byte(value, line))),
+        }
+        Ok(bytes)
+    }
+}
+
+fn fit_byte(value: i32, line: usize) -> Result<u8, AsmError> {
+    // Negative bytes are allowed, for things like `LDA #-1`.
+    if value >= -128 && value <= 0xff {
+        Ok(value as u8)
+    } else {
+        Err(AsmError::OutOfRange(line))
+    }
+}
+
+fn fit_word(value: i32, line: usize) -> Result<u16, AsmError> {
+    if value >= 0 && value <= 0xffff {
+        Ok(value as u16)
+    } else {
+        Err(AsmError::OutOfRange(line))
+    }
+}
@@ -4,16 +4,34 @@
 
 extern crate nes;
 
+use nes::cdl::{self, CodeDataLog};
+use nes::palette::{NtscSettings, Palette};
 use nes::rom::Rom;
+use nes::symbols::SymbolTable;
 use nes::Emulator;
 
 use std::env;
 use std::path::Path;
 use std::fs::File;
+use std::u16;
+
+/// Where the output colors come from.
+enum PaletteSource {
+    File(String),
+    Ntsc(NtscSettings),
+    Settings(String),
+}
 
 struct Options {
     rom_path: String,
     scale: f32,
+    cycle_accurate: bool,
+    breakpoints: Vec<u16>,
+    trace_path: Option<String>,
+    trace_ranges: Vec<(u16, u16)>,
+    symbol_paths: Vec<String>,
+    cdl_path: Option<String>,
+    palette: Option<PaletteSource>,
 }
 
 fn usage() {
@@ -22,19 +40,97 @@ fn usage() {
     println!("    -1 scale by 1x");
     println!("    -2 scale by 2x");
     println!("    -3 scale by 3x (default)");
+    println!("    -c cycle-accurate CPU timing (slower)");
+    println!("    -b <addr> dump the trace buffer to trace.log when PC reaches <addr>");
+    println!("    -t <file> stream the trace to <file>");
+    println!("    -r <start>-<end> only stream instructions in this PC range (repeatable)");
+    println!("    -s <file> load symbols from a .nl, .mlb or ca65 .dbg file (repeatable)");
+    println!("    -l <file> log code and data accesses to a .cdl file, adding to it if it exists");
+    println!("    -p <file> use a 64- or 512-color .pal palette");
+    println!("    -n use a palette generated from a model of the NTSC signal");
+    println!("    -N <key>=<value> set hue, saturation, contrast, brightness or gamma of the");
+    println!("       generated palette (repeatable; implies -n)");
+    println!("    -P <file> read palette settings (the -N keys, or palette = <file>) from <file>");
 }
 
 fn parse_args() -> Option<Options> {
     let mut options = Options {
         rom_path: String::new(),
         scale: 3.0,
+        cycle_accurate: false,
+        breakpoints: Vec::new(),
+        trace_path: None,
+        trace_ranges: Vec::new(),
+        symbol_paths: Vec::new(),
+        cdl_path: None,
+        palette: None,
     };
 
-    for arg in env::args().skip(1) {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
         match &*arg {
             "-1" => { options.scale = 1.0; },
             "-2" => { options.scale = 2.0; },
             "-3" => { options.scale = 3.0; },
+            "-c" => { options.cycle_accurate = true; },
+            "-b" => {
+                match args.next().and_then(|addr| parse_addr(&addr)) {
+                    Some(addr) => options.breakpoints.push(addr),
+                    None => { usage(); return None; },
+                }
+            },
+            "-t" => {
+                match args.next() {
+                    Some(path) => options.trace_path = Some(path),
+                    None => { usage(); return None; },
+                }
+            },
+            "-r" => {
+                match args.next().and_then(|range| parse_range(&range)) {
+                    Some(range) => options.trace_ranges.push(range),
+                    None => { usage(); return None; },
+                }
+            },
+            "-s" => {
+                match args.next() {
+                    Some(path) => options.symbol_paths.push(path),
+                    None => { usage(); return None; },
+                }
+            },
+            "-l" => {
+                match args.next() {
+                    Some(path) => options.cdl_path = Some(path),
+                    None => { usage(); return None; },
+                }
+            },
+            "-p" => {
+                match args.next() {
+                    Some(path) => options.palette = Some(PaletteSource::File(path)),
+                    None => { usage(); return None; },
+                }
+            },
+            "-n" => {
+                match options.palette {
+                    Some(PaletteSource::Ntsc(_)) => {}
+                    _ => options.palette = Some(PaletteSource::Ntsc(NtscSettings::default())),
+                }
+            },
+            "-N" => {
+                let mut settings = match options.palette {
+                    Some(PaletteSource::Ntsc(settings)) => settings,
+                    _ => NtscSettings::default(),
+                };
+                match args.next().and_then(|setting| parse_setting(&setting, &mut settings)) {
+                    Some(()) => options.palette = Some(PaletteSource::Ntsc(settings)),
+                    None => { usage(); return None; },
+                }
+            },
+            "-P" => {
+                match args.next() {
+                    Some(path) => options.palette = Some(PaletteSource::Settings(path)),
+                    None => { usage(); return None; },
+                }
+            },
             _ if arg.starts_with('-') => { usage(); return None; },
             _ => { options.rom_path = arg; },
         }
@@ -48,6 +144,30 @@ fn parse_args() -> Option<Options> {
     Some(options)
 }
 
+/// Parses a hexadecimal address, with or without a leading `$`.
+fn parse_addr(addr: &str) -> Option<u16> {
+    let addr = if addr.starts_with('$') { &addr[1..] } else { addr };
+    u16::from_str_radix(addr, 16).ok()
+}
+
+/// Parses an inclusive address range of the form `start-end`.
+fn parse_range(range: &str) -> Option<(u16, u16)> {
+    let mut bounds = range.splitn(2, '-');
+    match (bounds.next().and_then(parse_addr), bounds.next().and_then(parse_addr)) {
+        (Some(start), Some(end)) if start <= end => Some((start, end)),
+        _ => None,
+    }
+}
+
+/// Applies a palette generator setting of the form `key=value`.
+fn parse_setting(setting: &str, settings: &mut NtscSettings) -> Option<()> {
+    let mut fields = setting.splitn(2, '=');
+    match (fields.next(), fields.next()) {
+        (Some(key), Some(val)) if settings.set(key, val) => Some(()),
+        _ => None,
+    }
+}
+
 fn main() {
     let options = match parse_args() {
         Some(options) => options,
@@ -57,6 +177,66 @@ fn main() {
     let rom_path = &options.rom_path;
     let rom = Rom::load(&mut File::open(&Path::new(rom_path)).unwrap()).unwrap();
 
+    let mut symbols = SymbolTable::new();
+    for path in &options.symbol_paths {
+        match symbols.load(&Path::new(path)) {
+            Ok(count) => println!("Loaded {} symbols from {}", count, path),
+            Err(err) => println!("Couldn't load symbols from {}: {}", path, err),
+        }
+    }
+
+    let mut log = None;
+    if let Some(ref cdl_path) = options.cdl_path {
+        let mut cdl = CodeDataLog::new(&rom);
+        if Path::new(cdl_path).exists() {
+            if let Err(err) = cdl.load(&Path::new(cdl_path)) {
+                println!("Couldn't load code/data log from {}: {}", cdl_path, err);
+                return;
+            }
+        }
+        log = Some(cdl);
+    }
+
+    let palette = match options.palette {
+        Some(PaletteSource::File(ref path)) => Some(Palette::load(&Path::new(path))),
+        Some(PaletteSource::Ntsc(ref settings)) => Some(Ok(Palette::generate(settings))),
+        Some(PaletteSource::Settings(ref path)) => Some(Palette::load_settings(&Path::new(path))),
+        None => None,
+    };
+    let palette = match palette {
+        Some(Ok(palette)) => Some(palette),
+        Some(Err(err)) => {
+            println!("Couldn't load the palette: {}", err);
+            return;
+        }
+        None => None,
+    };
+
     let mut nes = Emulator::new(rom, options.scale);
+    nes.set_symbols(symbols);
+    nes.set_cycle_accurate(options.cycle_accurate);
+    if let Some(palette) = palette {
+        nes.set_palette(palette);
+    }
+    nes.tracer.breakpoints = options.breakpoints;
+    if let Some(ref trace_path) = options.trace_path {
+        nes.tracer.stream_to(&Path::new(trace_path), options.trace_ranges).unwrap();
+    }
+    if let Some(log) = log {
+        nes.start_code_data_log(log);
+    }
     nes.start();
+
+    if let (Some(cdl_path), Some(log)) = (options.cdl_path, nes.code_data_log()) {
+        match log.save(&Path::new(&cdl_path)) {
+            Ok(()) => {
+                println!("Code/data log written to {}: {} of {} PRG bytes code, {} data",
+                         cdl_path,
+                         log.prg_count(cdl::PRG_CODE),
+                         log.prg.len(),
+                         log.prg_count(cdl::PRG_DATA));
+            }
+            Err(err) => println!("Couldn't write code/data log to {}: {}", cdl_path, err),
+        }
+    };
 }
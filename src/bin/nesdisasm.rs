@@ -0,0 +1,60 @@
+//! Disassembles a whole ROM into ca65 source that reassembles to the same PRG-ROM image.
+
+extern crate nes;
+
+use nes::rom::Rom;
+use nes::static_disasm::StaticDisassembly;
+use nes::symbols::SymbolTable;
+
+use std::env;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::process;
+
+fn usage() {
+    println!("usage: nesdisasm <path-to-rom> <output.s> [symbol files...]");
+    println!();
+    println!("An ld65 linker configuration is written next to the output, with a .cfg extension.");
+    println!("Symbols from FCEUX .nl, Mesen .mlb or ca65 .dbg files are used to name labels.");
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() < 2 {
+        usage();
+        return;
+    }
+
+    let rom = Rom::load(&mut File::open(&Path::new(&args[0])).unwrap()).unwrap();
+    let mut disassembly = match StaticDisassembly::new(&rom) {
+        Some(disassembly) => disassembly,
+        None => {
+            println!("unsupported mapper: {}", rom.header.ines_mapper());
+            process::exit(1);
+        }
+    };
+
+    let mut symbols = SymbolTable::new();
+    for path in &args[2..] {
+        if let Err(err) = symbols.load(&Path::new(path)) {
+            println!("couldn't load symbols from {}: {}", path, err);
+            process::exit(1);
+        }
+    }
+    disassembly.apply_symbols(&symbols);
+
+    let asm_path = Path::new(&args[1]);
+    let config_path = asm_path.with_extension("cfg");
+    let config_name = config_path.file_name().unwrap().to_string_lossy().into_owned();
+
+    let mut asm = BufWriter::new(File::create(asm_path).unwrap());
+    disassembly.write_asm(&mut asm, &config_name).unwrap();
+    let mut config = BufWriter::new(File::create(&config_path).unwrap());
+    disassembly.write_linker_config(&mut config).unwrap();
+
+    println!("{} banks, {} of {} bytes identified as code",
+             disassembly.banks.len(),
+             disassembly.code_size(),
+             rom.prg.len());
+}
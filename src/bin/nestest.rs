@@ -0,0 +1,94 @@
+//! Runs nestest's automated mode, printing a Nintendulator-format trace or checking the trace
+//! against a reference log.
+
+extern crate nes;
+
+use nes::nestest;
+use nes::rom::Rom;
+
+use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process;
+
+/// The length of the official nestest.log, used when no reference log is given.
+const DEFAULT_LINES: usize = 8991;
+
+struct Options {
+    rom_path: String,
+    log_path: Option<String>,
+    ignore_ppu: bool,
+    cycle_accurate: bool,
+}
+
+fn usage() {
+    println!("usage: nestest [options] <path-to-nestest.nes> [path-to-nestest.log]");
+    println!("options:");
+    println!("    --ignore-ppu      don't compare the PPU position column");
+    println!("    --cycle-accurate  run the CPU in cycle-accurate mode");
+    println!();
+    println!("Without a reference log, the trace is printed to standard output.");
+}
+
+fn parse_args() -> Option<Options> {
+    let mut options = Options {
+        rom_path: String::new(),
+        log_path: None,
+        ignore_ppu: false,
+        cycle_accurate: false,
+    };
+
+    for arg in env::args().skip(1) {
+        match &*arg {
+            "--ignore-ppu" => { options.ignore_ppu = true; },
+            "--cycle-accurate" => { options.cycle_accurate = true; },
+            _ if arg.starts_with('-') => { usage(); return None; },
+            _ if options.rom_path.len() == 0 => { options.rom_path = arg; },
+            _ if options.log_path.is_none() => { options.log_path = Some(arg); },
+            _ => { usage(); return None; },
+        }
+    }
+
+    if options.rom_path.len() == 0 {
+        usage();
+        return None;
+    }
+
+    Some(options)
+}
+
+fn main() {
+    let options = match parse_args() {
+        Some(options) => options,
+        None => return,
+    };
+
+    let rom = Rom::load(&mut File::open(&Path::new(&options.rom_path)).unwrap()).unwrap();
+    let mut cpu = nestest::console(rom);
+    cpu.cycle_accurate = options.cycle_accurate;
+
+    match options.log_path {
+        None => {
+            for _ in 0..DEFAULT_LINES {
+                let (scanline, dot) = cpu.mem.ppu.position(cpu.cy);
+                println!("{}", nestest::trace_line(&mut cpu, scanline, dot));
+                nes::step(&mut cpu);
+            }
+        }
+        Some(log_path) => {
+            let log = BufReader::new(File::open(&Path::new(&log_path)).unwrap());
+            let lines = log.lines().map(|line| line.unwrap());
+            match nestest::check(&mut cpu, lines, options.ignore_ppu) {
+                Ok(count) => println!("{} lines match", count),
+                Err(mismatch) => {
+                    println!("{}", mismatch);
+                    process::exit(1);
+                }
+            }
+        }
+    }
+
+    // nestest stores its result codes at $02 and $03; zero means every test passed.
+    println!("result: $02 = {:02X}, $03 = {:02X}", cpu.mem.ram[2], cpu.mem.ram[3]);
+}
@@ -0,0 +1,103 @@
+//! Code/data logging, in the `.cdl` format used by FCEUX and Mesen.
+//!
+//! A log has one byte of flags for every byte of PRG-ROM, followed by one for every byte of
+//! CHR-ROM. Flags are only ever added, so a log can be loaded and built up over several sessions.
+
+use mem::Access;
+use rom::Rom;
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+// PRG-ROM flags
+/// Fetched as an opcode or operand.
+pub const PRG_CODE: u8 = 0x01;
+/// Read as data.
+pub const PRG_DATA: u8 = 0x02;
+/// Which 8K window of $8000-$FFFF the byte was last accessed through.
+pub const PRG_WINDOW_MASK: u8 = 0x0c;
+/// Fetched by the DMC as a sample.
+pub const PRG_PCM: u8 = 0x40;
+
+// CHR-ROM flags
+/// Fetched by the PPU while rendering.
+pub const CHR_RENDERED: u8 = 0x01;
+/// Read by the CPU through PPUDATA.
+pub const CHR_READ: u8 = 0x02;
+
+pub struct CodeDataLog {
+    pub prg: Vec<u8>,
+    pub chr: Vec<u8>,
+}
+
+impl CodeDataLog {
+    /// Creates an empty log sized for a ROM. Boards with CHR-RAM have no CHR part.
+    pub fn new(rom: &Rom) -> CodeDataLog {
+        CodeDataLog {
+            prg: vec![0; rom.prg.len()],
+            chr: vec![0; rom.chr.len()],
+        }
+    }
+
+    /// Records a CPU read of the PRG-ROM byte at `offset`, which was mapped at `addr`.
+    pub fn log_prg(&mut self, offset: usize, addr: u16, access: Access) {
+        let flag = match access {
+            Access::Code => PRG_CODE,
+            Access::Data => PRG_DATA,
+            Access::Dmc => PRG_PCM,
+            Access::Dummy => return,
+        };
+        if let Some(flags) = self.prg.get_mut(offset) {
+            let window = (((addr >> 13) & 3) as u8) << 2;
+            *flags = (*flags & !PRG_WINDOW_MASK) | window | flag;
+        }
+    }
+
+    /// Records a read of the CHR-ROM byte at `offset`.
+    pub fn log_chr(&mut self, offset: usize, flag: u8) {
+        if let Some(flags) = self.chr.get_mut(offset) {
+            *flags |= flag;
+        }
+    }
+
+    /// Returns the number of PRG-ROM bytes with any of the given flags set.
+    pub fn prg_count(&self, flags: u8) -> usize {
+        self.prg.iter().filter(|&&f| f & flags != 0).count()
+    }
+
+    /// Returns the number of CHR-ROM bytes with any of the given flags set.
+    pub fn chr_count(&self, flags: u8) -> usize {
+        self.chr.iter().filter(|&&f| f & flags != 0).count()
+    }
+
+    /// Merges a log file into this one. The file must have been made for a ROM of the same size.
+    pub fn load(&mut self, path: &Path) -> io::Result<()> {
+        let mut data = vec![];
+        try!(try!(File::open(path)).read_to_end(&mut data));
+        if data.len() != self.prg.len() + self.chr.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "code/data log doesn't match the ROM's size"));
+        }
+
+        let (prg, chr) = data.split_at(self.prg.len());
+        for (flags, &loaded) in self.prg.iter_mut().zip(prg) {
+            // The window bits of the log in memory are newer.
+            if *flags == 0 {
+                *flags = loaded;
+            } else {
+                *flags |= loaded & !PRG_WINDOW_MASK;
+            }
+        }
+        for (flags, &loaded) in self.chr.iter_mut().zip(chr) {
+            *flags |= loaded;
+        }
+        Ok(())
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut file = try!(File::create(path));
+        try!(file.write_all(&self.prg));
+        file.write_all(&self.chr)
+    }
+}
@@ -2,22 +2,25 @@
 // Author: Patrick Walton
 //
 
-use mem::Mem;
+use mem::{Access, InterruptLines, Mem};
+use symbols::{PrgMap, SymbolTable};
 use util::Save;
 
 use std::fs::File;
 use std::ops::Deref;
+use std::rc::Rc;
 
 use disasm::Disassembler;
 
-const CARRY_FLAG:    u8 = 1 << 0;
-const ZERO_FLAG:     u8 = 1 << 1;
-const IRQ_FLAG:      u8 = 1 << 2;
-const DECIMAL_FLAG:  u8 = 1 << 3;
-const BREAK_FLAG:    u8 = 1 << 4;
-const U_FLAG:        u8 = 1 << 5;
-const OVERFLOW_FLAG: u8 = 1 << 6;
-const NEGATIVE_FLAG: u8 = 1 << 7;
+// Processor status flags, as found in `Regs::flags`
+pub const CARRY_FLAG:    u8 = 1 << 0;
+pub const ZERO_FLAG:     u8 = 1 << 1;
+pub const IRQ_FLAG:      u8 = 1 << 2;
+pub const DECIMAL_FLAG:  u8 = 1 << 3;
+pub const BREAK_FLAG:    u8 = 1 << 4;
+pub const U_FLAG:        u8 = 1 << 5;
+pub const OVERFLOW_FLAG: u8 = 1 << 6;
+pub const NEGATIVE_FLAG: u8 = 1 << 7;
 
 const NMI_VECTOR:   u16 = 0xfffa;
 const RESET_VECTOR: u16 = 0xfffc;
@@ -41,31 +44,123 @@ static CYCLE_TABLE: [u8; 256] = [
     /*0xB0*/ 2,5,2,5,4,4,4,4,2,4,2,4,4,4,4,4,
     /*0xC0*/ 2,6,2,8,3,3,5,5,2,2,2,2,4,4,6,6,
     /*0xD0*/ 2,5,2,8,4,4,6,6,2,4,2,7,4,4,7,7,
-    /*0xE0*/ 2,6,3,8,3,3,5,5,2,2,2,2,4,4,6,6,
+    /*0xE0*/ 2,6,2,8,3,3,5,5,2,2,2,2,4,4,6,6,
     /*0xF0*/ 2,5,2,8,4,4,6,6,2,4,2,7,4,4,7,7,
 ];
 
+/// The extra cycle taken by instructions that only read their operand when indexing crosses a page
+/// boundary. Stores and read-modify-write instructions always take the worst case, which is already
+/// accounted for in `CYCLE_TABLE`. Indexed by opcode number.
+static PAGE_CROSS_TABLE: [u8; 256] = [
+    /*0x00*/ 0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,
+    /*0x10*/ 0,1,0,0,0,0,0,0,0,1,0,0,1,1,0,0,
+    /*0x20*/ 0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,
+    /*0x30*/ 0,1,0,0,0,0,0,0,0,1,0,0,1,1,0,0,
+    /*0x40*/ 0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,
+    /*0x50*/ 0,1,0,0,0,0,0,0,0,1,0,0,1,1,0,0,
+    /*0x60*/ 0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,
+    /*0x70*/ 0,1,0,0,0,0,0,0,0,1,0,0,1,1,0,0,
+    /*0x80*/ 0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,
+    /*0x90*/ 0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,
+    /*0xA0*/ 0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,
+    /*0xB0*/ 0,1,0,1,0,0,0,0,0,1,0,1,1,1,1,1,
+    /*0xC0*/ 0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,
+    /*0xD0*/ 0,1,0,0,0,0,0,0,0,1,0,0,1,1,0,0,
+    /*0xE0*/ 0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,
+    /*0xF0*/ 0,1,0,0,0,0,0,0,0,1,0,0,1,1,0,0,
+];
+
+/// `CYCLE_TABLE` for the 65C02. ADC and SBC take one more cycle in decimal mode.
+static CMOS_CYCLE_TABLE: [u8; 256] = [
+    /*0x00*/ 7,6,2,1,5,3,5,5,3,2,2,1,6,4,6,5,
+    /*0x10*/ 2,5,5,1,5,4,6,5,2,4,2,1,6,4,6,5,
+    /*0x20*/ 6,6,2,1,3,3,5,5,4,2,2,1,4,4,6,5,
+    /*0x30*/ 2,5,5,1,4,4,6,5,2,4,2,1,4,4,6,5,
+    /*0x40*/ 6,6,2,1,3,3,5,5,3,2,2,1,3,4,6,5,
+    /*0x50*/ 2,5,5,1,4,4,6,5,2,4,3,1,8,4,6,5,
+    /*0x60*/ 6,6,2,1,3,3,5,5,4,2,2,1,6,4,6,5,
+    /*0x70*/ 2,5,5,1,4,4,6,5,2,4,4,1,6,4,6,5,
+    /*0x80*/ 2,6,2,1,3,3,3,5,2,2,2,1,4,4,4,5,
+    /*0x90*/ 2,6,5,1,4,4,4,5,2,5,2,1,4,5,5,5,
+    /*0xA0*/ 2,6,2,1,3,3,3,5,2,2,2,1,4,4,4,5,
+    /*0xB0*/ 2,5,5,1,4,4,4,5,2,4,2,1,4,4,4,5,
+    /*0xC0*/ 2,6,2,1,3,3,5,5,2,2,2,3,4,4,6,5,
+    /*0xD0*/ 2,5,5,1,4,4,6,5,2,4,3,3,4,4,7,5,
+    /*0xE0*/ 2,6,2,1,3,3,5,5,2,2,2,1,4,4,6,5,
+    /*0xF0*/ 2,5,5,1,4,4,6,5,2,4,4,1,4,4,7,5,
+];
+
+/// `PAGE_CROSS_TABLE` for the 65C02, which also charges the extra cycle to shifts and rotates.
+static CMOS_PAGE_CROSS_TABLE: [u8; 256] = [
+    /*0x00*/ 0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,
+    /*0x10*/ 0,1,0,0,0,0,0,0,0,1,0,0,0,1,1,0,
+    /*0x20*/ 0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,
+    /*0x30*/ 0,1,0,0,0,0,0,0,0,1,0,0,1,1,1,0,
+    /*0x40*/ 0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,
+    /*0x50*/ 0,1,0,0,0,0,0,0,0,1,0,0,0,1,1,0,
+    /*0x60*/ 0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,
+    /*0x70*/ 0,1,0,0,0,0,0,0,0,1,0,0,0,1,1,0,
+    /*0x80*/ 0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,
+    /*0x90*/ 0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,
+    /*0xA0*/ 0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,
+    /*0xB0*/ 0,1,0,0,0,0,0,0,0,1,0,0,1,1,1,0,
+    /*0xC0*/ 0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,
+    /*0xD0*/ 0,1,0,0,0,0,0,0,0,1,0,0,0,1,0,0,
+    /*0xE0*/ 0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,
+    /*0xF0*/ 0,1,0,0,0,0,0,0,0,1,0,0,0,1,0,0,
+];
+
+/// The members of the 6502 family the core can run. The NES uses the 2A03, an NMOS 6502 with the
+/// decimal mode circuitry disconnected.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Variant {
+    /// The NES CPU: the decimal flag can be set but has no effect.
+    Ricoh2A03,
+    /// The original NMOS 6502, with decimal mode and the unofficial opcodes.
+    Nmos6502,
+    /// The WDC 65C02: new instructions and addressing modes, valid flags in decimal mode and no
+    /// JMP indirect bug. The cycle-accurate bus model only covers the NMOS parts, so the 65C02
+    /// always runs with instruction timing.
+    Cmos65C02,
+}
+
+/// The devices that can pull the IRQ line low. The line stays asserted as long as any of them holds
+/// it.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum IrqSource {
+    Mapper,
+    FrameCounter,
+    Dmc,
+}
+
+impl IrqSource {
+    fn mask(self) -> u8 { 1 << (self as u8) }
+}
+
 /// CPU Registers
-struct Regs {
-    a: u8,
-    x: u8,
-    y: u8,
-    s: u8,
-    flags: u8,
-    pc: u16
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Regs {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub s: u8,
+    /// The processor status register (P). See the `*_FLAG` constants.
+    pub flags: u8,
+    pub pc: u16
 }
 
 save_struct!(Regs { a, x, y, s, flags, pc });
 
 impl Regs {
+    /// The power-up state. The reset sequence that follows brings S down to $FD.
     fn new() -> Regs {
         Regs {
             a: 0,
             x: 0,
             y: 0,
-            s: 0xfd,
-            flags: 0x24,
-            pc: 0xc000
+            s: 0,
+            flags: IRQ_FLAG | U_FLAG,
+            pc: 0
         }
     }
 }
@@ -77,12 +172,15 @@ impl Regs {
 trait AddressingMode<M: Mem> {
     fn load(&self, cpu: &mut Cpu<M>) -> u8;
     fn store(&self, cpu: &mut Cpu<M>, val: u8);
+    /// The cycle between the read and the write of a read-modify-write instruction.
+    fn rewrite(&self, cpu: &mut Cpu<M>, val: u8);
 }
 
 struct AccumulatorAddressingMode;
 impl<M: Mem> AddressingMode<M> for AccumulatorAddressingMode {
     fn load(&self, cpu: &mut Cpu<M>) -> u8 { cpu.regs.a }
     fn store(&self, cpu: &mut Cpu<M>, val: u8) { cpu.regs.a = val }
+    fn rewrite(&self, _: &mut Cpu<M>, _: u8) {}
 }
 
 struct ImmediateAddressingMode;
@@ -92,6 +190,9 @@ impl<M: Mem> AddressingMode<M> for ImmediateAddressingMode {
         // Not particularly type-safe, but probably not worth using trait inheritance for this.
         panic!("can't store to immediate")
     }
+    fn rewrite(&self, _: &mut Cpu<M>, _: u8) {
+        panic!("can't store to immediate")
+    }
 }
 
 struct MemoryAddressingMode{val: u16}
@@ -107,6 +208,12 @@ impl Deref for MemoryAddressingMode {
 impl<M: Mem> AddressingMode<M> for MemoryAddressingMode {
     fn load(&self, cpu: &mut Cpu<M>) -> u8 { cpu.loadb(**self) }
     fn store(&self, cpu: &mut Cpu<M>, val: u8) { cpu.storeb(**self, val) }
+    fn rewrite(&self, cpu: &mut Cpu<M>, val: u8) {
+        // The unmodified value is written back first. Mappers such as MMC1 can see both writes.
+        if cpu.bus_accurate() {
+            cpu.storeb(**self, val)
+        }
+    }
 }
 
 /// Opcode decoding
@@ -311,7 +418,201 @@ macro_rules! decode_op {
             // No operation
             0xea => $this.nop(),
 
-            _ => panic!("unimplemented or illegal instruction: {}", $op)
+            //
+            // Unofficial opcodes
+            //
+
+            // Combined loads and stores
+            0xa7 => { let v = $this.zero_page(); $this.lax(v) }
+            0xb7 => { let v = $this.zero_page_y(); $this.lax(v) }
+            0xaf => { let v = $this.absolute(); $this.lax(v) }
+            0xbf => { let v = $this.absolute_y(); $this.lax(v) }
+            0xa3 => { let v = $this.indexed_indirect_x(); $this.lax(v) }
+            0xb3 => { let v = $this.indirect_indexed_y(); $this.lax(v) }
+
+            0x87 => { let v = $this.zero_page(); $this.sax(v) }
+            0x97 => { let v = $this.zero_page_y(); $this.sax(v) }
+            0x8f => { let v = $this.absolute(); $this.sax(v) }
+            0x83 => { let v = $this.indexed_indirect_x(); $this.sax(v) }
+
+            // Read-modify-write combinations
+            0xc7 => { let v = $this.zero_page(); $this.dcp(v) }
+            0xd7 => { let v = $this.zero_page_x(); $this.dcp(v) }
+            0xcf => { let v = $this.absolute(); $this.dcp(v) }
+            0xdf => { let v = $this.absolute_x(); $this.dcp(v) }
+            0xdb => { let v = $this.absolute_y(); $this.dcp(v) }
+            0xc3 => { let v = $this.indexed_indirect_x(); $this.dcp(v) }
+            0xd3 => { let v = $this.indirect_indexed_y(); $this.dcp(v) }
+
+            0xe7 => { let v = $this.zero_page(); $this.isc(v) }
+            0xf7 => { let v = $this.zero_page_x(); $this.isc(v) }
+            0xef => { let v = $this.absolute(); $this.isc(v) }
+            0xff => { let v = $this.absolute_x(); $this.isc(v) }
+            0xfb => { let v = $this.absolute_y(); $this.isc(v) }
+            0xe3 => { let v = $this.indexed_indirect_x(); $this.isc(v) }
+            0xf3 => { let v = $this.indirect_indexed_y(); $this.isc(v) }
+
+            0x07 => { let v = $this.zero_page(); $this.slo(v) }
+            0x17 => { let v = $this.zero_page_x(); $this.slo(v) }
+            0x0f => { let v = $this.absolute(); $this.slo(v) }
+            0x1f => { let v = $this.absolute_x(); $this.slo(v) }
+            0x1b => { let v = $this.absolute_y(); $this.slo(v) }
+            0x03 => { let v = $this.indexed_indirect_x(); $this.slo(v) }
+            0x13 => { let v = $this.indirect_indexed_y(); $this.slo(v) }
+
+            0x27 => { let v = $this.zero_page(); $this.rla(v) }
+            0x37 => { let v = $this.zero_page_x(); $this.rla(v) }
+            0x2f => { let v = $this.absolute(); $this.rla(v) }
+            0x3f => { let v = $this.absolute_x(); $this.rla(v) }
+            0x3b => { let v = $this.absolute_y(); $this.rla(v) }
+            0x23 => { let v = $this.indexed_indirect_x(); $this.rla(v) }
+            0x33 => { let v = $this.indirect_indexed_y(); $this.rla(v) }
+
+            0x47 => { let v = $this.zero_page(); $this.sre(v) }
+            0x57 => { let v = $this.zero_page_x(); $this.sre(v) }
+            0x4f => { let v = $this.absolute(); $this.sre(v) }
+            0x5f => { let v = $this.absolute_x(); $this.sre(v) }
+            0x5b => { let v = $this.absolute_y(); $this.sre(v) }
+            0x43 => { let v = $this.indexed_indirect_x(); $this.sre(v) }
+            0x53 => { let v = $this.indirect_indexed_y(); $this.sre(v) }
+
+            0x67 => { let v = $this.zero_page(); $this.rra(v) }
+            0x77 => { let v = $this.zero_page_x(); $this.rra(v) }
+            0x6f => { let v = $this.absolute(); $this.rra(v) }
+            0x7f => { let v = $this.absolute_x(); $this.rra(v) }
+            0x7b => { let v = $this.absolute_y(); $this.rra(v) }
+            0x63 => { let v = $this.indexed_indirect_x(); $this.rra(v) }
+            0x73 => { let v = $this.indirect_indexed_y(); $this.rra(v) }
+
+            // Immediate-only operations
+            0xeb => { let v = $this.immediate(); $this.sbc(v) }
+            0x0b => { let v = $this.immediate(); $this.anc(v) }
+            0x2b => { let v = $this.immediate(); $this.anc(v) }
+            0x4b => { let v = $this.immediate(); $this.alr(v) }
+            0x6b => { let v = $this.immediate(); $this.arr(v) }
+            0xcb => { let v = $this.immediate(); $this.axs(v) }
+            0x8b => { let v = $this.immediate(); $this.xaa(v) }
+            0xab => { let v = $this.immediate(); $this.lxa(v) }
+
+            // Unstable stores that AND with the high byte of the address
+            0x9c => { let v = $this.absolute_x(); $this.shy(v) }
+            0x9e => { let v = $this.absolute_y(); $this.shx(v) }
+            0x9f => { let v = $this.absolute_y(); $this.ahx(v) }
+            0x93 => { let v = $this.indirect_indexed_y(); $this.ahx(v) }
+            0x9b => { let v = $this.absolute_y(); $this.tas(v) }
+            0xbb => { let v = $this.absolute_y(); $this.las(v) }
+
+            // No operations, some of which read their operand
+            0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa => $this.nop(),
+            0x80 | 0x82 | 0x89 | 0xc2 | 0xe2 => { let v = $this.immediate(); $this.ign(v) }
+            0x04 | 0x44 | 0x64 => { let v = $this.zero_page(); $this.ign(v) }
+            0x14 | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4 => { let v = $this.zero_page_x(); $this.ign(v) }
+            0x0c => { let v = $this.absolute(); $this.ign(v) }
+            0x1c | 0x3c | 0x5c | 0x7c | 0xdc | 0xfc => { let v = $this.absolute_x(); $this.ign(v) }
+
+            // Processor lock-up
+            0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xb2 | 0xd2 | 0xf2 => {
+                $this.kil()
+            }
+        }
+    }
+}
+
+/// Opcode decoding for the 65C02, which reuses most of the NMOS unofficial opcodes for new
+/// instructions and turns the rest into NOPs. Everything else decodes as on the NMOS parts.
+macro_rules! decode_cmos_op {
+    ($op:expr, $this:ident) => {
+        match $op {
+            // Zero page indirect addressing
+            0x12 => { let v = $this.zero_page_indirect(); $this.ora(v) }
+            0x32 => { let v = $this.zero_page_indirect(); $this.and(v) }
+            0x52 => { let v = $this.zero_page_indirect(); $this.eor(v) }
+            0x72 => { let v = $this.zero_page_indirect(); $this.adc(v) }
+            0x92 => { let v = $this.zero_page_indirect(); $this.sta(v) }
+            0xb2 => { let v = $this.zero_page_indirect(); $this.lda(v) }
+            0xd2 => { let v = $this.zero_page_indirect(); $this.cmp(v) }
+            0xf2 => { let v = $this.zero_page_indirect(); $this.sbc(v) }
+
+            // New addressing modes for existing instructions
+            0x89 => { let v = $this.immediate(); $this.bit_immediate(v) }
+            0x34 => { let v = $this.zero_page_x(); $this.bit(v) }
+            0x3c => { let v = $this.absolute_x(); $this.bit(v) }
+            0x1a => { let v = $this.accumulator(); $this.inc(v) }
+            0x3a => { let v = $this.accumulator(); $this.dec(v) }
+            0x7c => $this.jmp_indexed_x(),
+
+            // Stores and bit tests
+            0x64 => { let v = $this.zero_page(); $this.stz(v) }
+            0x74 => { let v = $this.zero_page_x(); $this.stz(v) }
+            0x9c => { let v = $this.absolute(); $this.stz(v) }
+            0x9e => { let v = $this.absolute_x(); $this.stz(v) }
+
+            0x04 => { let v = $this.zero_page(); $this.tsb(v) }
+            0x0c => { let v = $this.absolute(); $this.tsb(v) }
+            0x14 => { let v = $this.zero_page(); $this.trb(v) }
+            0x1c => { let v = $this.absolute(); $this.trb(v) }
+
+            // Branches
+            0x80 => $this.bra(),
+
+            // Stack operations
+            0xda => $this.phx(),
+            0x5a => $this.phy(),
+            0xfa => $this.plx(),
+            0x7a => $this.ply(),
+
+            // Single bit operations
+            0x07 => { let v = $this.zero_page(); $this.rmb(0, v) }
+            0x17 => { let v = $this.zero_page(); $this.rmb(1, v) }
+            0x27 => { let v = $this.zero_page(); $this.rmb(2, v) }
+            0x37 => { let v = $this.zero_page(); $this.rmb(3, v) }
+            0x47 => { let v = $this.zero_page(); $this.rmb(4, v) }
+            0x57 => { let v = $this.zero_page(); $this.rmb(5, v) }
+            0x67 => { let v = $this.zero_page(); $this.rmb(6, v) }
+            0x77 => { let v = $this.zero_page(); $this.rmb(7, v) }
+            0x87 => { let v = $this.zero_page(); $this.smb(0, v) }
+            0x97 => { let v = $this.zero_page(); $this.smb(1, v) }
+            0xa7 => { let v = $this.zero_page(); $this.smb(2, v) }
+            0xb7 => { let v = $this.zero_page(); $this.smb(3, v) }
+            0xc7 => { let v = $this.zero_page(); $this.smb(4, v) }
+            0xd7 => { let v = $this.zero_page(); $this.smb(5, v) }
+            0xe7 => { let v = $this.zero_page(); $this.smb(6, v) }
+            0xf7 => { let v = $this.zero_page(); $this.smb(7, v) }
+            0x0f => $this.bbr(0),
+            0x1f => $this.bbr(1),
+            0x2f => $this.bbr(2),
+            0x3f => $this.bbr(3),
+            0x4f => $this.bbr(4),
+            0x5f => $this.bbr(5),
+            0x6f => $this.bbr(6),
+            0x7f => $this.bbr(7),
+            0x8f => $this.bbs(0),
+            0x9f => $this.bbs(1),
+            0xaf => $this.bbs(2),
+            0xbf => $this.bbs(3),
+            0xcf => $this.bbs(4),
+            0xdf => $this.bbs(5),
+            0xef => $this.bbs(6),
+            0xff => $this.bbs(7),
+
+            // Waiting and stopping
+            0xcb => $this.wai(),
+            0xdb => $this.kil(),
+
+            // No operations of various lengths
+            0x02 | 0x22 | 0x42 | 0x62 | 0x82 | 0xc2 | 0xe2 => {
+                let v = $this.immediate();
+                $this.ign(v)
+            }
+            0x44 => { let v = $this.zero_page(); $this.ign(v) }
+            0x54 | 0xd4 | 0xf4 => { let v = $this.zero_page_x(); $this.ign(v) }
+            0x5c | 0xdc | 0xfc => { let v = $this.absolute(); $this.ign(v) }
+            0x03 | 0x13 | 0x23 | 0x33 | 0x43 | 0x53 | 0x63 | 0x73 |
+            0x83 | 0x93 | 0xa3 | 0xb3 | 0xc3 | 0xd3 | 0xe3 | 0xf3 => $this.nop(),
+            0x0b | 0x1b | 0x2b | 0x3b | 0x4b | 0x5b | 0x6b | 0x7b |
+            0x8b | 0x9b | 0xab | 0xbb | 0xeb | 0xfb => $this.nop(),
+
+            op => decode_op!(op, $this),
         }
     }
 }
@@ -325,37 +626,93 @@ pub type Cycles = u64;
 /// The main CPU structure definition.
 pub struct Cpu<M: Mem> {
     pub cy: Cycles,
-    regs: Regs,
+    pub regs: Regs,
     pub mem: M,
     pub trace: bool,
+    /// When set, every bus access takes one cycle and the devices on the bus are clocked before
+    /// each one, including the dummy reads and writes the 6502 performs. Otherwise whole
+    /// instructions are charged from `CYCLE_TABLE` and the devices catch up afterwards.
+    pub cycle_accurate: bool,
+    pub variant: Variant,
+    /// Names substituted for addresses in the trace.
+    pub symbols: Rc<SymbolTable>,
+
+    /// The opcode of the instruction being executed.
+    opcode: u8,
+    /// Set by the indexed addressing modes when the effective address is on a different page than
+    /// the base address.
+    page_crossed: bool,
+
+    // Interrupt lines
+    /// One bit per `IrqSource` currently asserting the IRQ line.
+    irq_lines: u8,
+    /// The current level of the NMI line.
+    nmi_line: bool,
+    /// Set on a rising edge of the NMI line; cleared when the NMI vector is fetched.
+    nmi_pending: bool,
+    /// The I flag as it was when the interrupt lines were last polled.
+    irq_inhibit: bool,
+    /// Set by the 65C02's WAI until an interrupt is requested.
+    waiting: bool,
+    /// Set when a KIL or STP opcode has stopped the CPU. Only a reset gets it going again.
+    halted: bool,
 }
 
-/// The CPU implements Mem so that it can handle writes to the DMA register.
+/// The CPU implements Mem so that it can handle writes to the DMA register and let the DMC halt it.
 impl<M: Mem> Mem for Cpu<M> {
     fn loadb(&mut self, addr: u16) -> u8 {
-        self.mem.loadb(addr)
+        self.loadb_as(addr, Access::Data)
+    }
+
+    fn loadb_as(&mut self, addr: u16, access: Access) -> u8 {
+        // The DMA unit can only halt the CPU on a read, so DMC fetches wait for one.
+        if let Some(sample_addr) = self.mem.dmc_dma_request() {
+            self.dmc_dma(addr, sample_addr)
+        }
+
+        self.tick();
+        self.mem.loadb_as(addr, access)
     }
 
     fn storeb(&mut self, addr: u16, val: u8) {
+        self.tick();
+
         // Handle OAM_DMA.
         if addr == 0x4014 {
-            self.dma(val)
+            self.oam_dma(val)
         } else {
             self.mem.storeb(addr, val)
         }
     }
+
+    fn peekb(&mut self, addr: u16) -> Option<u8> {
+        // Peeking doesn't take bus time, so it bypasses the CPU.
+        self.mem.peekb(addr)
+    }
 }
 
 impl<M: Mem + Save> Save for Cpu<M> {
     fn save(&mut self, fd: &mut File) {
         self.cy.save(fd);
         self.regs.save(fd);
+        self.irq_lines.save(fd);
+        self.nmi_line.save(fd);
+        self.nmi_pending.save(fd);
+        self.irq_inhibit.save(fd);
+        self.waiting.save(fd);
+        self.halted.save(fd);
         self.mem.save(fd);
     }
 
     fn load(&mut self, fd: &mut File) {
         self.cy.load(fd);
         self.regs.load(fd);
+        self.irq_lines.load(fd);
+        self.nmi_line.load(fd);
+        self.nmi_pending.load(fd);
+        self.irq_inhibit.load(fd);
+        self.waiting.load(fd);
+        self.halted.load(fd);
         self.mem.load(fd);
     }
 }
@@ -363,35 +720,172 @@ impl<M: Mem + Save> Save for Cpu<M> {
 impl<M: Mem> Cpu<M> {
     // The main fetch-and-decode routine
     pub fn step(&mut self) {
+        // A stopped CPU ignores interrupts as well; only a reset gets it going again.
+        if self.halted {
+            if !self.bus_accurate() {
+                self.cy += 1;
+            } else {
+                self.tick();
+            }
+            return;
+        }
+
+        // WAI wakes up on any interrupt request. If the I flag masks the IRQ, execution simply
+        // continues after the WAI.
+        if self.waiting {
+            if !self.nmi_pending && self.irq_lines == 0 {
+                self.cy += 1;
+                return;
+            }
+            self.waiting = false;
+        }
+
+        // The interrupt lines were polled at the end of the previous instruction.
+        if self.nmi_pending || (self.irq_lines != 0 && !self.irq_inhibit) {
+            // The opcode fetch and the read after it still happen, but are discarded.
+            self.dummy_read_pc();
+            self.dummy_read_pc();
+            self.interrupt(false);
+            if !self.bus_accurate() {
+                self.cy += 7;
+            }
+
+            // The first instruction of the handler always runs before the lines are polled again.
+            self.irq_inhibit = true;
+            return;
+        }
+
         self.trace();
 
+        self.page_crossed = false;
+        let irq_inhibit = self.get_flag(IRQ_FLAG);
         let op = self.loadb_bump_pc();
-        decode_op!(op, self);
+        self.opcode = op;
+
+        // Single-byte instructions (the $x8 and $xA columns, RTI and RTS) read the following byte
+        // and throw it away.
+        if op & 0x0f == 0x08 || op & 0x0f == 0x0a || op == 0x40 || op == 0x60 {
+            self.dummy_read_pc();
+        }
 
-        self.cy += CYCLE_TABLE[op as usize] as Cycles;
+        let (cycle_table, page_cross_table) = match self.variant {
+            Variant::Cmos65C02 => {
+                decode_cmos_op!(op, self);
+                (&CMOS_CYCLE_TABLE, &CMOS_PAGE_CROSS_TABLE)
+            }
+            Variant::Ricoh2A03 | Variant::Nmos6502 => {
+                decode_op!(op, self);
+                (&CYCLE_TABLE, &PAGE_CROSS_TABLE)
+            }
+        };
+
+        if !self.bus_accurate() {
+            self.cy += cycle_table[op as usize] as Cycles;
+            if self.page_crossed {
+                self.cy += page_cross_table[op as usize] as Cycles;
+            }
+        }
+
+        // CLI, SEI and PLP change the I flag after the lines have been polled, so their effect is
+        // delayed by one instruction. RTI restores the flag in time.
+        self.irq_inhibit = match op {
+            0x28 | 0x58 | 0x78 => irq_inhibit,
+            _ => self.get_flag(IRQ_FLAG),
+        };
     }
 
-    /// Sets PC to the address stored in the reset vector
+    /// Runs the reset sequence. This is the interrupt sequence with the stack writes suppressed: S is
+    /// still decremented by 3, I is set, and PC is loaded from the reset vector.
     pub fn reset(&mut self) {
+        self.dummy_read_pc();
+        self.dummy_read_pc();
+        for _ in 0..3 {
+            self.dummy_read_stack();
+            self.regs.s = self.regs.s.wrapping_sub(1);
+        }
+        self.set_flag(IRQ_FLAG, true);
+        self.irq_inhibit = true;
+        self.nmi_pending = false;
+        self.waiting = false;
+        self.halted = false;
+        if self.variant == Variant::Cmos65C02 {
+            self.set_flag(DECIMAL_FLAG, false);
+        }
         self.regs.pc = self.loadw(RESET_VECTOR);
+        if !self.bus_accurate() {
+            self.cy += 7;
+        }
     }
 
-    pub fn nmi(&mut self) {
-        let (pc, flags) = (self.regs.pc, self.regs.flags);
-        self.pushw(pc);
-        self.pushb(flags);
-        self.regs.pc = self.loadw(NMI_VECTOR);
+    /// Puts the CPU back into its power-up state and runs the reset sequence. Memory is untouched.
+    pub fn power_on(&mut self) {
+        self.cy = 0;
+        self.regs = Regs::new();
+        self.irq_lines = 0;
+        self.nmi_line = false;
+        self.reset();
     }
 
-    pub fn irq(&mut self) {
-        if self.get_flag(IRQ_FLAG) {
-            return;
+    /// Sets the level of the NMI line. The NMI is edge-triggered, so it is only taken when the
+    /// line goes from deasserted to asserted.
+    pub fn set_nmi(&mut self, asserted: bool) {
+        if asserted && !self.nmi_line {
+            self.nmi_pending = true;
         }
+        self.nmi_line = asserted;
+    }
 
-        let (pc, flags) = (self.regs.pc, self.regs.flags);
-        self.pushw(pc);
-        self.pushb(flags);
-        self.regs.pc = self.loadw(BRK_VECTOR);
+    /// Pulls the IRQ line low on behalf of `source`. The IRQ is level-triggered: it is taken
+    /// whenever the line is asserted and the I flag is clear, until every source acknowledges it.
+    pub fn assert_irq(&mut self, source: IrqSource) {
+        self.irq_lines |= source.mask();
+    }
+
+    /// Releases the IRQ line on behalf of `source`.
+    pub fn ack_irq(&mut self, source: IrqSource) {
+        self.irq_lines &= !source.mask();
+    }
+
+    /// Updates the NMI and IRQ lines from the interrupt outputs of the devices on the bus.
+    pub fn set_interrupt_lines(&mut self, lines: InterruptLines) {
+        self.set_nmi(lines.nmi);
+        self.set_irq(IrqSource::Mapper, lines.mapper_irq);
+        self.set_irq(IrqSource::FrameCounter, lines.frame_irq);
+        self.set_irq(IrqSource::Dmc, lines.dmc_irq);
+    }
+
+    /// Returns true if an NMI edge has been latched and will be taken before the next instruction.
+    pub fn nmi_pending(&self) -> bool {
+        self.nmi_pending
+    }
+
+    /// Latches or discards a pending NMI without changing the level of the NMI line.
+    pub fn set_nmi_pending(&mut self, pending: bool) {
+        self.nmi_pending = pending;
+    }
+
+    /// Returns true if `source` is currently asserting the IRQ line.
+    pub fn irq_asserted(&self, source: IrqSource) -> bool {
+        (self.irq_lines & source.mask()) != 0
+    }
+
+    /// Returns true if a KIL or STP opcode has stopped the CPU.
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Returns true if an IRQ will be taken before the next instruction.
+    pub fn irq_pending(&self) -> bool {
+        self.irq_lines != 0 && !self.irq_inhibit
+    }
+
+    /// Asserts or releases the IRQ line on behalf of `source`, mirroring a device's IRQ output.
+    pub fn set_irq(&mut self, source: IrqSource, asserted: bool) {
+        if asserted {
+            self.assert_irq(source)
+        } else {
+            self.ack_irq(source)
+        }
     }
 
     pub fn new(mem: M) -> Cpu<M> {
@@ -400,20 +894,42 @@ impl<M: Mem> Cpu<M> {
             regs: Regs::new(),
             mem: mem,
             trace: false,
+            cycle_accurate: false,
+            variant: Variant::Ricoh2A03,
+            symbols: Rc::new(SymbolTable::new()),
+            opcode: 0,
+            page_crossed: false,
+
+            irq_lines: 0,
+            nmi_line: false,
+            nmi_pending: false,
+            irq_inhibit: true,
+            waiting: false,
+            halted: false,
         }
     }
 
     // Debugging
     fn trace(&mut self) {
         if self.trace && cfg!(feature = "cpuspew") {
-            let mut disassembler = Disassembler {
+            let map = PrgMap::capture(&mut self.mem);
+            let instruction = Disassembler {
                 pc: self.regs.pc,
                 mem: &mut self.mem
-            };
+            }.disassemble();
+            let symbols = &self.symbols;
+            let write = instruction.writes_memory();
+            let name = |addr| symbols.operand_name(addr, &map, write).map(str::to_owned);
+            let mut text = instruction.format_with(&name);
+            if let Some(effective) = instruction.effective_address(self.regs.x,
+                                                                   self.regs.y,
+                                                                   &mut self.mem) {
+                text.push_str(&instruction.format_effective(&effective, &name));
+            }
             println!(
-                "{:04X} {:20} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
-                self.regs.pc,
-                disassembler.disassemble(),
+                "{:4} {:32} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+                symbols.format_addr(self.regs.pc, &map),
+                text,
                 self.regs.a,
                 self.regs.x,
                 self.regs.y,
@@ -424,17 +940,134 @@ impl<M: Mem> Cpu<M> {
         }
     }
 
-    // Performs DMA to the OAMDATA ($2004) register.
-    fn dma(&mut self, hi_addr: u8) {
+    //
+    // DMA
+    //
+    // The DMA unit halts the CPU and then alternates between get cycles, on which it can read, and
+    // put cycles, on which it can write. Even cycles are get cycles.
+    //
+
+    // Performs DMA to the OAMDATA ($2004) register. This takes 513 cycles, or 514 if an alignment
+    // cycle is needed, plus 2 for every DMC fetch that cuts in.
+    fn oam_dma(&mut self, hi_addr: u8) {
         let start = (hi_addr as u16) << 8;
 
+        // The alignment depends on the cycle of the write to $4014, which is the last cycle of the
+        // instruction. Per-instruction timing hasn't charged that instruction yet, so count its
+        // cycles in.
+        let pending = self.pending_cycles();
+        self.dma_cycle();
+        if (self.cy + pending) % 2 != 0 {
+            self.dma_cycle();
+        }
+
         for addr in start..start + 256 {
-            let val = self.loadb(addr);
-            self.storeb(0x2004, val);
+            // The DMC wins the get cycle, which pushes the OAM read back by a get/put pair.
+            if let Some(sample_addr) = self.mem.dmc_dma_request() {
+                let val = self.dma_read(sample_addr, Access::Dmc);
+                self.mem.dmc_dma_complete(val);
+                self.dma_cycle();
+            }
+
+            let val = self.dma_read(addr, Access::Data);
+            self.dma_write(0x2004, val);
+        }
+    }
 
-            // FIXME: The last address sometimes takes 1 cycle, sometimes 2 -- NESdev isn't very
-            // clear on this.
-            self.cy += 2;
+    /// Fetches a sample byte for the DMC while the CPU is halted on a read of `addr`. The halt cycle
+    /// and the dummy cycle after it repeat the CPU's read, and the fetch must land on a get cycle,
+    /// so this takes 3 or 4 cycles.
+    fn dmc_dma(&mut self, addr: u16, sample_addr: u16) {
+        self.halted_read(addr, true);
+        self.halted_read(addr, false);
+        if !self.get_cycle() {
+            self.halted_read(addr, false);
+        }
+
+        let val = self.dma_read(sample_addr, Access::Dmc);
+        self.mem.dmc_dma_complete(val);
+    }
+
+    fn get_cycle(&self) -> bool { self.cy % 2 == 0 }
+
+    /// Spends one cycle on behalf of the DMA unit.
+    fn dma_cycle(&mut self) {
+        if self.bus_accurate() {
+            self.tick();
+        } else {
+            self.cy += 1;
+        }
+    }
+    fn dma_read(&mut self, addr: u16, access: Access) -> u8 {
+        self.dma_cycle();
+        self.mem.loadb_as(addr, access)
+    }
+    fn dma_write(&mut self, addr: u16, val: u8) {
+        self.dma_cycle();
+        self.mem.storeb(addr, val)
+    }
+    /// A cycle on which the CPU is halted and keeps repeating its read of `addr`. The controller
+    /// ports only see the first of a run of back-to-back reads; the read after the DMA clocks them
+    /// again, which is how DMC fetches corrupt controller reads.
+    fn halted_read(&mut self, addr: u16, first: bool) {
+        self.dma_cycle();
+        if first || (addr != 0x4016 && addr != 0x4017) {
+            let _ = self.mem.loadb_as(addr, Access::Dummy);
+        }
+    }
+
+    /// The cycles of the current instruction that `step` has yet to add to `cy`: all of them in
+    /// per-instruction mode, none in cycle-accurate mode. Only meaningful for instructions that
+    /// take the same time whether or not they cross a page, such as stores.
+    fn pending_cycles(&self) -> Cycles {
+        if self.bus_accurate() {
+            return 0;
+        }
+        let cycle_table = match self.variant {
+            Variant::Cmos65C02 => &CMOS_CYCLE_TABLE,
+            Variant::Ricoh2A03 | Variant::Nmos6502 => &CYCLE_TABLE,
+        };
+        cycle_table[self.opcode as usize] as Cycles
+    }
+
+    /// Whether bus accesses are being timed individually. See `cycle_accurate`.
+    fn bus_accurate(&self) -> bool {
+        self.cycle_accurate && self.variant != Variant::Cmos65C02
+    }
+
+    // Bus timing helpers
+    /// In cycle-accurate mode, brings the devices on the bus up to the current cycle and samples
+    /// their interrupt outputs before an access, then charges the access its cycle.
+    fn tick(&mut self) {
+        if self.bus_accurate() {
+            if let Some(lines) = self.mem.clock(self.cy) {
+                self.set_interrupt_lines(lines);
+            }
+            self.cy += 1;
+        }
+    }
+    /// Performs a read whose result the 6502 discards. These only matter for their timing and
+    /// side effects, so they are skipped outside cycle-accurate mode.
+    fn dummy_read(&mut self, addr: u16) {
+        if self.bus_accurate() {
+            let _ = self.loadb_as(addr, Access::Dummy);
+        }
+    }
+    fn dummy_read_pc(&mut self) {
+        let pc = self.regs.pc;
+        self.dummy_read(pc)
+    }
+    fn dummy_read_stack(&mut self) {
+        let s = self.regs.s;
+        self.dummy_read(0x100 + s as u16)
+    }
+    /// Spends a cycle that `CYCLE_TABLE` doesn't account for. In cycle-accurate mode that cycle is
+    /// a dummy read of `addr`.
+    fn extra_cycle(&mut self, addr: u16) {
+        if self.bus_accurate() {
+            let _ = self.loadb_as(addr, Access::Dummy);
+        } else {
+            self.cy += 1;
         }
     }
 
@@ -442,7 +1075,7 @@ impl<M: Mem> Cpu<M> {
     /// Loads the byte at the program counter and increments the program counter.
     fn loadb_bump_pc(&mut self) -> u8 {
         let pc = self.regs.pc;
-        let val = self.loadb(pc);
+        let val = self.loadb_as(pc, Access::Code);
         self.regs.pc += 1;
         val
     }
@@ -450,11 +1083,35 @@ impl<M: Mem> Cpu<M> {
     /// them.
     fn loadw_bump_pc(&mut self) -> u16 {
         let pc = self.regs.pc;
-        let val = self.loadw(pc);
+        let lo = self.loadb_as(pc, Access::Code);
+        let hi = self.loadb_as(pc.wrapping_add(1), Access::Code);
+        let val = (hi as u16) << 8 | lo as u16;
         self.regs.pc += 2;
         val
     }
 
+    /// The interrupt sequence shared by BRK, IRQ and NMI. Only BRK pushes the flags with the break
+    /// bit set.
+    fn interrupt(&mut self, brk: bool) {
+        let pc = self.regs.pc;
+        self.pushw(pc);
+        let flags = if brk { self.regs.flags | BREAK_FLAG } else { self.regs.flags & !BREAK_FLAG };
+        self.pushb(flags | U_FLAG);
+        self.set_flag(IRQ_FLAG, true);
+        if self.variant == Variant::Cmos65C02 {
+            self.set_flag(DECIMAL_FLAG, false);
+        }
+
+        // An NMI that is pending by the time the vector is fetched hijacks a BRK or IRQ sequence.
+        let vector = if self.nmi_pending {
+            self.nmi_pending = false;
+            NMI_VECTOR
+        } else {
+            BRK_VECTOR
+        };
+        self.regs.pc = self.loadw(vector);
+    }
+
     // Stack helpers
     fn pushb(&mut self, val: u8) {
         let s = self.regs.s;
@@ -490,8 +1147,9 @@ impl<M: Mem> Cpu<M> {
         }
     }
     fn set_flags(&mut self, val: u8) {
-        // The unused bit isn't really specified, but "most likely on", so we'll set it
-        self.regs.flags = val | U_FLAG;
+        // The unused bit isn't really specified, but "most likely on", so we'll set it. The break
+        // bit only exists on the stack.
+        self.regs.flags = (val & !BREAK_FLAG) | U_FLAG;
     }
     fn set_zn(&mut self, val: u8) -> u8 {
         self.set_flag(ZERO_FLAG, val == 0);
@@ -506,31 +1164,56 @@ impl<M: Mem> Cpu<M> {
         MemoryAddressingMode{val: self.loadb_bump_pc() as u16}
     }
     fn zero_page_x(&mut self) -> MemoryAddressingMode {
-        MemoryAddressingMode{val: (self.loadb_bump_pc() + self.regs.x) as u16}
+        let base = self.loadb_bump_pc();
+        self.dummy_read(base as u16);
+        MemoryAddressingMode{val: base.wrapping_add(self.regs.x) as u16}
     }
     fn zero_page_y(&mut self) -> MemoryAddressingMode {
-        MemoryAddressingMode{val: (self.loadb_bump_pc() + self.regs.y) as u16}
+        let base = self.loadb_bump_pc();
+        self.dummy_read(base as u16);
+        MemoryAddressingMode{val: base.wrapping_add(self.regs.y) as u16}
     }
     fn absolute(&mut self) -> MemoryAddressingMode {
         MemoryAddressingMode{val: self.loadw_bump_pc()}
     }
     fn absolute_x(&mut self) -> MemoryAddressingMode {
-        MemoryAddressingMode{val: self.loadw_bump_pc() + self.regs.x as u16}
+        let base = self.loadw_bump_pc();
+        let x = self.regs.x;
+        MemoryAddressingMode{val: self.index(base, x)}
     }
     fn absolute_y(&mut self) -> MemoryAddressingMode {
-        MemoryAddressingMode{val: self.loadw_bump_pc() + self.regs.y as u16}
+        let base = self.loadw_bump_pc();
+        let y = self.regs.y;
+        MemoryAddressingMode{val: self.index(base, y)}
     }
     fn indexed_indirect_x(&mut self) -> MemoryAddressingMode {
         let val = self.loadb_bump_pc();
+        self.dummy_read(val as u16);
         let x = self.regs.x;
-        let addr = self.loadw_zp(val + x);
+        let addr = self.loadw_zp(val.wrapping_add(x));
         MemoryAddressingMode{val: addr}
     }
     fn indirect_indexed_y(&mut self) -> MemoryAddressingMode {
         let val = self.loadb_bump_pc();
         let y = self.regs.y;
-        let addr = self.loadw_zp(val) + y as u16;
-        MemoryAddressingMode{val: addr}
+        let base = self.loadw_zp(val);
+        MemoryAddressingMode{val: self.index(base, y)}
+    }
+    fn zero_page_indirect(&mut self) -> MemoryAddressingMode {
+        let val = self.loadb_bump_pc();
+        MemoryAddressingMode{val: self.loadw_zp(val)}
+    }
+    /// Adds an index register to a base address, noting whether a page boundary was crossed.
+    fn index(&mut self, base: u16, index: u8) -> u16 {
+        let addr = base.wrapping_add(index as u16);
+        self.page_crossed = (base ^ addr) & 0xff00 != 0;
+
+        // The first read goes to the address before the carry into the high byte. Instructions
+        // that only read skip it when there is no carry; stores and read-modify-writes never do.
+        if self.page_crossed || PAGE_CROSS_TABLE[self.opcode as usize] == 0 {
+            self.dummy_read((base & 0xff00) | (addr & 0x00ff));
+        }
+        addr
     }
 
     //
@@ -569,6 +1252,14 @@ impl<M: Mem> Cpu<M> {
     #[inline(always)]
     fn adc<AM:AddressingMode<M>>(&mut self, am: AM) {
         let val = am.load(self);
+        self.adc_base(val)
+    }
+    #[inline(always)]
+    fn adc_base(&mut self, val: u8) {
+        if self.decimal_mode() {
+            return self.adc_decimal(val);
+        }
+
         let mut result = self.regs.a as u32 + val as u32;
         if self.get_flag(CARRY_FLAG) {
             result += 1;
@@ -581,27 +1272,91 @@ impl<M: Mem> Cpu<M> {
         self.set_flag(OVERFLOW_FLAG, (a ^ val) & 0x80 == 0 && (a ^ result) & 0x80 == 0x80);
         self.regs.a = self.set_zn(result);
     }
+    // Decimal arithmetic follows Bruce Clark's "Decimal Mode" tutorial on 6502.org, including the
+    // flags for invalid BCD operands.
+    fn adc_decimal(&mut self, val: u8) {
+        let a = self.regs.a;
+        let carry = if self.get_flag(CARRY_FLAG) { 1 } else { 0 };
+
+        let mut lo = (a & 0x0f) as i16 + (val & 0x0f) as i16 + carry;
+        if lo >= 0x0a {
+            lo = ((lo + 0x06) & 0x0f) + 0x10;
+        }
+        let mut result = (a & 0xf0) as i16 + (val & 0xf0) as i16 + lo;
+
+        // The NMOS parts set N and V before the high digit is adjusted, and Z from the binary sum.
+        let signed = (a & 0xf0) as i8 as i16 + (val & 0xf0) as i8 as i16 + lo;
+        self.set_flag(OVERFLOW_FLAG, signed < -128 || signed > 127);
+        self.set_flag(NEGATIVE_FLAG, result & 0x80 != 0);
+        self.set_flag(ZERO_FLAG, a.wrapping_add(val).wrapping_add(carry as u8) == 0);
+
+        if result >= 0xa0 {
+            result += 0x60;
+        }
+        self.set_flag(CARRY_FLAG, result >= 0x100);
+        self.regs.a = result as u8;
+
+        if self.variant == Variant::Cmos65C02 {
+            let a = self.regs.a;
+            self.set_zn(a);
+            self.cy += 1;
+        }
+    }
     #[inline(always)]
     fn sbc<AM:AddressingMode<M>>(&mut self, am: AM) {
         let val = am.load(self);
+        self.sbc_base(val)
+    }
+    #[inline(always)]
+    fn sbc_base(&mut self, val: u8) {
         let a = self.regs.a;
-        let mut result = a as u32 - val as u32;
-        if !self.get_flag(CARRY_FLAG) {
-            result -= 1;
-        }
+        let borrow = if self.get_flag(CARRY_FLAG) { 0 } else { 1 };
+        let result = (a as u32).wrapping_sub(val as u32).wrapping_sub(borrow);
 
         self.set_flag(CARRY_FLAG, (result & 0x100) == 0);
 
         let result = result as u8;
-        let a = self.regs.a;
         self.set_flag(OVERFLOW_FLAG, (a ^ result) & 0x80 != 0 && (a ^ val) & 0x80 == 0x80);
         self.regs.a = self.set_zn(result);
+
+        // In decimal mode the flags still come from the binary difference, except on the 65C02.
+        if self.decimal_mode() {
+            self.sbc_decimal(a, val, borrow as i16);
+        }
+    }
+    fn sbc_decimal(&mut self, a: u8, val: u8, borrow: i16) {
+        let lo = (a & 0x0f) as i16 - (val & 0x0f) as i16 - borrow;
+        if self.variant == Variant::Cmos65C02 {
+            let mut result = a as i16 - val as i16 - borrow;
+            if result < 0 {
+                result -= 0x60;
+            }
+            if lo < 0 {
+                result -= 0x06;
+            }
+            self.regs.a = self.set_zn(result as u8);
+            self.cy += 1;
+        } else {
+            let lo = if lo < 0 { ((lo - 0x06) & 0x0f) - 0x10 } else { lo };
+            let mut result = (a & 0xf0) as i16 - (val & 0xf0) as i16 + lo;
+            if result < 0 {
+                result -= 0x60;
+            }
+            self.regs.a = result as u8;
+        }
+    }
+    /// The 2A03 has no decimal mode, so the flag only has an effect on the other variants.
+    fn decimal_mode(&self) -> bool {
+        self.get_flag(DECIMAL_FLAG) && self.variant != Variant::Ricoh2A03
     }
 
     // Comparisons
     fn cmp_base<AM:AddressingMode<M>>(&mut self, x: u8, am: AM) {
         let y = am.load(self);
-        let result = x as u32 - y as u32;
+        self.cmp_vals(x, y)
+    }
+    fn cmp_vals(&mut self, x: u8, y: u8) {
+        let result = (x as u32).wrapping_sub(y as u32);
         self.set_flag(CARRY_FLAG, (result & 0x100) == 0);
         let _ = self.set_zn(result as u8);
     }
@@ -640,8 +1395,10 @@ impl<M: Mem> Cpu<M> {
     }
 
     // Shifts and rotates
-    fn shl_base<AM:AddressingMode<M>>(&mut self, lsb: bool, am: AM) {
+    // The shift helpers return the stored result so that the unofficial opcodes can reuse them.
+    fn shl_base<AM:AddressingMode<M>>(&mut self, lsb: bool, am: &AM) -> u8 {
         let val = am.load(self);
+        am.rewrite(self, val);
         let new_carry = (val & 0x80) != 0;
         let mut result = val << 1;
         if lsb {
@@ -649,10 +1406,12 @@ impl<M: Mem> Cpu<M> {
         }
         self.set_flag(CARRY_FLAG, new_carry);
         let val = self.set_zn(result as u8);
-        am.store(self, val)
+        am.store(self, val);
+        val
     }
-    fn shr_base<AM:AddressingMode<M>>(&mut self, msb: bool, am: AM) {
+    fn shr_base<AM:AddressingMode<M>>(&mut self, msb: bool, am: &AM) -> u8 {
         let val = am.load(self);
+        am.rewrite(self, val);
         let new_carry = (val & 0x1) != 0;
         let mut result = val >> 1;
         if msb {
@@ -660,30 +1419,37 @@ impl<M: Mem> Cpu<M> {
         }
         self.set_flag(CARRY_FLAG, new_carry);
         let val = self.set_zn(result as u8);
-        am.store(self, val)
+        am.store(self, val);
+        val
     }
     fn rol<AM:AddressingMode<M>>(&mut self, am: AM) {
         let val = self.get_flag(CARRY_FLAG);
-        self.shl_base(val, am)
+        self.shl_base(val, &am);
     }
     fn ror<AM:AddressingMode<M>>(&mut self, am: AM) {
         let val = self.get_flag(CARRY_FLAG);
-        self.shr_base(val, am)
+        self.shr_base(val, &am);
     }
-    fn asl<AM:AddressingMode<M>>(&mut self, am: AM) { self.shl_base(false, am) }
-    fn lsr<AM:AddressingMode<M>>(&mut self, am: AM) { self.shr_base(false, am) }
+    fn asl<AM:AddressingMode<M>>(&mut self, am: AM) { self.shl_base(false, &am); }
+    fn lsr<AM:AddressingMode<M>>(&mut self, am: AM) { self.shr_base(false, &am); }
 
     // Increments and decrements
-    fn inc<AM:AddressingMode<M>>(&mut self, am: AM) {
+    fn inc_base<AM:AddressingMode<M>>(&mut self, am: &AM) -> u8 {
         let val = am.load(self);
-        let val = self.set_zn(val + 1);
-        am.store(self, val)
+        am.rewrite(self, val);
+        let val = self.set_zn(val.wrapping_add(1));
+        am.store(self, val);
+        val
     }
-    fn dec<AM:AddressingMode<M>>(&mut self, am: AM) {
+    fn dec_base<AM:AddressingMode<M>>(&mut self, am: &AM) -> u8 {
         let val = am.load(self);
-        let val = self.set_zn(val - 1);
-        am.store(self, val)
+        am.rewrite(self, val);
+        let val = self.set_zn(val.wrapping_sub(1));
+        am.store(self, val);
+        val
     }
+    fn inc<AM:AddressingMode<M>>(&mut self, am: AM) { self.inc_base(&am); }
+    fn dec<AM:AddressingMode<M>>(&mut self, am: AM) { self.dec_base(&am); }
     fn inx(&mut self) {
         let x = self.regs.x;
         self.regs.x = self.set_zn(x + 1)
@@ -737,7 +1503,14 @@ impl<M: Mem> Cpu<M> {
     fn bra_base(&mut self, cond: bool) {
         let disp = self.loadb_bump_pc() as i8;
         if cond {
-            self.regs.pc = (self.regs.pc as i32 + disp as i32) as u16;
+            // Taken branches cost one more cycle, and another if the target is on a different page.
+            let pc = self.regs.pc;
+            let target = (pc as i32 + disp as i32) as u16;
+            self.extra_cycle(pc);
+            if (pc ^ target) & 0xff00 != 0 {
+                self.extra_cycle((pc & 0xff00) | (target & 0x00ff));
+            }
+            self.regs.pc = target;
         }
     }
     fn bpl(&mut self) {
@@ -778,30 +1551,42 @@ impl<M: Mem> Cpu<M> {
     fn jmpi(&mut self) {
         let addr = self.loadw_bump_pc();
 
-        // Replicate the famous CPU bug...
+        // Replicate the famous CPU bug... which the 65C02 fixed.
         let lo = self.loadb(addr);
-        let hi = self.loadb((addr & 0xff00) | ((addr + 1) & 0x00ff));
+        let hi_addr = if self.variant == Variant::Cmos65C02 {
+            addr.wrapping_add(1)
+        } else {
+            (addr & 0xff00) | (addr.wrapping_add(1) & 0x00ff)
+        };
+        let hi = self.loadb(hi_addr);
 
         self.regs.pc = (hi as u16) << 8 | lo as u16;
     }
 
     // Procedure calls
     fn jsr(&mut self) {
-        let addr = self.loadw_bump_pc();
+        // The high byte of the target is only fetched after the return address has been pushed,
+        // so the pushed address points at it.
+        let lo = self.loadb_bump_pc();
+        self.dummy_read_stack();
         let pc = self.regs.pc;
-        self.pushw(pc - 1);
-        self.regs.pc = addr;
+        self.pushw(pc);
+        let hi = self.loadb_as(pc, Access::Code);
+        self.regs.pc = (hi as u16) << 8 | lo as u16;
+    }
+    fn rts(&mut self) {
+        self.dummy_read_stack();
+        self.regs.pc = self.popw();
+        self.dummy_read_pc();
+        self.regs.pc += 1;
     }
-    fn rts(&mut self) { self.regs.pc = self.popw() + 1 }
     fn brk(&mut self) {
-        self.set_flag(IRQ_FLAG, true);
-        let pc = self.regs.pc;
-        self.pushw(pc + 1);
-        let flags = self.regs.flags;
-        self.pushb(flags | BREAK_FLAG);
-        self.regs.pc = self.loadw(BRK_VECTOR);
+        // BRK reads and skips the padding byte after the opcode.
+        let _ = self.loadb_bump_pc();
+        self.interrupt(true)
     }
     fn rti(&mut self) {
+        self.dummy_read_stack();
         let flags = self.popb();
         self.set_flags(flags);
         self.regs.pc = self.popw(); // NB: no + 1
@@ -813,6 +1598,7 @@ impl<M: Mem> Cpu<M> {
         self.pushb(a)
     }
     fn pla(&mut self) {
+        self.dummy_read_stack();
         let val = self.popb();
         self.regs.a = self.set_zn(val)
     }
@@ -822,10 +1608,215 @@ impl<M: Mem> Cpu<M> {
         // NB: Setting BREAK is documented in the nesdev wiki but not in the MCS6500 manual!
     }
     fn plp(&mut self) {
+        self.dummy_read_stack();
         let val = self.popb();
         self.set_flags(val)
     }
 
     // No operation
     fn nop(&mut self) {}
+
+    //
+    // Unofficial instructions
+    //
+
+    // Combined loads and stores
+    fn lax<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let val = am.load(self);
+        self.regs.x = val;
+        self.regs.a = self.set_zn(val)
+    }
+    fn sax<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let val = self.regs.a & self.regs.x;
+        am.store(self, val)
+    }
+
+    // Read-modify-write combinations
+    fn dcp<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let val = self.dec_base(&am);
+        let a = self.regs.a;
+        self.cmp_vals(a, val)
+    }
+    fn isc<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let val = self.inc_base(&am);
+        self.sbc_base(val)
+    }
+    fn slo<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let val = self.shl_base(false, &am) | self.regs.a;
+        self.regs.a = self.set_zn(val)
+    }
+    fn rla<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let carry = self.get_flag(CARRY_FLAG);
+        let val = self.shl_base(carry, &am) & self.regs.a;
+        self.regs.a = self.set_zn(val)
+    }
+    fn sre<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let val = self.shr_base(false, &am) ^ self.regs.a;
+        self.regs.a = self.set_zn(val)
+    }
+    fn rra<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let carry = self.get_flag(CARRY_FLAG);
+        let val = self.shr_base(carry, &am);
+        self.adc_base(val)
+    }
+
+    // Immediate-only operations
+    fn anc<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let val = am.load(self) & self.regs.a;
+        self.regs.a = self.set_zn(val);
+        self.set_flag(CARRY_FLAG, (val & 0x80) != 0);
+    }
+    fn alr<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let val = am.load(self) & self.regs.a;
+        self.regs.a = val;
+        self.shr_base(false, &AccumulatorAddressingMode);
+    }
+    fn arr<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let val = am.load(self) & self.regs.a;
+        let mut result = val >> 1;
+        if self.get_flag(CARRY_FLAG) {
+            result |= 0x80;
+        }
+        self.regs.a = self.set_zn(result);
+        self.set_flag(CARRY_FLAG, (result & 0x40) != 0);
+        self.set_flag(OVERFLOW_FLAG, ((result >> 6) ^ (result >> 5)) & 1 != 0);
+    }
+    fn axs<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let val = am.load(self);
+        let x = self.regs.a & self.regs.x;
+        self.set_flag(CARRY_FLAG, x >= val);
+        self.regs.x = self.set_zn(x.wrapping_sub(val))
+    }
+    // XAA and LXA depend on analog effects; $EE is the constant most consoles exhibit.
+    fn xaa<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let val = (self.regs.a | 0xee) & self.regs.x & am.load(self);
+        self.regs.a = self.set_zn(val)
+    }
+    fn lxa<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let val = (self.regs.a | 0xee) & am.load(self);
+        self.regs.x = val;
+        self.regs.a = self.set_zn(val)
+    }
+
+    // Unstable stores. These write `val & (H + 1)`, where H is the high byte of the base address;
+    // if indexing crossed a page the written value also replaces the high byte of the address.
+    fn unstable_store(&mut self, addr: u16, index: u8, val: u8) {
+        let base = addr.wrapping_sub(index as u16);
+        let val = val & ((base >> 8) as u8).wrapping_add(1);
+        let addr = if (base ^ addr) & 0xff00 != 0 {
+            ((val as u16) << 8) | (addr & 0xff)
+        } else {
+            addr
+        };
+        self.storeb(addr, val)
+    }
+    fn shy(&mut self, am: MemoryAddressingMode) {
+        let (x, y) = (self.regs.x, self.regs.y);
+        self.unstable_store(*am, x, y)
+    }
+    fn shx(&mut self, am: MemoryAddressingMode) {
+        let (x, y) = (self.regs.x, self.regs.y);
+        self.unstable_store(*am, y, x)
+    }
+    fn ahx(&mut self, am: MemoryAddressingMode) {
+        let (val, y) = (self.regs.a & self.regs.x, self.regs.y);
+        self.unstable_store(*am, y, val)
+    }
+    fn tas(&mut self, am: MemoryAddressingMode) {
+        self.regs.s = self.regs.a & self.regs.x;
+        let (val, y) = (self.regs.s, self.regs.y);
+        self.unstable_store(*am, y, val)
+    }
+    fn las<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let val = am.load(self) & self.regs.s;
+        self.regs.s = val;
+        self.regs.x = val;
+        self.regs.a = self.set_zn(val)
+    }
+
+    // No operation that still performs the operand read
+    fn ign<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let _ = am.load(self);
+    }
+
+    // Processor lock-up. The CPU stops fetching, so we keep PC on the KIL opcode forever. The
+    // 65C02's STP behaves the same way.
+    fn kil(&mut self) {
+        self.regs.pc -= 1;
+        self.halted = true;
+    }
+
+    //
+    // 65C02 instructions
+    //
+
+    // Stores and bit tests
+    fn stz<AM:AddressingMode<M>>(&mut self, am: AM) { am.store(self, 0) }
+    fn bit_immediate<AM:AddressingMode<M>>(&mut self, am: AM) {
+        // Only Z is affected, since there is no memory operand to take N and V from.
+        let val = am.load(self) & self.regs.a;
+        self.set_flag(ZERO_FLAG, val == 0);
+    }
+    fn tsb<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let val = am.load(self);
+        let a = self.regs.a;
+        self.set_flag(ZERO_FLAG, (val & a) == 0);
+        am.store(self, val | a)
+    }
+    fn trb<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let val = am.load(self);
+        let a = self.regs.a;
+        self.set_flag(ZERO_FLAG, (val & a) == 0);
+        am.store(self, val & !a)
+    }
+
+    // Single bit operations (originally Rockwell's)
+    fn rmb<AM:AddressingMode<M>>(&mut self, bit: u8, am: AM) {
+        let val = am.load(self) & !(1 << bit);
+        am.store(self, val)
+    }
+    fn smb<AM:AddressingMode<M>>(&mut self, bit: u8, am: AM) {
+        let val = am.load(self) | (1 << bit);
+        am.store(self, val)
+    }
+    fn bbr(&mut self, bit: u8) {
+        let am = self.zero_page();
+        let val = am.load(self);
+        self.bra_base(val & (1 << bit) == 0)
+    }
+    fn bbs(&mut self, bit: u8) {
+        let am = self.zero_page();
+        let val = am.load(self);
+        self.bra_base(val & (1 << bit) != 0)
+    }
+
+    // Branches and jumps
+    fn bra(&mut self) { self.bra_base(true) }
+    fn jmp_indexed_x(&mut self) {
+        let addr = self.loadw_bump_pc().wrapping_add(self.regs.x as u16);
+        let lo = self.loadb(addr);
+        let hi = self.loadb(addr.wrapping_add(1));
+        self.regs.pc = (hi as u16) << 8 | lo as u16;
+    }
+
+    // Stack operations
+    fn phx(&mut self) {
+        let x = self.regs.x;
+        self.pushb(x)
+    }
+    fn phy(&mut self) {
+        let y = self.regs.y;
+        self.pushb(y)
+    }
+    fn plx(&mut self) {
+        let val = self.popb();
+        self.regs.x = self.set_zn(val)
+    }
+    fn ply(&mut self) {
+        let val = self.popb();
+        self.regs.y = self.set_zn(val)
+    }
+
+    // Stops executing until an interrupt is requested.
+    fn wai(&mut self) { self.waiting = true }
 }
@@ -2,8 +2,263 @@
 // Author: Patrick Walton
 //
 
-use mem::Mem;
+use mem::{self, Mem};
 
+use std::fmt;
+
+// Defines the `Mnemonic` enum along with the text of each mnemonic.
+macro_rules! mnemonics {
+    ($($variant:ident => $text:expr),*) => {
+        #[derive(Copy, Clone, PartialEq, Eq, Debug)]
+        pub enum Mnemonic {
+            $($variant),*
+        }
+
+        impl Mnemonic {
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Mnemonic::$variant => $text),*
+                }
+            }
+        }
+    }
+}
+
+mnemonics!(
+    // Official instructions
+    Lda => "LDA", Ldx => "LDX", Ldy => "LDY",
+    Sta => "STA", Stx => "STX", Sty => "STY",
+    Adc => "ADC", Sbc => "SBC",
+    Cmp => "CMP", Cpx => "CPX", Cpy => "CPY",
+    And => "AND", Ora => "ORA", Eor => "EOR", Bit => "BIT",
+    Rol => "ROL", Ror => "ROR", Asl => "ASL", Lsr => "LSR",
+    Inc => "INC", Dec => "DEC", Inx => "INX", Dex => "DEX", Iny => "INY", Dey => "DEY",
+    Tax => "TAX", Tay => "TAY", Txa => "TXA", Tya => "TYA", Txs => "TXS", Tsx => "TSX",
+    Clc => "CLC", Sec => "SEC", Cli => "CLI", Sei => "SEI", Clv => "CLV", Cld => "CLD",
+    Sed => "SED",
+    Bpl => "BPL", Bmi => "BMI", Bvc => "BVC", Bvs => "BVS",
+    Bcc => "BCC", Bcs => "BCS", Bne => "BNE", Beq => "BEQ",
+    Jmp => "JMP", Jsr => "JSR", Rts => "RTS", Brk => "BRK", Rti => "RTI",
+    Pha => "PHA", Pla => "PLA", Php => "PHP", Plp => "PLP",
+    Nop => "NOP",
+
+    // Unofficial instructions
+    Lax => "LAX", Sax => "SAX",
+    Dcp => "DCP", Isc => "ISC", Slo => "SLO", Rla => "RLA", Sre => "SRE", Rra => "RRA",
+    Anc => "ANC", Alr => "ALR", Arr => "ARR", Axs => "AXS", Xaa => "XAA", Lxa => "LXA",
+    Shy => "SHY", Shx => "SHX", Ahx => "AHX", Tas => "TAS", Las => "LAS",
+    Kil => "KIL"
+);
+
+/// How an instruction finds its operand.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum AddressingMode {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    /// JMP ($nnnn)
+    Indirect,
+    /// ($nn,X)
+    IndexedIndirectX,
+    /// ($nn),Y
+    IndirectIndexedY,
+    /// Branch displacements
+    Relative,
+}
+
+/// Where an instruction's memory operand pointed just before it ran.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct EffectiveAddress {
+    pub addr: u16,
+    /// The value there, unless reading it would have had side effects.
+    pub val: Option<u8>,
+}
+
+/// A decoded instruction.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Instruction {
+    /// The address of the opcode.
+    pub addr: u16,
+    pub opcode: u8,
+    pub mnemonic: Mnemonic,
+    pub mode: AddressingMode,
+    /// The operand bytes, little-endian; zero if there are none. Branches hold their displacement.
+    pub operand: u16,
+    /// The length of the instruction in bytes, including the opcode.
+    pub len: u8,
+    /// Where a branch, JMP or JSR goes, if it can be known without running the code.
+    pub target: Option<u16>,
+}
+
+impl Instruction {
+    /// Returns false for the unofficial opcodes, including the alternate encodings of SBC and NOP.
+    pub fn is_official(&self) -> bool {
+        match self.mnemonic {
+            Mnemonic::Lax | Mnemonic::Sax | Mnemonic::Dcp | Mnemonic::Isc | Mnemonic::Slo |
+            Mnemonic::Rla | Mnemonic::Sre | Mnemonic::Rra | Mnemonic::Anc | Mnemonic::Alr |
+            Mnemonic::Arr | Mnemonic::Axs | Mnemonic::Xaa | Mnemonic::Lxa | Mnemonic::Shy |
+            Mnemonic::Shx | Mnemonic::Ahx | Mnemonic::Tas | Mnemonic::Las | Mnemonic::Kil => false,
+            Mnemonic::Sbc => self.opcode != 0xeb,
+            Mnemonic::Nop => self.opcode == 0xea,
+            _ => true,
+        }
+    }
+
+    /// Returns true if the instruction stores to its memory operand.
+    pub fn writes_memory(&self) -> bool {
+        match self.mnemonic {
+            Mnemonic::Sta | Mnemonic::Stx | Mnemonic::Sty | Mnemonic::Sax | Mnemonic::Shy |
+            Mnemonic::Shx | Mnemonic::Ahx | Mnemonic::Tas => true,
+            Mnemonic::Asl | Mnemonic::Lsr | Mnemonic::Rol | Mnemonic::Ror | Mnemonic::Inc |
+            Mnemonic::Dec | Mnemonic::Dcp | Mnemonic::Isc | Mnemonic::Slo | Mnemonic::Rla |
+            Mnemonic::Sre | Mnemonic::Rra => self.mode != AddressingMode::Accumulator,
+            _ => false,
+        }
+    }
+
+    /// Works out the address of the memory operand from the index registers and the pointers in
+    /// memory. For JMP (indirect) this is where the jump goes. Returns None for instructions that
+    /// don't access memory through an operand, and when a pointer can't be read.
+    pub fn effective_address<M: Mem>(&self, x: u8, y: u8, mem: &mut M) -> Option<EffectiveAddress> {
+        let operand = self.operand;
+        let addr = match self.mode {
+            AddressingMode::Implied | AddressingMode::Accumulator | AddressingMode::Immediate |
+            AddressingMode::Relative => return None,
+            AddressingMode::Absolute if self.target.is_some() => return None,
+            AddressingMode::ZeroPage | AddressingMode::Absolute => operand,
+            AddressingMode::ZeroPageX => (operand as u8).wrapping_add(x) as u16,
+            AddressingMode::ZeroPageY => (operand as u8).wrapping_add(y) as u16,
+            AddressingMode::AbsoluteX => operand.wrapping_add(x as u16),
+            AddressingMode::AbsoluteY => operand.wrapping_add(y as u16),
+            AddressingMode::Indirect => {
+                // The pointer's high byte comes from the same page, as on the NMOS 6502.
+                let hi_addr = (operand & 0xff00) | (operand.wrapping_add(1) & 0x00ff);
+                let target = match (mem.peekb(operand), mem.peekb(hi_addr)) {
+                    (Some(lo), Some(hi)) => (hi as u16) << 8 | lo as u16,
+                    _ => return None,
+                };
+                return Some(EffectiveAddress { addr: target, val: None });
+            }
+            AddressingMode::IndexedIndirectX => {
+                let pointer = (operand as u8).wrapping_add(x);
+                match peekw_zp(mem, pointer) {
+                    Some(addr) => addr,
+                    None => return None,
+                }
+            }
+            AddressingMode::IndirectIndexedY => {
+                match peekw_zp(mem, operand as u8) {
+                    Some(addr) => addr.wrapping_add(y as u16),
+                    None => return None,
+                }
+            }
+        };
+        // Stores to ROM go to mapper registers, whose contents can't be read back.
+        let val = if addr >= 0x8000 && self.writes_memory() { None } else { mem.peekb(addr) };
+        Some(EffectiveAddress { addr: addr, val: val })
+    }
+
+    /// Formats what `effective_address` found as it is appended to live traces: ` @ $0634 = $7F`.
+    /// The address is only shown for modes where it isn't already spelled out by the operand.
+    pub fn format_effective<F>(&self, effective: &EffectiveAddress, symbol: F) -> String
+                               where F: Fn(u16) -> Option<String> {
+        let mut text = String::new();
+        match self.mode {
+            AddressingMode::ZeroPage | AddressingMode::Absolute => {}
+            AddressingMode::ZeroPageX | AddressingMode::ZeroPageY => {
+                let addr = symbol(effective.addr).unwrap_or(format!("${:02X}", effective.addr));
+                text.push_str(&format!(" @ {}", addr));
+            }
+            _ => {
+                let addr = symbol(effective.addr).unwrap_or(format!("${:04X}", effective.addr));
+                text.push_str(&format!(" @ {}", addr));
+            }
+        }
+        if let Some(val) = effective.val {
+            text.push_str(&format!(" = ${:02X}", val));
+        }
+        text
+    }
+
+    /// The address of the next instruction in memory.
+    pub fn next_addr(&self) -> u16 {
+        self.addr.wrapping_add(self.len as u16)
+    }
+
+    /// Formats the instruction like `Display` does, except that addresses for which `symbol`
+    /// returns a name are replaced by it.
+    pub fn format_with<F>(&self, symbol: F) -> String where F: Fn(u16) -> Option<String> {
+        let name = self.mnemonic.name();
+        let operand = self.operand;
+        let zp = || symbol(operand).unwrap_or(format!("${:02X}", operand));
+        let abs = || symbol(operand).unwrap_or(format!("${:04X}", operand));
+        match self.mode {
+            AddressingMode::Implied => name.to_owned(),
+            AddressingMode::Accumulator => format!("{} ", name),
+            AddressingMode::Immediate => format!("{} #${:02X}", name, operand),
+            AddressingMode::ZeroPage => format!("{} {}", name, zp()),
+            AddressingMode::ZeroPageX => format!("{} {},X", name, zp()),
+            AddressingMode::ZeroPageY => format!("{} {},Y", name, zp()),
+            AddressingMode::Absolute => format!("{} {}", name, abs()),
+            AddressingMode::AbsoluteX => format!("{} {},X", name, abs()),
+            AddressingMode::AbsoluteY => format!("{} {},Y", name, abs()),
+            AddressingMode::Indirect => format!("{} ({})", name, abs()),
+            AddressingMode::IndexedIndirectX => format!("{} ({},X)", name, zp()),
+            AddressingMode::IndirectIndexedY => format!("{} ({}),Y", name, zp()),
+            AddressingMode::Relative => {
+                match self.target.and_then(|target| symbol(target)) {
+                    Some(label) => format!("{} {}", name, label),
+                    None => format!("{} {:+}", name, operand as u8 as i8),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(&self.format_with(|addr| mem::register_name(addr).map(str::to_owned)))
+    }
+}
+
+fn peekw_zp<M: Mem>(mem: &mut M, addr: u8) -> Option<u16> {
+    match (mem.peekb(addr as u16), mem.peekb(addr.wrapping_add(1) as u16)) {
+        (Some(lo), Some(hi)) => Some((hi as u16) << 8 | lo as u16),
+        _ => None,
+    }
+}
+
+/// An addressing mode together with the operand bytes it consumed.
+type Operand = (AddressingMode, u16);
+
+// Generates mnemonic methods that take an operand.
+macro_rules! operand_mnemonics {
+    ($($name:ident => $mnemonic:ident),*) => {
+        $(fn $name(&mut self, am: Operand) -> (Mnemonic, Operand) { (Mnemonic::$mnemonic, am) })*
+    }
+}
+
+// Generates mnemonic methods for instructions without an operand.
+macro_rules! implied_mnemonics {
+    ($($name:ident => $mnemonic:ident),*) => {
+        $(fn $name(&mut self) -> (Mnemonic, Operand) {
+            (Mnemonic::$mnemonic, (AddressingMode::Implied, 0))
+        })*
+    }
+}
+
+// Generates mnemonic methods for branches.
+macro_rules! branch_mnemonics {
+    ($($name:ident => $mnemonic:ident),*) => {
+        $(fn $name(&mut self) -> (Mnemonic, Operand) { (Mnemonic::$mnemonic, self.relative()) })*
+    }
+}
 
 pub struct Disassembler<'a, M: Mem + 'a> {
     pub pc: u16,
@@ -12,12 +267,12 @@ pub struct Disassembler<'a, M: Mem + 'a> {
 
 impl<'a, M: Mem> Disassembler<'a, M> {
     //
-    // Loads and byte-to-string conversion
+    // Loads
     //
 
     fn loadb_bump_pc(&mut self) -> u8 {
         let val = (&mut *self.mem).loadb(self.pc);
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
         val
     }
     fn loadw_bump_pc(&mut self) -> u16 {
@@ -26,150 +281,110 @@ impl<'a, M: Mem> Disassembler<'a, M> {
         bottom | top
     }
 
-    fn disb_bump_pc(&mut self) -> String {
-        format!("${:02X}", self.loadb_bump_pc() as usize)
-    }
-    fn disw_bump_pc(&mut self) -> String {
-        format!("${:04X}", self.loadw_bump_pc() as usize)
-    }
-
     //
     // Mnemonics
     //
 
-    // TODO: When we get method macros some of this ugly duplication can go away.
+    operand_mnemonics!(
+        lda => Lda, ldx => Ldx, ldy => Ldy,
+        sta => Sta, stx => Stx, sty => Sty,
+        adc => Adc, sbc => Sbc,
+        cmp => Cmp, cpx => Cpx, cpy => Cpy,
+        and => And, ora => Ora, eor => Eor, bit => Bit,
+        rol => Rol, ror => Ror, asl => Asl, lsr => Lsr,
+        inc => Inc, dec => Dec,
 
-    // Loads
-    fn lda(&mut self, am: String) -> String { format!("LDA {}", am) }
-    fn ldx(&mut self, am: String) -> String { format!("LDX {}", am) }
-    fn ldy(&mut self, am: String) -> String { format!("LDY {}", am) }
-
-    // Stores
-    fn sta(&mut self, am: String) -> String { format!("STA {}", am) }
-    fn stx(&mut self, am: String) -> String { format!("STX {}", am) }
-    fn sty(&mut self, am: String) -> String { format!("STY {}", am) }
-
-    // Arithmetic
-    fn adc(&mut self, am: String) -> String { format!("ADC {}", am) }
-    fn sbc(&mut self, am: String) -> String { format!("SBC {}", am) }
-
-    // Comparisons
-    fn cmp(&mut self, am: String) -> String { format!("CMP {}", am) }
-    fn cpx(&mut self, am: String) -> String { format!("CPX {}", am) }
-    fn cpy(&mut self, am: String) -> String { format!("CPY {}", am) }
-
-    // Bitwise operations
-    fn and(&mut self, am: String) -> String { format!("AND {}", am) }
-    fn ora(&mut self, am: String) -> String { format!("ORA {}", am) }
-    fn eor(&mut self, am: String) -> String { format!("EOR {}", am) }
-    fn bit(&mut self, am: String) -> String { format!("BIT {}", am) }
-
-    // Shifts and rotates
-    fn rol(&mut self, am: String) -> String { format!("ROL {}", am) }
-    fn ror(&mut self, am: String) -> String { format!("ROR {}", am) }
-    fn asl(&mut self, am: String) -> String { format!("ASL {}", am) }
-    fn lsr(&mut self, am: String) -> String { format!("LSR {}", am) }
-
-    // Increments and decrements
-    fn inc(&mut self, am: String) -> String { format!("INC {}", am) }
-    fn dec(&mut self, am: String) -> String { format!("DEC {}", am) }
-    fn inx(&mut self) -> String           { "INX".to_owned()       }
-    fn dex(&mut self) -> String           { "DEX".to_owned()       }
-    fn iny(&mut self) -> String           { "INY".to_owned()       }
-    fn dey(&mut self) -> String           { "DEY".to_owned()       }
-
-    // Register moves
-    fn tax(&mut self) -> String           { "TAX".to_owned()       }
-    fn tay(&mut self) -> String           { "TAY".to_owned()       }
-    fn txa(&mut self) -> String           { "TXA".to_owned()       }
-    fn tya(&mut self) -> String           { "TYA".to_owned()       }
-    fn txs(&mut self) -> String           { "TXS".to_owned()       }
-    fn tsx(&mut self) -> String           { "TSX".to_owned()       }
-
-    // Flag operations
-    fn clc(&mut self) -> String           { "CLC".to_owned()       }
-    fn sec(&mut self) -> String           { "SEC".to_owned()       }
-    fn cli(&mut self) -> String           { "CLI".to_owned()       }
-    fn sei(&mut self) -> String           { "SEI".to_owned()       }
-    fn clv(&mut self) -> String           { "CLV".to_owned()       }
-    fn cld(&mut self) -> String           { "CLD".to_owned()       }
-    fn sed(&mut self) -> String           { "SED".to_owned()       }
-
-    // Branches
-    fn bpl(&mut self) -> String           { format!("BPL {}", self.relative()) }
-    fn bmi(&mut self) -> String           { format!("BMI {}", self.relative()) }
-    fn bvc(&mut self) -> String           { format!("BVC {}", self.relative()) }
-    fn bvs(&mut self) -> String           { format!("BVS {}", self.relative()) }
-    fn bcc(&mut self) -> String           { format!("BCC {}", self.relative()) }
-    fn bcs(&mut self) -> String           { format!("BCS {}", self.relative()) }
-    fn bne(&mut self) -> String           { format!("BNE {}", self.relative()) }
-    fn beq(&mut self) -> String           { format!("BEQ {}", self.relative()) }
+        lax => Lax, sax => Sax,
+        dcp => Dcp, isc => Isc, slo => Slo, rla => Rla, sre => Sre, rra => Rra,
+        anc => Anc, alr => Alr, arr => Arr, axs => Axs, xaa => Xaa, lxa => Lxa,
+        shy => Shy, shx => Shx, ahx => Ahx, tas => Tas, las => Las,
+        ign => Nop
+    );
 
-    // Jumps
-    fn jmp(&mut self) -> String           { format!("JMP {}", self.disw_bump_pc()) }
-    fn jmpi(&mut self) -> String          { format!("JMP ({})", self.disw_bump_pc())  }
-
-    // Procedure calls
-    fn jsr(&mut self) -> String           { format!("JSR {}", self.disw_bump_pc()) }
-    fn rts(&mut self) -> String           { "RTS".to_owned()       }
-    fn brk(&mut self) -> String           { "BRK".to_owned()       }
-    fn rti(&mut self) -> String           { "RTI".to_owned()       }
+    implied_mnemonics!(
+        inx => Inx, dex => Dex, iny => Iny, dey => Dey,
+        tax => Tax, tay => Tay, txa => Txa, tya => Tya, txs => Txs, tsx => Tsx,
+        clc => Clc, sec => Sec, cli => Cli, sei => Sei, clv => Clv, cld => Cld, sed => Sed,
+        rts => Rts, brk => Brk, rti => Rti,
+        pha => Pha, pla => Pla, php => Php, plp => Plp,
+        nop => Nop, kil => Kil
+    );
 
-    // Stack operations
-    fn pha(&mut self) -> String           { "PHA".to_owned()       }
-    fn pla(&mut self) -> String           { "PLA".to_owned()       }
-    fn php(&mut self) -> String           { "PHP".to_owned()       }
-    fn plp(&mut self) -> String           { "PLP".to_owned()       }
+    branch_mnemonics!(
+        bpl => Bpl, bmi => Bmi, bvc => Bvc, bvs => Bvs,
+        bcc => Bcc, bcs => Bcs, bne => Bne, beq => Beq
+    );
 
-    // No operation
-    fn nop(&mut self) -> String           { "NOP".to_owned()       }
+    // Jumps
+    fn jmp(&mut self) -> (Mnemonic, Operand)  { (Mnemonic::Jmp, self.absolute()) }
+    fn jmpi(&mut self) -> (Mnemonic, Operand) {
+        (Mnemonic::Jmp, (AddressingMode::Indirect, self.loadw_bump_pc()))
+    }
+    fn jsr(&mut self) -> (Mnemonic, Operand)  { (Mnemonic::Jsr, self.absolute()) }
 
+    //
     // Addressing modes
-    fn immediate(&mut self) -> String {
-        format!("#{}", self.disb_bump_pc())
+    //
+
+    fn immediate(&mut self) -> Operand {
+        (AddressingMode::Immediate, self.loadb_bump_pc() as u16)
     }
-    fn accumulator(&mut self) -> String {
-        String::new()
+    fn accumulator(&mut self) -> Operand {
+        (AddressingMode::Accumulator, 0)
     }
-    fn zero_page(&mut self) -> String {
-        self.disb_bump_pc()
+    fn zero_page(&mut self) -> Operand {
+        (AddressingMode::ZeroPage, self.loadb_bump_pc() as u16)
     }
-    fn zero_page_x(&mut self) -> String {
-        let mut buf = self.disb_bump_pc();
-        buf.push_str(",X");
-        buf
+    fn zero_page_x(&mut self) -> Operand {
+        (AddressingMode::ZeroPageX, self.loadb_bump_pc() as u16)
     }
-    fn zero_page_y(&mut self) -> String {
-        let mut buf = self.disb_bump_pc();
-        buf.push_str(",Y");
-        buf
+    fn zero_page_y(&mut self) -> Operand {
+        (AddressingMode::ZeroPageY, self.loadb_bump_pc() as u16)
     }
-    fn absolute(&mut self) -> String      { self.disw_bump_pc() }
-    fn absolute_x(&mut self) -> String {
-        let mut buf = self.disw_bump_pc();
-        buf.push_str(",X");
-        buf
+    fn absolute(&mut self) -> Operand {
+        (AddressingMode::Absolute, self.loadw_bump_pc())
     }
-    fn absolute_y(&mut self) -> String {
-        let mut buf = self.disw_bump_pc();
-        buf.push_str(",Y");
-        buf
+    fn absolute_x(&mut self) -> Operand {
+        (AddressingMode::AbsoluteX, self.loadw_bump_pc())
     }
-    fn indexed_indirect_x(&mut self) -> String {
-        format!("({},X)", self.disb_bump_pc())
+    fn absolute_y(&mut self) -> Operand {
+        (AddressingMode::AbsoluteY, self.loadw_bump_pc())
     }
-    fn indirect_indexed_y(&mut self) -> String {
-        format!("({}),Y", self.disb_bump_pc())
+    fn indexed_indirect_x(&mut self) -> Operand {
+        (AddressingMode::IndexedIndirectX, self.loadb_bump_pc() as u16)
     }
-    // Special mode only used in comparison opcodes
-    fn relative(&mut self) -> String {
-        format!("{:+}", self.loadb_bump_pc() as i8)
+    fn indirect_indexed_y(&mut self) -> Operand {
+        (AddressingMode::IndirectIndexedY, self.loadb_bump_pc() as u16)
+    }
+    // Special mode only used in branch opcodes
+    fn relative(&mut self) -> Operand {
+        (AddressingMode::Relative, self.loadb_bump_pc() as u16)
     }
 
-    // The main disassembly routine.
+    // The main disassembly routine. Decodes the instruction at PC and leaves PC after it.
     #[inline(never)]
-    pub fn disassemble(&mut self) -> String {
+    pub fn disassemble(&mut self) -> Instruction {
+        let addr = self.pc;
         let op = self.loadb_bump_pc();
-        decode_op!(op, self)
+        let (mnemonic, (mode, operand)) = decode_op!(op, self);
+
+        let target = match (mnemonic, mode) {
+            (_, AddressingMode::Relative) => {
+                Some(self.pc.wrapping_add(operand as u8 as i8 as u16))
+            }
+            (Mnemonic::Jmp, AddressingMode::Absolute) |
+            (Mnemonic::Jsr, AddressingMode::Absolute) => Some(operand),
+            _ => None,
+        };
+
+        Instruction {
+            addr: addr,
+            opcode: op,
+            mnemonic: mnemonic,
+            mode: mode,
+            operand: operand,
+            len: self.pc.wrapping_sub(addr) as u8,
+            target: target,
+        }
     }
 }
@@ -13,7 +13,9 @@ extern crate time;
 pub mod util;
 
 pub mod apu;
+pub mod asm;
 pub mod audio;
+pub mod cdl;
 #[macro_use]
 pub mod cpu;
 pub mod disasm;
@@ -21,25 +23,34 @@ pub mod gfx;
 pub mod input;
 pub mod mapper;
 pub mod mem;
+pub mod nestest;
+pub mod palette;
 pub mod ppu;
 pub mod rom;
 pub mod resampler;
+pub mod static_disasm;
+pub mod symbols;
+pub mod tracer;
 
 use apu::Apu;
+use cdl::CodeDataLog;
 use cpu::Cpu;
 use gfx::Gfx;
 use input::Input;
 use mapper::Mapper;
-use mem::MemMap;
+use mem::{Mem, MemMap};
+use palette::Palette;
 use ppu::{Oam, Ppu, Vram};
 use rom::Rom;
+use symbols::SymbolTable;
+use tracer::Tracer;
 use util::Save;
 
 use sdl2::EventPump;
 
-use std::cell::RefCell;
+use std::cell::{Ref, RefCell};
 use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
 fn record_fps(last_time: &mut f64, frames: &mut usize) {
@@ -55,17 +66,34 @@ fn record_fps(last_time: &mut f64, frames: &mut usize) {
     }
 }
 
+/// Executes one CPU instruction, lets the PPU and APU catch up, and routes their interrupt outputs
+/// back to the CPU. Returns true if a frame was completed.
+pub fn step(cpu: &mut Cpu<MemMap>) -> bool {
+    cpu.step();
+
+    // In cycle-accurate mode the devices have already been clocked up to the last access; this
+    // only covers the cycle of that access.
+    let cy = cpu.cy;
+    if let Some(lines) = cpu.mem.clock(cy) {
+        cpu.set_interrupt_lines(lines);
+    }
+
+    cpu.mem.take_new_frame()
+}
+
 pub struct Emulator {
     cpu: Cpu<MemMap>,
+    rom: Rom,
     gfx: Gfx<'static>,
     event_pump: EventPump,
     pub mute: bool,
+    pub tracer: Tracer,
+    cdl: Option<Rc<RefCell<CodeDataLog>>>,
 }
 
 impl Emulator {
     /// Creates a new emulator and window
     pub fn new(rom: Rom, scale: f32) -> Emulator {
-        let rom = Box::new(rom);
         println!("Loaded ROM: {}", rom.header);
 
         let sdl = sdl2::init().unwrap();
@@ -75,23 +103,77 @@ impl Emulator {
         let gfx = Gfx::new(&video, scale);
         let audio_buffer = audio::open(&audio);
 
-        let mapper: Box<Mapper+Send> = mapper::create_mapper(rom);
+        let mapper: Box<Mapper+Send> = mapper::create_mapper(Box::new(rom.clone()));
         let mapper = Rc::new(RefCell::new(mapper));
         let ppu = Ppu::new(Vram::new(mapper.clone()), Oam::new());
         let input = Input::new();
         let apu = Apu::new(audio_buffer);
         let memmap = MemMap::new(ppu, input, mapper, apu);
         let mut cpu = Cpu::new(memmap);
-
-        // TODO: Add a flag to not reset for nestest.log
         cpu.reset();
 
-        Emulator {
+        let mut emulator = Emulator {
             cpu: cpu,
+            rom: rom,
             gfx: gfx,
             event_pump: event_pump,
             mute: false,
+            tracer: Tracer::new(tracer::DEFAULT_CAPACITY, PathBuf::from("trace.log")),
+            cdl: None,
+        };
+        emulator.set_symbols(SymbolTable::new());
+        emulator
+    }
+
+    /// Switches between cycle-accurate and per-instruction CPU timing.
+    pub fn set_cycle_accurate(&mut self, cycle_accurate: bool) {
+        self.cpu.cycle_accurate = cycle_accurate;
+    }
+
+    /// Sets the names used for addresses in traces. The loaded mapper's registers are named too.
+    pub fn set_symbols(&mut self, mut symbols: SymbolTable) {
+        symbols.mapper = Some(self.rom.header.ines_mapper());
+        let symbols = Rc::new(symbols);
+        self.cpu.symbols = symbols.clone();
+        self.tracer.set_symbols(symbols);
+    }
+
+    /// Sets the colors the screen is drawn with.
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.cpu.mem.ppu.set_palette(palette);
+    }
+
+    /// Starts logging which parts of PRG-ROM and CHR-ROM are used as code and data.
+    pub fn start_code_data_log(&mut self, log: CodeDataLog) {
+        let log = Rc::new(RefCell::new(log));
+        self.cpu.mem.set_code_data_log(Some(log.clone()));
+        self.cdl = Some(log);
+    }
+
+    /// Returns the code/data log, if logging was started.
+    pub fn code_data_log(&self) -> Option<Ref<'_, CodeDataLog>> {
+        self.cdl.as_ref().map(|log| log.borrow())
+    }
+
+    /// Presses the console's reset button.
+    pub fn reset(&mut self) {
+        self.cpu.mem.reset();
+        self.cpu.reset();
+    }
+
+    /// Turns the console off and on again. RAM and the mapper state are reinitialized.
+    pub fn power_cycle(&mut self) {
+        self.cpu.mem.power_on(Box::new(self.rom.clone()));
+        self.cpu.power_on();
+    }
+
+    /// Writes out the trace buffer, reporting why on the status line.
+    fn dump_trace(&mut self, reason: &str) {
+        match self.tracer.dump(reason) {
+            Ok(()) => println!("{}; trace written to {}", reason, self.tracer.dump_path.display()),
+            Err(err) => println!("{}; couldn't write trace: {}", reason, err),
         }
+        self.gfx.status_line.set(reason.to_owned());
     }
 
     /// Starts the emulator main loop. Returns when the user presses escape or the window is
@@ -101,24 +183,28 @@ impl Emulator {
         let mut frames = 0;
 
         'main: loop {
-            self.cpu.step();
-
-            let ppu_result = self.cpu.mem.ppu.step(self.cpu.cy);
-            if ppu_result.vblank_nmi {
-                self.cpu.nmi();
-            } else if ppu_result.scanline_irq {
-                self.cpu.irq();
+            // A halted CPU would only fill the trace with copies of the KIL.
+            let halted = self.cpu.halted();
+            if !halted && self.tracer.record(&mut self.cpu) {
+                let pc = self.cpu.regs.pc;
+                self.dump_trace(&format!("Breakpoint at ${:04X}", pc));
             }
 
-            self.cpu.mem.apu.step(self.cpu.cy);
+            let new_frame = step(&mut self.cpu);
+
+            if !halted && self.cpu.halted() {
+                let pc = self.cpu.regs.pc;
+                self.dump_trace(&format!("CPU halted at ${:04X}", pc));
+            }
 
-            if ppu_result.new_frame {
+            if new_frame {
                 self.gfx.tick();
                 self.gfx.composite(&mut self.cpu.mem.ppu.screen);
                 record_fps(&mut last_time, &mut frames);
                 self.cpu.mem.apu.play_channels(self.mute);
 
-                for event in self.event_pump.poll_iter() {
+                let events: Vec<_> = self.event_pump.poll_iter().collect();
+                for event in events {
                     use sdl2::event::Event;
                     use sdl2::event::WindowEventId;
                     use sdl2::keyboard::Keycode;
@@ -138,6 +224,14 @@ impl Emulator {
                         Event::KeyDown { keycode: Some(Keycode::M), .. } => {
                             self.mute = !self.mute;
                         }
+                        Event::KeyDown { keycode: Some(Keycode::R), .. } => {
+                            self.reset();
+                            self.gfx.status_line.set("Reset".to_owned());
+                        }
+                        Event::KeyDown { keycode: Some(Keycode::P), .. } => {
+                            self.power_cycle();
+                            self.gfx.status_line.set("Power cycled".to_owned());
+                        }
                         #[cfg(feature = "cpuspew")]
                         Event::KeyDown { keycode: Some(Keycode::T), .. } => {
                             self.cpu.trace = !self.cpu.trace;
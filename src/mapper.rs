@@ -9,18 +9,56 @@ use rom::Rom;
 
 use std::ops::Deref;
 
-#[derive(PartialEq, Eq)]
-pub enum MapperResult {
-    Continue,
-    Irq,
-}
-
 pub trait Mapper {
     fn prg_loadb(&mut self, addr: u16) -> u8;
+    /// Returns the offset within PRG-ROM that a CPU address currently reads from, or None if the
+    /// address isn't backed by PRG-ROM.
+    fn prg_offset(&self, addr: u16) -> Option<usize>;
     fn prg_storeb(&mut self, addr: u16, val: u8);
     fn chr_loadb(&mut self, addr: u16) -> u8;
+    /// Returns the offset within CHR-ROM that a PPU address currently reads from, or None if the
+    /// address isn't backed by CHR-ROM.
+    fn chr_offset(&self, addr: u16) -> Option<usize>;
     fn chr_storeb(&mut self, addr: u16, val: u8);
-    fn next_scanline(&mut self) -> MapperResult;
+    /// Returns how the nametable area at $2000-$2FFF is laid out.
+    fn mirroring(&self) -> Mirroring;
+    /// Reads a nametable byte from memory on the cartridge, for boards that supply their own
+    /// nametables in place of the console's. Returns None if `addr` goes to the console's VRAM.
+    fn nametable_loadb(&mut self, _addr: u16) -> Option<u8> { None }
+    /// Writes a nametable byte to memory on the cartridge. Returns false if `addr` goes to the
+    /// console's VRAM.
+    fn nametable_storeb(&mut self, _addr: u16, _val: u8) -> bool { false }
+    /// Called at dot 260 of every visible and pre-render scanline while the PPU is rendering.
+    fn next_scanline(&mut self);
+    /// Returns true while the mapper is holding the CPU's IRQ line.
+    fn irq_pending(&self) -> bool;
+    /// Called when the console's reset button is pressed. Most boards can't see the reset line.
+    fn reset(&mut self);
+}
+
+/// How the four nametables at $2000-$2FFF map onto nametable memory. The console has 2K, enough
+/// for two; four-screen boards add another 2K so that all four are distinct.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Mirroring {
+    OneScreenLower,
+    OneScreenUpper,
+    Vertical,
+    Horizontal,
+    FourScreen,
+}
+
+impl Mirroring {
+    /// Returns the offset into nametable memory that a PPU address in $2000-$3EFF goes to.
+    pub fn nametable_offset(self, addr: u16) -> usize {
+        let addr = addr as usize & 0x0fff;
+        match self {
+            Mirroring::OneScreenLower => addr & 0x03ff,
+            Mirroring::OneScreenUpper => 0x0400 | (addr & 0x03ff),
+            Mirroring::Vertical => addr & 0x07ff,
+            Mirroring::Horizontal => ((addr >> 1) & 0x0400) | (addr & 0x03ff),
+            Mirroring::FourScreen => addr,
+        }
+    }
 }
 
 pub fn create_mapper(rom: Box<Rom>) -> Box<Mapper+Send> {
@@ -33,6 +71,81 @@ pub fn create_mapper(rom: Box<Rom>) -> Box<Mapper+Send> {
     }
 }
 
+/// Returns the name of the register of the given mapper that a CPU write to `addr` goes to.
+/// Reads of these addresses go to PRG-ROM instead.
+pub fn register_name(mapper: u8, addr: u16) -> Option<&'static str> {
+    if addr < 0x8000 {
+        return None;
+    }
+    let name = match mapper {
+        1 => match addr {
+            0x8000...0x9fff => "MMC1_CTRL",
+            0xa000...0xbfff => "MMC1_CHR0",
+            0xc000...0xdfff => "MMC1_CHR1",
+            _ => "MMC1_PRG",
+        },
+        2 => "UXROM_BANK",
+        4 => match (addr & 0xe000, addr & 1) {
+            (0x8000, 0) => "MMC3_BANK_SELECT",
+            (0x8000, _) => "MMC3_BANK_DATA",
+            (0xa000, 0) => "MMC3_MIRRORING",
+            (0xa000, _) => "MMC3_PRG_RAM_PROTECT",
+            (0xc000, 0) => "MMC3_IRQ_LATCH",
+            (0xc000, _) => "MMC3_IRQ_RELOAD",
+            (_, 0) => "MMC3_IRQ_DISABLE",
+            (_, _) => "MMC3_IRQ_ENABLE",
+        },
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// A bank of PRG-ROM and where it appears in the CPU's address space, for tools that look at a
+/// ROM without running it.
+#[derive(Copy, Clone, Debug)]
+pub struct PrgBank {
+    /// Offset of the bank within PRG-ROM
+    pub offset: usize,
+    pub size: usize,
+    /// The CPU address the bank is mapped at
+    pub addr: u16,
+    /// True if the bank is always mapped there; false if it shares its window with other banks.
+    pub fixed: bool,
+}
+
+impl PrgBank {
+    /// Returns true if the CPU address falls within the bank's window.
+    pub fn covers(&self, addr: u16) -> bool {
+        addr >= self.addr && ((addr - self.addr) as usize) < self.size
+    }
+}
+
+/// Splits PRG-ROM into the banks the mapper switches between, or returns None for unsupported
+/// mappers. Switchable banks are assumed to appear at the lowest address they can be mapped to.
+pub fn prg_banks(rom: &Rom) -> Option<Vec<PrgBank>> {
+    let (bank_size, fixed_count) = match rom.header.ines_mapper() {
+        // NROM-128 is mirrored into both halves; the upper half is where the vectors are.
+        0 if rom.prg.len() <= 16384 => {
+            return Some(vec![PrgBank { offset: 0, size: rom.prg.len(), addr: 0xc000, fixed: true }])
+        }
+        0 => (rom.prg.len(), 1),
+        // MMC1 powers up with the last bank fixed at $C000 on most boards.
+        1 | 2 => (16384, 1),
+        // MMC3's default mode fixes the last two 8K banks at $C000 and $E000.
+        4 => (8192, 2),
+        _ => return None,
+    };
+
+    let count = rom.prg.len() / bank_size;
+    let banks = (0..count).map(|i| {
+        let from_end = count - i;
+        let fixed = from_end <= fixed_count;
+        let addr = if fixed { 0x10000 - from_end * bank_size } else { 0x8000 };
+        PrgBank { offset: i * bank_size, size: bank_size, addr: addr as u16, fixed: fixed }
+    }).collect();
+    Some(banks)
+}
+
 //
 // Mapper 0 (NROM)
 //
@@ -66,22 +179,36 @@ impl Nrom {
 
 impl Mapper for Nrom {
     fn prg_loadb(&mut self, addr: u16) -> u8 {
+        match self.prg_offset(addr) {
+            Some(offset) => self.rom.prg[offset],
+            None => 0u8,
+        }
+    }
+    fn prg_offset(&self, addr: u16) -> Option<usize> {
         if addr < 0x8000 {
-            0u8
+            None
         } else if self.rom.prg.len() > 16384 {
-            self.rom.prg[addr as usize & 0x7fff]
+            Some(addr as usize & 0x7fff)
         } else {
-            self.rom.prg[addr as usize & 0x3fff]
+            Some(addr as usize & 0x3fff)
         }
     }
     fn prg_storeb(&mut self, _: u16, _: u8) {}  // Can't store to PRG-ROM.
     fn chr_loadb(&mut self, addr: u16) -> u8 {
         self.chr_ram[addr as usize % 8192]
     }
+    fn chr_offset(&self, addr: u16) -> Option<usize> {
+        // CHR-ROM is copied into the RAM, which boards without CHR-ROM use as is.
+        let offset = addr as usize % 8192;
+        if offset < self.rom.chr.len() { Some(offset) } else { None }
+    }
     fn chr_storeb(&mut self, addr: u16, val: u8) {
         self.chr_ram[addr as usize % 8192] = val;
     }
-    fn next_scanline(&mut self) -> MapperResult { MapperResult::Continue }
+    fn mirroring(&self) -> Mirroring { self.rom.header.mirroring() }
+    fn next_scanline(&mut self) {}
+    fn irq_pending(&self) -> bool { false }
+    fn reset(&mut self) {}
 }
 
 //
@@ -93,13 +220,6 @@ impl Mapper for Nrom {
 #[derive(Copy, Clone)]
 struct SxCtrl{ val: u8 }
 
-pub enum Mirroring {
-    OneScreenLower,
-    OneScreenUpper,
-    Vertical,
-    Horizontal,
-}
-
 enum SxPrgBankMode {
     /// Switch 32K at $8000, ignore low bit
     Switch32K,
@@ -118,6 +238,15 @@ impl SxCtrl {
             _ => panic!("can't happen")
         }
     }
+
+    fn mirroring(self) -> Mirroring {
+        match self.val & 3 {
+            0 => Mirroring::OneScreenLower,
+            1 => Mirroring::OneScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -165,22 +294,29 @@ impl SxRom {
 
 impl Mapper for SxRom {
     fn prg_loadb(&mut self, addr: u16) -> u8 {
+        match self.prg_offset(addr) {
+            Some(offset) => self.rom.prg[offset],
+            None => 0u8,
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> Option<usize> {
         if addr < 0x8000 {
-            0u8
+            None
         } else if addr < 0xc000 {
             let bank = match self.regs.ctrl.prg_rom_mode() {
                 SxPrgBankMode::Switch32K => self.regs.prg_bank & 0xfe,
                 SxPrgBankMode::FixFirstBank => 0,
                 SxPrgBankMode::FixLastBank => self.regs.prg_bank,
             };
-            self.rom.prg[(bank as usize * 16384) | ((addr & 0x3fff) as usize)]
+            Some((bank as usize * 16384) | ((addr & 0x3fff) as usize))
         } else {
             let bank = match self.regs.ctrl.prg_rom_mode() {
                 SxPrgBankMode::Switch32K => (self.regs.prg_bank & 0xfe) | 1,
                 SxPrgBankMode::FixFirstBank => self.regs.prg_bank,
                 SxPrgBankMode::FixLastBank => self.rom.header.prg_rom_size - 1,
             };
-            self.rom.prg[(bank as usize * 16384) | ((addr & 0x3fff) as usize)]
+            Some((bank as usize * 16384) | ((addr & 0x3fff) as usize))
         }
     }
 
@@ -224,12 +360,27 @@ impl Mapper for SxRom {
         self.chr_ram[addr as usize]
     }
 
+    fn chr_offset(&self, _: u16) -> Option<usize> { None }
+
     fn chr_storeb(&mut self, addr: u16, val: u8) {
         self.chr_ram[addr as usize] = val
     }
 
-    fn next_scanline(&mut self) -> MapperResult {
-        MapperResult::Continue
+    fn mirroring(&self) -> Mirroring {
+        self.regs.ctrl.mirroring()
+    }
+
+    fn next_scanline(&mut self) {}
+
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    fn reset(&mut self) {
+        // The MMC1 sees the CPU's M2 clock stop, which has the same effect as a write with bit 7 set.
+        self.write_count = 0;
+        self.accum = 0;
+        self.regs.ctrl = SxCtrl{val: self.regs.ctrl.val | (3 << 2)};
     }
 }
 
@@ -259,8 +410,15 @@ impl UxRom {
 
 impl Mapper for UxRom {
     fn prg_loadb(&mut self, addr: u16) -> u8 {
+        match self.prg_offset(addr) {
+            Some(offset) => self.rom.prg[offset],
+            None => 0,
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> Option<usize> {
         if addr < 0x8000 {
-            0
+            None
         } else {
             let bank = if addr < 0xC000 {
                 // switchable 16K PRG ROM bank
@@ -270,7 +428,7 @@ impl Mapper for UxRom {
                 self.rom.header.prg_rom_size - 1
             };
 
-            self.rom.prg[(bank as usize * 16384) | ((addr & 0x3fff) as usize)]
+            Some((bank as usize * 16384) | ((addr & 0x3fff) as usize))
         }
     }
 
@@ -287,13 +445,23 @@ impl Mapper for UxRom {
         self.chr_ram[addr as usize]
     }
 
+    fn chr_offset(&self, _: u16) -> Option<usize> { None }
+
     fn chr_storeb(&mut self, addr: u16, val: u8) {
         self.chr_ram[addr as usize] = val;
     }
 
-    fn next_scanline(&mut self) -> MapperResult {
-        MapperResult::Continue
+    fn mirroring(&self) -> Mirroring {
+        self.rom.header.mirroring()
     }
+
+    fn next_scanline(&mut self) {}
+
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    fn reset(&mut self) {}
 }
 
 //
@@ -349,14 +517,17 @@ struct TxRom {
     chr_banks_2k: [u8; 2],    // 2KB CHR-ROM banks
     chr_banks_1k: [u8; 4],    // 1KB CHR-ROM banks
     prg_banks:    [u8; 2],    // 8KB PRG-ROM banks
+    mirroring: Mirroring,
 
     scanline_counter: u8,
     irq_reload: u8,             // Copied into the scanline counter when it hits zero.
     irq_enabled: bool,
+    irq_pending: bool,          // Held until acknowledged by a write to $E000.
 }
 
 impl TxRom {
     fn new(rom: Box<Rom>) -> TxRom {
+        let mirroring = rom.header.mirroring();
         TxRom {
             rom: rom,
             regs: TxRegs { bank_select: TxBankSelect{val: 0} },
@@ -365,10 +536,12 @@ impl TxRom {
             chr_banks_2k: [ 0, 0 ],
             chr_banks_1k: [ 0, 0, 0, 0 ],
             prg_banks: [ 0, 0 ],
+            mirroring: mirroring,
 
             scanline_counter: 0,
             irq_reload: 0,
             irq_enabled: false,
+            irq_pending: false,
         }
     }
 
@@ -383,28 +556,34 @@ impl Mapper for TxRom {
             0u8
         } else if addr < 0x8000 {
             self.prg_ram[addr as usize & 0x1fff]
+        } else {
+            self.rom.prg[self.prg_offset(addr).unwrap()]
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> Option<usize> {
+        let bank = if addr < 0x8000 {
+            return None;
         } else if addr < 0xa000 {
             // $8000-$9FFF might be switchable or fixed to the second to last bank.
-            let bank = match self.regs.bank_select.prg_bank_mode() {
+            match self.regs.bank_select.prg_bank_mode() {
                 TxPrgBankMode::Swappable8000 => self.prg_banks[0],
                 TxPrgBankMode::SwappableC000 => self.prg_bank_count() - 2,
-            };
-            self.rom.prg[(bank as usize * 8192) | (addr as usize & 0x1fff)]
+            }
         } else if addr < 0xc000 {
             // $A000-$BFFF is switchable.
-            self.rom.prg[(self.prg_banks[1] as usize * 8192) | (addr as usize & 0x1fff)]
+            self.prg_banks[1]
         } else if addr < 0xe000 {
             // $C000-$DFFF might be switchable or fixed to the second to last bank.
-            let bank = match self.regs.bank_select.prg_bank_mode() {
+            match self.regs.bank_select.prg_bank_mode() {
                 TxPrgBankMode::Swappable8000 => self.prg_bank_count() - 2,
                 TxPrgBankMode::SwappableC000 => self.prg_banks[0],
-            };
-            self.rom.prg[(bank as usize * 8192) | (addr as usize & 0x1fff)]
+            }
         } else {
             // $E000-$FFFF is fixed to the last bank.
-            let bank = self.prg_bank_count() - 1;
-            self.rom.prg[(bank as usize * 8192) | (addr as usize & 0x1fff)]
-        }
+            self.prg_bank_count() - 1
+        };
+        Some((bank as usize * 8192) | (addr as usize & 0x1fff))
     }
 
     fn prg_storeb(&mut self, addr: u16, val: u8) {
@@ -429,7 +608,14 @@ impl Mapper for TxRom {
                 }
             }
         } else if addr < 0xc000 {
-            // TODO: Mirroring and PRG-RAM protect
+            // Mirroring. Boards with four-screen VRAM don't connect it. TODO: PRG-RAM protect
+            if (addr & 1) == 0 && self.mirroring != Mirroring::FourScreen {
+                self.mirroring = if (val & 1) == 0 {
+                    Mirroring::Vertical
+                } else {
+                    Mirroring::Horizontal
+                };
+            }
         } else if addr < 0xe000 {
             if (addr & 1) == 0 {
                 // IRQ latch.
@@ -439,12 +625,22 @@ impl Mapper for TxRom {
                 self.scanline_counter = self.irq_reload;
             }
         } else {
-            // IRQ enable.
+            // IRQ enable. Disabling also acknowledges any pending IRQ.
             self.irq_enabled = (addr & 1) == 1;
+            if !self.irq_enabled {
+                self.irq_pending = false;
+            }
         }
     }
 
     fn chr_loadb(&mut self, addr: u16) -> u8 {
+        match self.chr_offset(addr) {
+            Some(offset) => self.rom.chr[offset],
+            None => 0,
+        }
+    }
+
+    fn chr_offset(&self, addr: u16) -> Option<usize> {
         let (bank, two_kb) = match (addr, self.regs.bank_select.chr_a12_inversion()) {
             (0x0000 ... 0x07ff, false) | (0x1000 ... 0x17ff, true) => (self.chr_banks_2k[0], true),
             (0x0800 ... 0x0fff, false) | (0x1800 ... 0x1fff, true) => (self.chr_banks_2k[1], true),
@@ -452,12 +648,12 @@ impl Mapper for TxRom {
             (0x1400 ... 0x17ff, false) | (0x0400 ... 0x07ff, true) => (self.chr_banks_1k[1], false),
             (0x1800 ... 0x1bff, false) | (0x0800 ... 0x0bff, true) => (self.chr_banks_1k[2], false),
             (0x1c00 ... 0x1fff, false) | (0x0c00 ... 0x0fff, true) => (self.chr_banks_1k[3], false),
-            _ => return 0,
+            _ => return None,
         };
         if two_kb {
-            self.rom.chr[(bank as usize * 1024) + (addr as usize & 0x7ff)]
+            Some((bank as usize * 1024) + (addr as usize & 0x7ff))
         } else {
-            self.rom.chr[(bank as usize * 1024) | (addr as usize & 0x3ff)]
+            Some((bank as usize * 1024) | (addr as usize & 0x3ff))
         }
     }
 
@@ -465,7 +661,11 @@ impl Mapper for TxRom {
         // TODO: CHR-RAM
     }
 
-    fn next_scanline(&mut self) -> MapperResult {
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn next_scanline(&mut self) {
         if self.scanline_counter != 0 {
             self.scanline_counter -= 1;
             if self.scanline_counter == 0 {
@@ -473,10 +673,15 @@ impl Mapper for TxRom {
 
                 if self.irq_enabled {
                     //debug!("*** Generated IRQ! ***");
-                    return MapperResult::Irq;
+                    self.irq_pending = true;
                 }
             }
         }
-        MapperResult::Continue
     }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn reset(&mut self) {}
 }
@@ -3,9 +3,11 @@
 //
 
 use apu::Apu;
+use cdl::CodeDataLog;
 use input::Input;
-use mapper::Mapper;
+use mapper::{self, Mapper};
 use ppu::Ppu;
+use rom::Rom;
 use util::Save;
 
 use std::cell::RefCell;
@@ -17,11 +19,36 @@ use std::ops::{Deref, DerefMut};
 // The memory interface
 //
 
+/// The interrupt outputs of the devices on a bus.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct InterruptLines {
+    /// The level of the NMI output. The PPU holds it during vblank while NMIs are enabled.
+    pub nmi: bool,
+    pub mapper_irq: bool,
+    pub frame_irq: bool,
+    pub dmc_irq: bool,
+}
+
+/// Why the CPU is reading a byte, for the code/data logger.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Access {
+    /// An opcode or operand fetch
+    Code,
+    Data,
+    /// A sample fetch on behalf of the DMC
+    Dmc,
+    /// A read whose value is thrown away
+    Dummy,
+}
+
 /// The basic memory interface
 pub trait Mem {
     fn loadb(&mut self, addr: u16) -> u8;
     fn storeb(&mut self, addr: u16, val: u8);
 
+    /// Loads a byte, telling the bus what kind of access it is.
+    fn loadb_as(&mut self, addr: u16, _access: Access) -> u8 { self.loadb(addr) }
+
     fn loadw(&mut self, addr: u16) -> u16 {
         self.loadb(addr) as u16 | (self.loadb(addr + 1) as u16) << 8
     }
@@ -33,8 +60,63 @@ pub trait Mem {
     
     /// Like loadw, but has wraparound behavior on the zero page for address 0xff.
     fn loadw_zp(&mut self, addr: u8) -> u16 {
-        self.loadb(addr as u16) as u16 | (self.loadb((addr + 1) as u16) as u16) << 8
+        self.loadb(addr as u16) as u16 | (self.loadb(addr.wrapping_add(1) as u16) as u16) << 8
     }
+
+    /// Clocks the devices on the bus up to CPU cycle `cy` and returns their interrupt outputs.
+    /// Memory without devices attached returns `None`, which leaves the CPU's lines alone.
+    fn clock(&mut self, _cy: u64) -> Option<InterruptLines> { None }
+
+    /// Returns the address the DMC wants to fetch a sample byte from, if it is requesting DMA.
+    fn dmc_dma_request(&mut self) -> Option<u16> { None }
+
+    /// Hands the DMC the byte the CPU fetched on its behalf.
+    fn dmc_dma_complete(&mut self, _val: u8) {}
+
+    /// Returns the PRG-ROM offset an address is currently mapped to, for debugging tools.
+    fn prg_offset(&mut self, _addr: u16) -> Option<usize> { None }
+
+    /// Reads a byte for debugging tools. Returns None where reading could have side effects, which
+    /// unless overridden is everywhere.
+    fn peekb(&mut self, _addr: u16) -> Option<u8> { None }
+}
+
+/// Returns the name of the PPU, APU or I/O register at `addr`, as the NESdev wiki calls them.
+pub fn register_name(addr: u16) -> Option<&'static str> {
+    let name = match addr {
+        0x2000 => "PPUCTRL",
+        0x2001 => "PPUMASK",
+        0x2002 => "PPUSTATUS",
+        0x2003 => "OAMADDR",
+        0x2004 => "OAMDATA",
+        0x2005 => "PPUSCROLL",
+        0x2006 => "PPUADDR",
+        0x2007 => "PPUDATA",
+        0x4000 => "SQ1_VOL",
+        0x4001 => "SQ1_SWEEP",
+        0x4002 => "SQ1_LO",
+        0x4003 => "SQ1_HI",
+        0x4004 => "SQ2_VOL",
+        0x4005 => "SQ2_SWEEP",
+        0x4006 => "SQ2_LO",
+        0x4007 => "SQ2_HI",
+        0x4008 => "TRI_LINEAR",
+        0x400a => "TRI_LO",
+        0x400b => "TRI_HI",
+        0x400c => "NOISE_VOL",
+        0x400e => "NOISE_LO",
+        0x400f => "NOISE_HI",
+        0x4010 => "DMC_FREQ",
+        0x4011 => "DMC_RAW",
+        0x4012 => "DMC_START",
+        0x4013 => "DMC_LEN",
+        0x4014 => "OAMDMA",
+        0x4015 => "SND_CHN",
+        0x4016 => "JOY1",
+        0x4017 => "JOY2",
+        _ => return None,
+    };
+    Some(name)
 }
 
 //
@@ -81,6 +163,11 @@ pub struct MemMap {
     pub input: Input,
     pub mapper: Rc<RefCell<Box<Mapper+Send>>>,
     pub apu: Apu,
+    /// Where PRG-ROM reads are logged, if anywhere. Shared with the PPU, which logs CHR-ROM reads.
+    cdl: Option<Rc<RefCell<CodeDataLog>>>,
+
+    /// Set when clocking the PPU completes a frame; cleared by `take_new_frame`.
+    new_frame: bool,
 }
 
 impl MemMap {
@@ -97,8 +184,38 @@ impl MemMap {
             input: input,
             mapper: mapper,
             apu: apu,
+            cdl: None,
+            new_frame: false,
         }
     }
+
+    /// Sets where PRG-ROM and CHR-ROM reads are logged.
+    pub fn set_code_data_log(&mut self, log: Option<Rc<RefCell<CodeDataLog>>>) {
+        self.ppu.set_code_data_log(log.clone());
+        self.cdl = log;
+    }
+
+    /// Propagates the console's reset button to everything connected to the reset line.
+    pub fn reset(&mut self) {
+        self.ppu.reset();
+        self.apu.reset();
+        self.mapper.borrow_mut().reset();
+    }
+
+    /// Returns RAM, the PPU and the APU to their power-up state and rebuilds the mapper from `rom`.
+    pub fn power_on(&mut self, rom: Box<Rom>) {
+        *self.mapper.borrow_mut() = mapper::create_mapper(rom);
+        self.ram = Ram { val: [ 0; 0x800 ] };
+        self.ppu.power_on();
+        self.apu.power_on();
+    }
+
+    /// Returns true if a frame has been completed since the last call.
+    pub fn take_new_frame(&mut self) -> bool {
+        let new_frame = self.new_frame;
+        self.new_frame = false;
+        new_frame
+    }
 }
 
 impl Mem for MemMap {
@@ -134,6 +251,53 @@ impl Mem for MemMap {
             mapper.prg_storeb(addr, val)
         }
     }
+
+    fn loadb_as(&mut self, addr: u16, access: Access) -> u8 {
+        if addr >= 0x8000 {
+            if let Some(ref cdl) = self.cdl {
+                if let Some(offset) = self.mapper.borrow().prg_offset(addr) {
+                    cdl.borrow_mut().log_prg(offset, addr, access);
+                }
+            }
+        }
+        self.loadb(addr)
+    }
+
+    fn clock(&mut self, cy: u64) -> Option<InterruptLines> {
+        let ppu_result = self.ppu.step(cy);
+        if ppu_result.new_frame {
+            self.new_frame = true;
+        }
+        self.apu.step(cy);
+
+        Some(InterruptLines {
+            nmi: ppu_result.vblank_nmi,
+            mapper_irq: self.mapper.borrow().irq_pending(),
+            frame_irq: self.apu.irq_pending(),
+            dmc_irq: self.apu.dmc_irq_pending(),
+        })
+    }
+
+    fn dmc_dma_request(&mut self) -> Option<u16> {
+        self.apu.dmc_dma_request()
+    }
+
+    fn dmc_dma_complete(&mut self, val: u8) {
+        self.apu.dmc_dma_complete(val)
+    }
+
+    fn prg_offset(&mut self, addr: u16) -> Option<usize> {
+        self.mapper.borrow().prg_offset(addr)
+    }
+
+    fn peekb(&mut self, addr: u16) -> Option<u8> {
+        // Reading the PPU, APU and controller registers changes their state.
+        if addr < 0x2000 || addr >= 0x6000 {
+            Some(self.loadb(addr))
+        } else {
+            None
+        }
+    }
 }
 
 save_struct!(MemMap { ram, ppu, apu });
@@ -0,0 +1,241 @@
+//! nestest conformance mode.
+//!
+//! nestest's automated mode starts at $C000 instead of at the reset vector, and its reference log
+//! was produced by Nintendulator. This module sets up a console the way that mode expects, formats
+//! trace lines exactly like the log, and compares a run against a reference log.
+
+use apu::Apu;
+use cpu::{Cpu, Regs};
+use disasm::{AddressingMode, Disassembler, Instruction, Mnemonic};
+use input::Input;
+use mapper::{self, Mapper};
+use mem::{Mem, MemMap};
+use ppu::{Oam, Ppu, Vram};
+use rom::Rom;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::rc::Rc;
+
+/// The address that nestest's automated mode starts at.
+pub const START_ADDR: u16 = 0xc000;
+
+/// The number of matching lines shown before a mismatch.
+const CONTEXT_LINES: usize = 5;
+
+/// Builds a console without video or audio output in the state nestest's automated mode expects.
+/// The reset sequence isn't run; instead the registers and cycle count are set to what
+/// Nintendulator reports on the first line of the log.
+pub fn console(rom: Rom) -> Cpu<MemMap> {
+    let mapper: Box<Mapper+Send> = mapper::create_mapper(Box::new(rom));
+    let mapper = Rc::new(RefCell::new(mapper));
+    let ppu = Ppu::new(Vram::new(mapper.clone()), Oam::new());
+    let memmap = MemMap::new(ppu, Input::new(), mapper, Apu::new(None));
+
+    let mut cpu = Cpu::new(memmap);
+    cpu.regs = Regs { a: 0, x: 0, y: 0, s: 0xfd, flags: 0x24, pc: START_ADDR };
+    cpu.cy = 7;
+    cpu
+}
+
+//
+// Trace formatting
+//
+
+/// Returns the trace line for the instruction at PC, in Nintendulator's format:
+///
+/// `C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7`
+pub fn trace_line<M: Mem>(cpu: &mut Cpu<M>, scanline: u16, dot: u16) -> String {
+    let regs = cpu.regs;
+    let instruction = Disassembler { pc: regs.pc, mem: &mut cpu.mem }.disassemble();
+    let text = {
+        let mut annotator = Annotator { mem: &mut cpu.mem, x: regs.x, y: regs.y };
+        annotator.format(&instruction)
+    };
+
+    let mut bytes = vec![];
+    for i in 0..instruction.len as u16 {
+        bytes.push(format!("{:02X}", cpu.mem.loadb(regs.pc.wrapping_add(i))));
+    }
+
+    format!("{:04X}  {:<9}{:<33}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:3},{:3} CYC:{}",
+            regs.pc,
+            bytes.join(" "),
+            text,
+            regs.a,
+            regs.x,
+            regs.y,
+            regs.flags,
+            regs.s,
+            scanline,
+            dot,
+            cpu.cy)
+}
+
+/// Formats a disassembled instruction in Nintendulator's style. Memory operands are annotated with
+/// their effective address and the value stored there. Official mnemonics are prefixed with a
+/// space and unofficial ones with a `*`, which lines up with the log's columns.
+struct Annotator<'a, M: Mem + 'a> {
+    mem: &'a mut M,
+    x: u8,
+    y: u8,
+}
+
+impl<'a, M: Mem> Annotator<'a, M> {
+    /// Loads a byte for an annotation. Memory-mapped registers are not read, since reading them can
+    /// have side effects.
+    fn peekb(&mut self, addr: u16) -> u8 {
+        self.mem.peekb(addr).unwrap_or(0xff)
+    }
+    fn peekw_zp(&mut self, addr: u8) -> u16 {
+        self.peekb(addr as u16) as u16 | (self.peekb(addr.wrapping_add(1) as u16) as u16) << 8
+    }
+
+    fn format(&mut self, instruction: &Instruction) -> String {
+        // Nintendulator calls ISC "ISB".
+        let name = match instruction.mnemonic {
+            Mnemonic::Isc => "ISB",
+            mnemonic => mnemonic.name(),
+        };
+        let prefix = if instruction.is_official() { " " } else { "*" };
+        match self.operand(instruction) {
+            Some(operand) => format!("{}{} {}", prefix, name, operand),
+            None => format!("{}{}", prefix, name),
+        }
+    }
+
+    fn operand(&mut self, instruction: &Instruction) -> Option<String> {
+        let operand = instruction.operand;
+        let text = match instruction.mode {
+            AddressingMode::Implied => return None,
+            AddressingMode::Accumulator => "A".to_owned(),
+            AddressingMode::Immediate => format!("#${:02X}", operand),
+            AddressingMode::ZeroPage => {
+                format!("${:02X} = {:02X}", operand, self.peekb(operand))
+            }
+            AddressingMode::ZeroPageX => {
+                let addr = (operand as u8).wrapping_add(self.x);
+                format!("${:02X},X @ {:02X} = {:02X}", operand, addr, self.peekb(addr as u16))
+            }
+            AddressingMode::ZeroPageY => {
+                let addr = (operand as u8).wrapping_add(self.y);
+                format!("${:02X},Y @ {:02X} = {:02X}", operand, addr, self.peekb(addr as u16))
+            }
+            // Jumps don't annotate their targets with the value stored there.
+            AddressingMode::Absolute if instruction.target.is_some() => {
+                format!("${:04X}", operand)
+            }
+            AddressingMode::Absolute => format!("${:04X} = {:02X}", operand, self.peekb(operand)),
+            AddressingMode::AbsoluteX => {
+                let addr = operand.wrapping_add(self.x as u16);
+                format!("${:04X},X @ {:04X} = {:02X}", operand, addr, self.peekb(addr))
+            }
+            AddressingMode::AbsoluteY => {
+                let addr = operand.wrapping_add(self.y as u16);
+                format!("${:04X},Y @ {:04X} = {:02X}", operand, addr, self.peekb(addr))
+            }
+            AddressingMode::Indirect => {
+                let lo = self.peekb(operand);
+                let hi = self.peekb((operand & 0xff00) | (operand.wrapping_add(1) & 0x00ff));
+                format!("(${:04X}) = {:04X}", operand, (hi as u16) << 8 | lo as u16)
+            }
+            AddressingMode::IndexedIndirectX => {
+                let pointer = (operand as u8).wrapping_add(self.x);
+                let addr = self.peekw_zp(pointer);
+                format!("(${:02X},X) @ {:02X} = {:04X} = {:02X}",
+                        operand,
+                        pointer,
+                        addr,
+                        self.peekb(addr))
+            }
+            AddressingMode::IndirectIndexedY => {
+                let pointer = self.peekw_zp(operand as u8);
+                let addr = pointer.wrapping_add(self.y as u16);
+                format!("(${:02X}),Y = {:04X} @ {:04X} = {:02X}",
+                        operand,
+                        pointer,
+                        addr,
+                        self.peekb(addr))
+            }
+            // Branches show their absolute target.
+            AddressingMode::Relative => format!("${:04X}", instruction.target.unwrap()),
+        };
+        Some(text)
+    }
+}
+
+//
+// Running and comparing
+//
+
+/// The first line at which a trace diverged from the reference log.
+pub struct Mismatch {
+    /// The line number in the reference log, starting at 1.
+    pub line: usize,
+    pub expected: String,
+    pub actual: String,
+    /// The matching lines immediately before the mismatch.
+    pub context: Vec<String>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        try!(writeln!(f, "trace diverges from the reference log at line {}:", self.line));
+        for line in self.context.iter() {
+            try!(writeln!(f, "  {}", line));
+        }
+        try!(writeln!(f, "- {}", self.expected));
+        write!(f, "+ {}", self.actual)
+    }
+}
+
+/// Compares two trace lines. If `ignore_ppu` is set, the PPU position is not compared.
+pub fn lines_match(expected: &str, actual: &str, ignore_ppu: bool) -> bool {
+    if ignore_ppu {
+        strip_ppu_position(expected) == strip_ppu_position(actual)
+    } else {
+        expected == actual
+    }
+}
+
+fn strip_ppu_position(line: &str) -> String {
+    match (line.find(" PPU:"), line.find(" CYC:")) {
+        (Some(start), Some(end)) if start < end => format!("{}{}", &line[..start], &line[end..]),
+        _ => line.to_owned(),
+    }
+}
+
+/// Runs `cpu` one instruction per line of the reference log until the log ends or a line differs.
+/// Returns the number of lines that matched.
+pub fn check<I>(cpu: &mut Cpu<MemMap>, reference: I, ignore_ppu: bool) -> Result<usize, Mismatch>
+                where I: IntoIterator<Item=String> {
+    let mut context = VecDeque::new();
+    let mut count = 0;
+
+    for expected in reference {
+        // The reference log uses DOS line endings.
+        let expected = expected.trim_end().to_owned();
+        let (scanline, dot) = cpu.mem.ppu.position(cpu.cy);
+        let actual = trace_line(cpu, scanline, dot);
+
+        if !lines_match(&expected, &actual, ignore_ppu) {
+            return Err(Mismatch {
+                line: count + 1,
+                expected: expected,
+                actual: actual,
+                context: context.into_iter().collect(),
+            });
+        }
+
+        context.push_back(actual);
+        if context.len() > CONTEXT_LINES {
+            context.pop_front();
+        }
+        count += 1;
+
+        ::step(cpu);
+    }
+
+    Ok(count)
+}
@@ -0,0 +1,256 @@
+//! Output palettes, which turn the PPU's 6-bit colors into RGB.
+//!
+//! A palette has an entry for each of the 64 colors under each of the 8 combinations of the
+//! PPUMASK emphasis bits, indexed by `emphasis << 6 | color`. Palettes can be loaded from the
+//! usual `.pal` files, either 64 entries (the emphasized colors are then approximated) or 512
+//! entries, or generated from a model of the NTSC composite signal the PPU puts out.
+
+use std::f32::consts::PI;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+
+/// The number of entries in a palette: 64 colors times 8 emphasis combinations.
+pub const PALETTE_ENTRIES: usize = 512;
+
+/// The palette used unless another one is chosen.
+static DEFAULT_PALETTE: [u8; 192] = [
+    124,124,124,    0,0,252,        0,0,188,        68,40,188,
+    148,0,132,      168,0,32,       168,16,0,       136,20,0,
+    80,48,0,        0,120,0,        0,104,0,        0,88,0,
+    0,64,88,        0,0,0,          0,0,0,          0,0,0,
+    188,188,188,    0,120,248,      0,88,248,       104,68,252,
+    216,0,204,      228,0,88,       248,56,0,       228,92,16,
+    172,124,0,      0,184,0,        0,168,0,        0,168,68,
+    0,136,136,      0,0,0,          0,0,0,          0,0,0,
+    248,248,248,    60,188,252,     104,136,252,    152,120,248,
+    248,120,248,    248,88,152,     248,120,88,     252,160,68,
+    248,184,0,      184,248,24,     88,216,84,      88,248,152,
+    0,232,216,      120,120,120,    0,0,0,          0,0,0,
+    252,252,252,    164,228,252,    184,184,248,    216,184,248,
+    248,184,248,    248,164,192,    240,208,176,    252,224,168,
+    248,216,120,    216,248,120,    184,248,184,    184,248,216,
+    0,252,252,      248,216,248,    0,0,0,          0,0,0
+];
+
+// Composite signal levels, in volts, of the low and high halves of the wave for each luma row.
+// See http://wiki.nesdev.com/w/index.php/NTSC_video
+static SIGNAL_LOW: [f32; 4] = [ 0.350, 0.518, 0.962, 1.550 ];
+static SIGNAL_HIGH: [f32; 4] = [ 1.094, 1.506, 1.962, 1.962 ];
+const SIGNAL_BLACK: f32 = 0.518;
+const SIGNAL_WHITE: f32 = 1.962;
+
+/// Where the demodulator's reference phase falls in the color cycle, in twelfths of a cycle. This
+/// lines up the hues with a television's: colors $x6 come out red, $xA green and $x2 blue.
+const DECODER_PHASE: f32 = 4.0;
+
+/// How much an emphasis bit attenuates the signal during its part of the color cycle.
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+
+/// The gamma the NTSC standard assumes the television has.
+const NTSC_GAMMA: f32 = 2.2;
+
+#[derive(Debug)]
+pub enum PaletteLoadError {
+    /// IO error while reading the palette or settings file
+    IoError(io::Error),
+    /// The palette file is neither 64 nor 512 entries long; holds the file's size in bytes
+    SizeError(usize),
+    /// The settings file line with this (1-based) number couldn't be parsed
+    FormatError(usize),
+}
+
+impl From<io::Error> for PaletteLoadError {
+    fn from(err: io::Error) -> Self {
+        PaletteLoadError::IoError(err)
+    }
+}
+
+impl fmt::Display for PaletteLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            PaletteLoadError::IoError(ref err) => write!(f, "{}", err),
+            PaletteLoadError::SizeError(size) => {
+                write!(f, "{} bytes isn't a 64- or 512-color palette", size)
+            }
+            PaletteLoadError::FormatError(line) => write!(f, "can't parse line {}", line),
+        }
+    }
+}
+
+/// The knobs of the NTSC palette generator, which correspond to a television's picture controls.
+#[derive(Copy, Clone, Debug)]
+pub struct NtscSettings {
+    /// Rotation of the hues, in degrees.
+    pub hue: f32,
+    /// Scale of the chroma; 0 gives grayscale.
+    pub saturation: f32,
+    /// Scale of the whole signal around black.
+    pub contrast: f32,
+    /// Offset added to the luma, where 1 is the distance from black to white.
+    pub brightness: f32,
+    /// Gamma of the display; 2.2 leaves the signal's NTSC gamma as it is.
+    pub gamma: f32,
+}
+
+impl Default for NtscSettings {
+    fn default() -> NtscSettings {
+        NtscSettings {
+            hue: 0.0,
+            saturation: 1.0,
+            contrast: 1.0,
+            brightness: 0.0,
+            gamma: NTSC_GAMMA,
+        }
+    }
+}
+
+impl NtscSettings {
+    /// Sets the setting named `key` from `val`. Returns false if the key is unknown or the value
+    /// isn't a number.
+    pub fn set(&mut self, key: &str, val: &str) -> bool {
+        let val = match val.parse::<f32>() {
+            Ok(val) => val,
+            Err(_) => return false,
+        };
+        match key {
+            "hue" => self.hue = val,
+            "saturation" => self.saturation = val,
+            "contrast" => self.contrast = val,
+            "brightness" => self.brightness = val,
+            "gamma" => self.gamma = val,
+            _ => return false,
+        }
+        true
+    }
+}
+
+pub struct Palette {
+    rgb: Box<[u8; PALETTE_ENTRIES * 3]>,
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette::from_colors(&DEFAULT_PALETTE)
+    }
+}
+
+impl Palette {
+    /// Loads a `.pal` file of 64 or 512 RGB triples.
+    pub fn load(path: &Path) -> Result<Palette, PaletteLoadError> {
+        let mut data = vec![];
+        try!(try!(File::open(path)).read_to_end(&mut data));
+        if data.len() == 64 * 3 {
+            Ok(Palette::from_colors(&data))
+        } else if data.len() == PALETTE_ENTRIES * 3 {
+            let mut palette = Palette { rgb: Box::new([ 0; PALETTE_ENTRIES * 3 ]) };
+            palette.rgb.copy_from_slice(&data);
+            Ok(palette)
+        } else {
+            Err(PaletteLoadError::SizeError(data.len()))
+        }
+    }
+
+    /// Reads a palette settings file of `key = value` lines. If `palette` names a `.pal` file,
+    /// relative to the settings file, that palette is loaded; otherwise one is generated, with the
+    /// `NtscSettings` fields as keys. Blank lines and lines starting with `#` are skipped.
+    pub fn load_settings(path: &Path) -> Result<Palette, PaletteLoadError> {
+        let reader = BufReader::new(try!(File::open(path)));
+        let mut settings = NtscSettings::default();
+        let mut palette_path = None;
+        for (index, line) in reader.lines().enumerate() {
+            let line = try!(line);
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.splitn(2, '=').map(|field| field.trim());
+            match (fields.next(), fields.next()) {
+                (Some("palette"), Some(file)) if !file.is_empty() => {
+                    palette_path = Some(path.parent().unwrap_or(Path::new("")).join(file));
+                }
+                (Some(key), Some(val)) if settings.set(key, val) => {}
+                _ => return Err(PaletteLoadError::FormatError(index + 1)),
+            }
+        }
+
+        match palette_path {
+            Some(palette_path) => Palette::load(&palette_path),
+            None => Ok(Palette::generate(&settings)),
+        }
+    }
+
+    /// Builds a full palette from 64 colors. There's no telling what the emphasized colors should
+    /// look like, so emphasizing a color just darkens the other two channels.
+    fn from_colors(colors: &[u8]) -> Palette {
+        let mut palette = Palette { rgb: Box::new([ 0; PALETTE_ENTRIES * 3 ]) };
+        let dim = |c: u8| (c as u16 * 3 / 4) as u8;
+        for emphasis in 0..8 {
+            for color in 0..64 {
+                let mut rgb = [ colors[color * 3], colors[color * 3 + 1], colors[color * 3 + 2] ];
+                if (emphasis & 6) != 0 { rgb[0] = dim(rgb[0]); }
+                if (emphasis & 5) != 0 { rgb[1] = dim(rgb[1]); }
+                if (emphasis & 3) != 0 { rgb[2] = dim(rgb[2]); }
+
+                let offset = (emphasis << 6 | color) * 3;
+                palette.rgb[offset..offset + 3].copy_from_slice(&rgb);
+            }
+        }
+        palette
+    }
+
+    /// Generates a palette by sampling the square wave the PPU outputs for each color over one
+    /// color cycle and decoding it like an idealized television would.
+    pub fn generate(settings: &NtscSettings) -> Palette {
+        let mut palette = Palette { rgb: Box::new([ 0; PALETTE_ENTRIES * 3 ]) };
+        for entry in 0..PALETTE_ENTRIES {
+            let (color, luma, emphasis) = (entry & 0x0f, (entry >> 4) & 3, entry >> 6);
+
+            // Colors $xE and $xF are black; $x0 is a flat high level and $xD a flat low one.
+            let luma = if color >= 0x0e { 1 } else { luma };
+            let low = if color == 0x00 { SIGNAL_HIGH[luma] } else { SIGNAL_LOW[luma] };
+            let high = if color < 0x0d { SIGNAL_HIGH[luma] } else { SIGNAL_LOW[luma] };
+
+            // Twelve samples per color cycle; color n is high during phases n to n + 5.
+            let in_color_phase = |color: usize, phase: usize| (color + phase) % 12 < 6;
+            let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+            for phase in 0..12 {
+                let mut signal = if in_color_phase(color, phase) { high } else { low };
+                if ((emphasis & 1) != 0 && in_color_phase(0, phase)) ||
+                   ((emphasis & 2) != 0 && in_color_phase(4, phase)) ||
+                   ((emphasis & 4) != 0 && in_color_phase(8, phase)) {
+                    signal *= EMPHASIS_ATTENUATION;
+                }
+
+                let level = (signal - SIGNAL_BLACK) / (SIGNAL_WHITE - SIGNAL_BLACK) / 12.0;
+                let angle = PI * (phase as f32 + DECODER_PHASE) / 6.0 + settings.hue.to_radians();
+                y += level;
+                i += level * angle.cos();
+                q += level * angle.sin();
+            }
+
+            let y = y * settings.contrast + settings.brightness;
+            let i = i * settings.contrast * settings.saturation;
+            let q = q * settings.contrast * settings.saturation;
+
+            let gamma = |c: f32| {
+                let c = if c <= 0.0 { 0.0 } else { c.powf(NTSC_GAMMA / settings.gamma) };
+                (c * 255.0).round().min(255.0) as u8
+            };
+            let offset = entry * 3;
+            palette.rgb[offset + 0] = gamma(y + 0.946882 * i + 0.623557 * q);
+            palette.rgb[offset + 1] = gamma(y - 0.274788 * i - 0.635691 * q);
+            palette.rgb[offset + 2] = gamma(y - 1.108545 * i + 1.709007 * q);
+        }
+        palette
+    }
+
+    /// Returns the red, green and blue of a color under the given emphasis bits.
+    #[inline(always)]
+    pub fn rgb(&self, color: u8, emphasis: u8) -> (u8, u8, u8) {
+        let offset = ((emphasis as usize & 7) << 6 | color as usize & 0x3f) * 3;
+        (self.rgb[offset], self.rgb[offset + 1], self.rgb[offset + 2])
+    }
+}
@@ -2,11 +2,14 @@
 // Author: Patrick Walton
 //
 
-use mapper::{Mapper, MapperResult};
+use cdl::{self, CodeDataLog};
+use mapper::Mapper;
 use mem::Mem;
+use palette::Palette;
 use util::Save;
 
 use std::cell::RefCell;
+use std::cmp;
 use std::fs::File;
 use std::rc::Rc;
 use std::ops::{Deref, DerefMut};
@@ -14,28 +17,12 @@ use std::ops::{Deref, DerefMut};
 
 pub const SCREEN_WIDTH: usize = 256;
 pub const SCREEN_HEIGHT: usize = 240;
-pub const CYCLES_PER_SCANLINE: u64 = 114;   // 29781 cycles per frame / 261 scanlines
-pub const VBLANK_SCANLINE: usize = 241;
-pub const LAST_SCANLINE: usize = 261;       // pre-render scanline
-
-static PALETTE: [u8; 192] = [
-    124,124,124,    0,0,252,        0,0,188,        68,40,188,
-    148,0,132,      168,0,32,       168,16,0,       136,20,0,
-    80,48,0,        0,120,0,        0,104,0,        0,88,0,
-    0,64,88,        0,0,0,          0,0,0,          0,0,0,
-    188,188,188,    0,120,248,      0,88,248,       104,68,252,
-    216,0,204,      228,0,88,       248,56,0,       228,92,16,
-    172,124,0,      0,184,0,        0,168,0,        0,168,68,
-    0,136,136,      0,0,0,          0,0,0,          0,0,0,
-    248,248,248,    60,188,252,     104,136,252,    152,120,248,
-    248,120,248,    248,88,152,     248,120,88,     252,160,68,
-    248,184,0,      184,248,24,     88,216,84,      88,248,152,
-    0,232,216,      120,120,120,    0,0,0,          0,0,0,
-    252,252,252,    164,228,252,    184,184,248,    216,184,248,
-    248,184,248,    248,164,192,    240,208,176,    252,224,168,
-    248,216,120,    216,248,120,    184,248,184,    184,248,216,
-    0,252,252,      248,216,248,    0,0,0,          0,0,0
-];
+pub const DOTS_PER_SCANLINE: u16 = 341;
+pub const DOTS_PER_CYCLE: u64 = 3;          // 89342 dots per frame / 29780.67 CPU cycles
+pub const VBLANK_SCANLINE: u16 = 241;
+pub const LAST_SCANLINE: u16 = 261;         // pre-render scanline
+/// How long a bit of the I/O latch holds a 1 without being refreshed, in CPU cycles: about 600 ms.
+pub const OPEN_BUS_DECAY_CYCLES: u64 = 1_070_000;
 
 //
 // Registers
@@ -47,11 +34,16 @@ struct Regs {
     mask: PpuMask,      // PPUMASK: 0x2001
     status: PpuStatus,  // PPUSTATUS: 0x2002
     oam_addr: u8,       // OAMADDR: 0x2003
-    scroll: PpuScroll,  // PPUSCROLL: 0x2005
-    addr: PpuAddr,      // PPUADDR: 0x2006
+
+    // The internal registers that PPUSCROLL (0x2005) and PPUADDR (0x2006) write to. See
+    // http://wiki.nesdev.com/w/index.php/PPU_scrolling
+    v: VramAddr,        // The current VRAM address, which rendering also uses as its position.
+    t: VramAddr,        // The VRAM address that `v` is reloaded from.
+    x: u8,              // Fine X scroll, 3 bits.
+    w: bool,            // The write toggle shared by PPUSCROLL and PPUADDR. Set after one write.
 }
 
-save_struct!(Regs { ctrl, mask, status, oam_addr, scroll, addr });
+save_struct!(Regs { ctrl, mask, status, oam_addr, v, t, x, w });
 
 //
 // PPUCTRL: 0x2000
@@ -80,14 +72,19 @@ impl DerefMut for PpuCtrl {
 }
 
 impl PpuCtrl {
-    fn x_scroll_offset(self) -> u16               { if (*self & 0x01) == 0 { 0 } else { 256 } }
-    fn y_scroll_offset(self) -> u16               { if (*self & 0x02) == 0 { 0 } else { 240 } }
+    fn nametable_select(self) -> u16              { (*self & 0x03) as u16 }
     fn vram_addr_increment(self) -> u16           { if (*self & 0x04) == 0 { 1 } else { 32 } }
     fn sprite_pattern_table_addr(self) -> u16     { if (*self & 0x08) == 0 { 0 } else { 0x1000 } }
     fn background_pattern_table_addr(self) -> u16 { if (*self & 0x10) == 0 { 0 } else { 0x1000 } }
     fn sprite_size(self) -> SpriteSize {
         if (*self & 0x20) == 0 { SpriteSize::SpriteSize8x8 } else { SpriteSize::SpriteSize8x16 }
     }
+    fn sprite_height(self) -> u16 {
+        match self.sprite_size() {
+            SpriteSize::SpriteSize8x8 => 8,
+            SpriteSize::SpriteSize8x16 => 16,
+        }
+    }
     fn vblank_nmi(self) -> bool                   { (*self & 0x80) != 0 }
 }
 
@@ -113,14 +110,14 @@ impl DerefMut for PpuMask {
 }
 
 impl PpuMask {
-    // 0x01: grayscale
-    // 0x02: show background on left
-    // 0x04: show sprites on left
+    fn grayscale(self) -> bool               { (*self & 0x01) != 0 }
+    fn show_background_left(self) -> bool    { (*self & 0x02) != 0 }
+    fn show_sprites_left(self) -> bool       { (*self & 0x04) != 0 }
     fn show_background(self) -> bool         { (*self & 0x08) != 0 }
     fn show_sprites(self) -> bool            { (*self & 0x10) != 0 }
-    // 0x20: intensify reds
-    // 0x40: intensify greens
-    // 0x80: intensify blues
+    fn rendering_enabled(self) -> bool       { (*self & 0x18) != 0 }
+    // Bit 0: intensify reds, bit 1: intensify greens, bit 2: intensify blues
+    fn emphasis(self) -> u8                  { *self >> 5 }
 }
 
 //
@@ -145,7 +142,6 @@ impl DerefMut for PpuStatus {
 }
 
 impl PpuStatus {
-    // TODO: open bus junk in bits [0,5)
     fn set_sprite_overflow(&mut self, val: bool) {
         *self = if val { PpuStatus{ val: **self | 0x20 } }
         else { PpuStatus{ val: **self & !0x20} }
@@ -154,6 +150,7 @@ impl PpuStatus {
         *self = if val { PpuStatus{ val: **self | 0x40 } }
         else { PpuStatus{ val: **self & !0x40} }
     }
+    fn in_vblank(self) -> bool { (*self & 0x80) != 0 }
     fn set_in_vblank(&mut self, val: bool) {
         *self = if val { PpuStatus{ val: **self | 0x80 } }
         else { PpuStatus{ val: **self & !0x80} }
@@ -161,79 +158,268 @@ impl PpuStatus {
 }
 
 //
-// PPUSCROLL: 0x2005
+// The I/O latch
+//
+// The data bus between the CPU and the PPU holds the last value driven onto it. Reads of
+// write-only registers and of the undriven bits of PPUSTATUS and palette RAM return what's left
+// there. Without refreshing, each bit fades to 0 on its own. See
+// http://wiki.nesdev.com/w/index.php/PPU_registers#Ports
 //
 
-#[derive(Copy, Clone)]
-struct PpuScroll {
-    x: u8,
-    y: u8,
-    next: PpuScrollDir
+struct OpenBus {
+    val: u8,
+    refreshed: [u64; 8],    // The CPU cycle each bit was last driven on.
 }
 
-save_struct!(PpuScroll { x, y, next });
+impl OpenBus {
+    fn new() -> OpenBus {
+        OpenBus { val: 0, refreshed: [0; 8] }
+    }
 
-#[derive(Copy, Clone)]
-enum PpuScrollDir {
-    XDir,
-    YDir,
+    /// Returns the latch's value at CPU cycle `cy`, once decayed bits have faded.
+    fn get(&mut self, cy: u64) -> u8 {
+        for bit in 0..8 {
+            if cy.saturating_sub(self.refreshed[bit]) > OPEN_BUS_DECAY_CYCLES {
+                self.val &= !(1 << bit);
+            }
+        }
+        self.val
+    }
+
+    /// Drives the bits of `val` selected by `mask` onto the latch at CPU cycle `cy`.
+    fn refresh(&mut self, val: u8, mask: u8, cy: u64) {
+        self.val = (self.val & !mask) | (val & mask);
+        for bit in 0..8 {
+            if (mask & (1 << bit)) != 0 {
+                self.refreshed[bit] = cy;
+            }
+        }
+    }
 }
 
-save_enum!(PpuScrollDir { XDir, YDir });
+impl Save for OpenBus {
+    fn save(&mut self, fd: &mut File) {
+        self.val.save(fd);
+        for refreshed in self.refreshed.iter_mut() {
+            refreshed.save(fd);
+        }
+    }
+    fn load(&mut self, fd: &mut File) {
+        self.val.load(fd);
+        for refreshed in self.refreshed.iter_mut() {
+            refreshed.load(fd);
+        }
+    }
+}
 
 //
-// PPUADDR: 0x2006
+// The internal VRAM address: v and t
 //
 
+// 0yyy NNYY YYYX XXXX
+//  ||| |||| |||+-++++- coarse X scroll
+//  ||| ||++-+++------- coarse Y scroll
+//  ||| ++------------- nametable select
+//  +++---------------- fine Y scroll
 #[derive(Copy, Clone)]
-struct PpuAddr {
-    val: u16,
-    next: PpuAddrByte
+struct VramAddr { val: u16 }
+
+save_struct!(VramAddr { val });
+
+impl Deref for VramAddr {
+    type Target = u16;
+
+    fn deref(&self) -> &u16 {
+        &self.val
+    }
 }
 
-save_struct!(PpuAddr { val, next });
+impl VramAddr {
+    fn coarse_x(self) -> u16            { *self & 0x001f }
+    fn coarse_y(self) -> u16            { (*self >> 5) & 0x1f }
+    fn fine_y(self) -> u16              { (*self >> 12) & 0x07 }
+
+    /// The address of the nametable byte for the tile this points at.
+    fn tile_addr(self) -> u16           { 0x2000 | (*self & 0x0fff) }
+
+    /// The address of the attribute byte covering the tile this points at.
+    fn attribute_addr(self) -> u16 {
+        0x23c0 | (*self & 0x0c00) | ((self.coarse_y() >> 2) << 3) | (self.coarse_x() >> 2)
+    }
+
+    /// How far the attribute byte must be shifted right to get this tile's palette bits.
+    fn attribute_shift(self) -> u8 {
+        (((self.coarse_y() & 2) << 1) | (self.coarse_x() & 2)) as u8
+    }
+
+    /// Moves to the next tile to the right, wrapping into the horizontally adjacent nametable.
+    fn increment_x(&mut self) {
+        if self.coarse_x() == 31 {
+            self.val = (self.val & !0x001f) ^ 0x0400;
+        } else {
+            self.val += 1;
+        }
+    }
+
+    /// Moves down one pixel row, wrapping into the vertically adjacent nametable after row 29.
+    /// Coarse Y values of 30 and 31, which are only reachable by writing them, wrap to 0 without
+    /// switching nametables.
+    fn increment_y(&mut self) {
+        if self.fine_y() < 7 {
+            self.val += 0x1000;
+            return;
+        }
+        self.val &= !0x7000;
+        let coarse_y = match self.coarse_y() {
+            29 => {
+                self.val ^= 0x0800;
+                0
+            }
+            31 => 0,
+            coarse_y => coarse_y + 1,
+        };
+        self.val = (self.val & !0x03e0) | (coarse_y << 5);
+    }
+
+    /// Copies coarse X and the horizontal nametable bit from `from`.
+    fn copy_horizontal(&mut self, from: VramAddr) {
+        self.val = (self.val & !0x041f) | (from.val & 0x041f);
+    }
+
+    /// Copies fine Y, coarse Y and the vertical nametable bit from `from`.
+    fn copy_vertical(&mut self, from: VramAddr) {
+        self.val = (self.val & !0x7be0) | (from.val & 0x7be0);
+    }
+}
+
+//
+// The background fetch pipeline
+//
+// Every 8 dots the PPU fetches a nametable byte, an attribute byte and the two pattern planes of
+// the next tile into latches. Those are loaded into the low half of 16-bit shift registers, which
+// shift once per dot, so the high half always holds the tile being drawn. See
+// http://wiki.nesdev.com/w/index.php/PPU_rendering
+//
 
 #[derive(Copy, Clone)]
-enum PpuAddrByte {
-    Hi,
-    Lo,
+struct Background {
+    // Latches
+    nametable: u8,
+    attribute: u8,      // The two palette bits of the tile, already shifted into place.
+    pattern_lo: u8,
+    pattern_hi: u8,
+
+    // Shift registers. The attribute bits are expanded to 8 bits per tile to shift alongside.
+    shift_pattern_lo: u16,
+    shift_pattern_hi: u16,
+    shift_attribute_lo: u16,
+    shift_attribute_hi: u16,
 }
 
-save_enum!(PpuAddrByte { Hi, Lo });
+save_struct!(Background {
+    nametable, attribute, pattern_lo, pattern_hi,
+    shift_pattern_lo, shift_pattern_hi, shift_attribute_lo, shift_attribute_hi
+});
+
+impl Background {
+    fn new() -> Background {
+        Background {
+            nametable: 0,
+            attribute: 0,
+            pattern_lo: 0,
+            pattern_hi: 0,
+            shift_pattern_lo: 0,
+            shift_pattern_hi: 0,
+            shift_attribute_lo: 0,
+            shift_attribute_hi: 0,
+        }
+    }
+
+    fn shift(&mut self) {
+        self.shift_pattern_lo <<= 1;
+        self.shift_pattern_hi <<= 1;
+        self.shift_attribute_lo <<= 1;
+        self.shift_attribute_hi <<= 1;
+    }
+
+    /// Moves the latched tile into the low half of the shift registers.
+    fn reload(&mut self) {
+        fn expand(bit: u8) -> u16 { if bit != 0 { 0xff } else { 0 } }
+
+        self.shift_pattern_lo = (self.shift_pattern_lo & 0xff00) | self.pattern_lo as u16;
+        self.shift_pattern_hi = (self.shift_pattern_hi & 0xff00) | self.pattern_hi as u16;
+        self.shift_attribute_lo = (self.shift_attribute_lo & 0xff00) | expand(self.attribute & 1);
+        self.shift_attribute_hi = (self.shift_attribute_hi & 0xff00) | expand(self.attribute & 2);
+    }
+
+    /// Returns the palette RAM index of the current pixel, offset `fine_x` pixels to the right.
+    /// The low two bits are zero if the pixel is transparent.
+    fn pixel(&self, fine_x: u8) -> u8 {
+        let bit = 0x8000 >> fine_x;
+        let bit_of = |shift: u16, val: u8| if (shift & bit) != 0 { val } else { 0 };
+        bit_of(self.shift_pattern_lo, 1) | bit_of(self.shift_pattern_hi, 2) |
+            bit_of(self.shift_attribute_lo, 4) | bit_of(self.shift_attribute_hi, 8)
+    }
+}
 
 // PPU VRAM. This implements the same Mem trait that the CPU memory does.
 
 pub struct Vram {
     pub mapper: Rc<RefCell<Box<Mapper+Send>>>,
-    pub nametables: [u8; 0x800],  // 2 nametables, 0x400 each. FIXME: Not correct for all mappers.
+    /// The console's 2K of nametable RAM, followed by the extra 2K that four-screen boards carry.
+    /// The mapper decides which part of it each nametable address goes to.
+    pub nametables: [u8; 0x1000],
     pub palette: [u8; 0x20],
+    /// Where CHR-ROM reads are logged, if anywhere.
+    pub cdl: Option<Rc<RefCell<CodeDataLog>>>,
 }
 
 impl Vram {
     pub fn new(mapper: Rc<RefCell<Box<Mapper+Send>>>) -> Vram {
         Vram {
             mapper: mapper,
-            nametables: [ 0; 0x800 ],
-            palette: [ 0; 0x20 ]
+            nametables: [ 0; 0x1000 ],
+            palette: [ 0; 0x20 ],
+            cdl: None,
         }
     }
-}
 
-impl Mem for Vram {
+    /// Loads a byte for the CPU through PPUDATA. The code/data logger tells these apart from
+    /// rendering fetches.
+    pub fn load_ppudata(&mut self, addr: u16) -> u8 {
+        self.load_logged(addr, cdl::CHR_READ)
+    }
+
     #[inline(always)]
-    fn loadb(&mut self, addr: u16) -> u8 {
+    fn load_logged(&mut self, addr: u16, chr_flag: u8) -> u8 {
         let addr = addr & 0x3fff;   // mirrored at 0x4000...
         if addr < 0x2000 {          // Tilesets 0 or 1
             let mut mapper = self.mapper.borrow_mut();
+            if let Some(ref cdl) = self.cdl {
+                if let Some(offset) = mapper.chr_offset(addr) {
+                    cdl.borrow_mut().log_chr(offset, chr_flag);
+                }
+            }
             mapper.chr_loadb(addr)
         } else if addr < 0x3f00 {   // Name table area
-            self.nametables[addr as usize & 0x07ff]
+            let mut mapper = self.mapper.borrow_mut();
+            match mapper.nametable_loadb(addr) {
+                Some(val) => val,
+                None => self.nametables[mapper.mirroring().nametable_offset(addr)],
+            }
         } else if addr < 0x4000 {   // Palette area
-            self.palette[addr as usize & 0x1f]
+            self.palette[palette_offset(addr)]
         } else {
             unreachable!()
         }
     }
+}
+
+impl Mem for Vram {
+    #[inline(always)]
+    fn loadb(&mut self, addr: u16) -> u8 {
+        self.load_logged(addr, cdl::CHR_RENDERED)
+    }
 
     fn storeb(&mut self, addr: u16, val: u8) {
         let addr = addr & 0x3fff;
@@ -241,18 +427,23 @@ impl Mem for Vram {
             let mut mapper = self.mapper.borrow_mut();
             mapper.chr_storeb(addr, val)
         } else if addr < 0x3f00 {       // Name table area
-            let addr = addr & 0x07ff;
-            self.nametables[addr as usize] = val;
-        } else {                        // Palette area
-            let mut addr = addr & 0x1f;
-            if addr == 0x10 {
-                addr = 0x00;    // Mirror sprite background color into universal background color.
+            let mut mapper = self.mapper.borrow_mut();
+            if !mapper.nametable_storeb(addr, val) {
+                self.nametables[mapper.mirroring().nametable_offset(addr)] = val;
             }
-            self.palette[addr as usize] = val;
+        } else {                        // Palette area
+            self.palette[palette_offset(addr)] = val & 0x3f;
         }
     }
 }
 
+/// Returns the index into palette RAM of a PPU address in $3F00-$3FFF. Entry 0 of each sprite
+/// palette is the same byte as entry 0 of the matching background palette.
+fn palette_offset(addr: u16) -> usize {
+    let addr = addr as usize & 0x1f;
+    if (addr & 0x13) == 0x10 { addr & 0x0f } else { addr }
+}
+
 impl Save for Vram {
     fn save(&mut self, fd: &mut File) {
         let mut nametables: &mut [u8] = &mut self.nametables;
@@ -298,57 +489,71 @@ impl Save for Oam {
     }
 }
 
-struct SpriteStruct {
-    x: u8,
-    y: u8,
-    tile_index_byte: u8,
-    attribute_byte: u8,
+//
+// Sprite evaluation
+//
+// On each visible scanline the PPU searches OAM for the sprites on the next line and copies up
+// to eight of them to secondary OAM, reading OAM on odd dots and writing on even ones. At dots
+// 257-320 it fetches their patterns into eight sprite units, which draw them on the next line.
+// See http://wiki.nesdev.com/w/index.php/PPU_sprite_evaluation
+//
+
+/// How far sprite evaluation has got. OAMADDR is its pointer into OAM.
+#[derive(Copy, Clone)]
+struct SpriteEval {
+    secondary_index: u8,    // The next byte of secondary OAM to write.
+    count: u8,              // The number of sprites copied to secondary OAM.
+    copying: u8,            // Bytes left to copy of a sprite found on the line.
+    latch: u8,              // The byte read from OAM on the last odd dot.
+    sprite_zero: bool,      // The first sprite examined is on the line, so it went to slot 0.
+    done: bool,             // OAMADDR has wrapped around; the rest of the line is idle.
 }
 
-// Specifies the indices of the tiles that make up this sprite.
-enum SpriteTiles {
-    SpriteTiles8x8(u16),
-    SpriteTiles8x16(u16, u16)
+save_struct!(SpriteEval { secondary_index, count, copying, latch, sprite_zero, done });
+
+impl SpriteEval {
+    fn new() -> SpriteEval {
+        SpriteEval {
+            secondary_index: 0,
+            count: 0,
+            copying: 0,
+            latch: 0,
+            sprite_zero: false,
+            done: false,
+        }
+    }
 }
 
-use self::SpriteTiles::*;
+/// A sprite output unit, loaded with one of the sprites on the current scanline.
+#[derive(Copy, Clone)]
+struct SpriteUnit {
+    x: u8,
+    attribute: u8,
+    pattern_lo: u8,     // Already flipped if the sprite is flipped horizontally.
+    pattern_hi: u8,
+}
 
-impl SpriteStruct {
-    fn tiles(&self, ppu: &Ppu) -> SpriteTiles {
-        let base = ppu.regs.ctrl.sprite_pattern_table_addr();
-        match ppu.regs.ctrl.sprite_size() {
-            SpriteSize::SpriteSize8x8 => SpriteTiles8x8(self.tile_index_byte as u16 | base),
-            SpriteSize::SpriteSize8x16 => {
-                // We ignore the base set in PPUCTRL here.
-                let mut first = (self.tile_index_byte & !1) as u16;
-                if (self.tile_index_byte & 1) != 0 {
-                    first += 0x1000;
-                }
-                SpriteTiles8x16(first, first + 1)
-            }
-        }
+save_struct!(SpriteUnit { x, attribute, pattern_lo, pattern_hi });
+
+impl SpriteUnit {
+    fn new() -> SpriteUnit {
+        SpriteUnit { x: 0xff, attribute: 0, pattern_lo: 0, pattern_hi: 0 }
     }
 
-    fn palette(&self) -> u8                 { (self.attribute_byte & 3) + 4 }
-    fn flip_horizontal(&self) -> bool       { (self.attribute_byte & 0x40) != 0 }
-    fn flip_vertical(&self) -> bool         { (self.attribute_byte & 0x80) != 0 }
+    fn palette(&self) -> u8                 { (self.attribute & 3) + 4 }
 
     fn priority(&self) -> SpritePriority {
-        if (self.attribute_byte & 0x20) == 0 { AboveBg } else { BelowBg }
+        if (self.attribute & 0x20) == 0 { AboveBg } else { BelowBg }
     }
 
-    // Quick test to see whether this sprite is on the given scanline.
-    fn on_scanline(&self, ppu: &Ppu, y: u8) -> bool {
-        if y < self.y { return false; }
-        match ppu.regs.ctrl.sprite_size() {
-            SpriteSize::SpriteSize8x8 => (y as u16) < self.y as u16 + 8,
-            SpriteSize::SpriteSize8x16 => (y as u16) < self.y as u16 + 16
+    // Returns the color (pre-palette lookup) of the sprite at column `x` of the screen, or 0 if
+    // the sprite is transparent or not there.
+    fn pixel(&self, x: u8) -> u8 {
+        if x < self.x || x - self.x >= 8 {
+            return 0;
         }
-    }
-
-    // Quick test to see whether the given point is in the bounding box of this sprite.
-    fn in_bounding_box(&self, ppu: &Ppu, x: u8, y: u8) -> bool {
-        x >= self.x && (x as u16) < (self.x as u16 + 8) && self.on_scanline(ppu, y)
+        let shift = 7 - (x - self.x);
+        ((self.pattern_lo >> shift) & 1) | (((self.pattern_hi >> shift) & 1) << 1)
     }
 }
 
@@ -360,29 +565,36 @@ pub struct Ppu {
     oam: Oam,
 
     pub screen: Box<[u8; 184320]>,  // 256 * 240 * 3
+    background: Background,
+    secondary_oam: [u8; 0x20],
+    sprite_eval: SpriteEval,
+    sprites: [SpriteUnit; 8],   // The sprites on the current scanline, in OAM order.
+    sprite_zero_line: bool,     // Sprite 0 is in `sprites[0]`.
+    oam_bus: u8,                // What $2004 reads return during rendering.
     scanline: u16,
+    dot: u16,
+    odd_frame: bool,
+    suppress_vblank: bool,  // Set by a PPUSTATUS read just before the VBL flag would be set.
     ppudata_buffer: u8,
+    open_bus: OpenBus,
+    palette: Palette,   // The RGB output colors; not part of the console's state.
 
-    // NB: These two cannot always be computed from PPUCTRL and PPUSCROLL, because PPUADDR *also*
-    // updates the scroll position. This is important to emulate.
-    scroll_x: u16,
-    scroll_y: u16,
-
-    cy: u64
+    cy: u64     // The CPU cycle the PPU has been stepped up to.
 }
 
 impl Mem for Ppu {
     // Performs a load of the PPU register at the given CPU address.
     fn loadb(&mut self, addr: u16) -> u8 {
         debug_assert!(addr >= 0x2000 && addr < 0x4000, "invalid PPU register");
+        let cy = self.cy;
         match addr & 7 {
-            0 => 0, // PPUCTRL is write-only
-            1 => 0, // PPUMASK is write-only
+            0 => self.open_bus.get(cy), // PPUCTRL is write-only
+            1 => self.open_bus.get(cy), // PPUMASK is write-only
             2 => self.read_ppustatus(),
-            3 => 0, // OAMADDR is write-only
+            3 => self.open_bus.get(cy), // OAMADDR is write-only
             4 => self.read_oamdata(),
-            5 => 0, // PPUSCROLL is write-only
-            6 => 0, // PPUADDR is write-only
+            5 => self.open_bus.get(cy), // PPUSCROLL is write-only
+            6 => self.open_bus.get(cy), // PPUADDR is write-only
             7 => self.read_ppudata(),
             _ => unreachable!()
         }
@@ -391,6 +603,8 @@ impl Mem for Ppu {
     // Performs a store to the PPU register at the given CPU address.
     fn storeb(&mut self, addr: u16, val: u8) {
         debug_assert!(addr >= 0x2000 && addr < 0x4000, "invalid PPU register");
+        let cy = self.cy;
+        self.open_bus.refresh(val, 0xff, cy);
         match addr & 7 {
             0 => self.update_ppuctrl(val),
             1 => self.regs.mask = PpuMask{val: val},
@@ -408,8 +622,7 @@ impl Mem for Ppu {
 #[derive(PartialEq, Eq)]
 pub struct StepResult {
     pub new_frame: bool,    // We wrapped around to the next scanline.
-    pub vblank_nmi: bool,   // We entered VBLANK and must generate an NMI.
-    pub scanline_irq: bool, // The mapper wants to execute a scanline IRQ.
+    pub vblank_nmi: bool,   // The level of the NMI output at the end of the step.
 }
 
 #[derive(Copy, Clone)]
@@ -419,20 +632,9 @@ struct Rgb {
     b: u8,
 }
 
-enum PatternPixelKind {
-    Background,
-    Sprite,
-}
-
-struct NametableAddr {
-    base: u16,
-    x_index: u8,
-    y_index: u8,
-}
-
 struct SpriteColor {
     priority: SpritePriority,
-    color: Rgb,
+    color: u8,  // Offset into palette RAM.
 }
 
 enum SpritePriority {
@@ -447,20 +649,46 @@ impl Save for Ppu {
         self.regs.save(fd);
         self.vram.save(fd);
         self.oam.save(fd);
+        self.background.save(fd);
+        {
+            let mut secondary_oam: &mut [u8] = &mut self.secondary_oam;
+            secondary_oam.save(fd);
+        }
+        self.sprite_eval.save(fd);
+        for sprite in self.sprites.iter_mut() {
+            sprite.save(fd);
+        }
+        self.sprite_zero_line.save(fd);
+        self.oam_bus.save(fd);
         self.scanline.save(fd);
+        self.dot.save(fd);
+        self.odd_frame.save(fd);
+        self.suppress_vblank.save(fd);
         self.ppudata_buffer.save(fd);
-        self.scroll_x.save(fd);
-        self.scroll_y.save(fd);
+        self.open_bus.save(fd);
         self.cy.save(fd);
     }
     fn load(&mut self, fd: &mut File) {
         self.regs.load(fd);
         self.vram.load(fd);
         self.oam.load(fd);
+        self.background.load(fd);
+        {
+            let mut secondary_oam: &mut [u8] = &mut self.secondary_oam;
+            secondary_oam.load(fd);
+        }
+        self.sprite_eval.load(fd);
+        for sprite in self.sprites.iter_mut() {
+            sprite.load(fd);
+        }
+        self.sprite_zero_line.load(fd);
+        self.oam_bus.load(fd);
         self.scanline.load(fd);
+        self.dot.load(fd);
+        self.odd_frame.load(fd);
+        self.suppress_vblank.load(fd);
         self.ppudata_buffer.load(fd);
-        self.scroll_x.load(fd);
-        self.scroll_y.load(fd);
+        self.open_bus.load(fd);
         self.cy.load(fd);
     }
 }
@@ -473,34 +701,83 @@ impl Ppu {
                 mask: PpuMask{val: 0},
                 status: PpuStatus{val:0},
                 oam_addr: 0,
-                scroll: PpuScroll { x: 0, y: 0, next: PpuScrollDir::XDir },
-                addr: PpuAddr { val: 0, next: PpuAddrByte::Hi },
+                v: VramAddr { val: 0 },
+                t: VramAddr { val: 0 },
+                x: 0,
+                w: false,
             },
             vram: vram,
             oam: oam,
 
             screen: Box::new([ 0; 184320 ]),
+            background: Background::new(),
+            secondary_oam: [ 0xff; 0x20 ],
+            sprite_eval: SpriteEval::new(),
+            sprites: [ SpriteUnit::new(); 8 ],
+            sprite_zero_line: false,
+            oam_bus: 0,
             scanline: 0,
+            dot: 0,
+            odd_frame: false,
+            suppress_vblank: false,
             ppudata_buffer: 0,
-
-            scroll_x: 0,
-            scroll_y: 0,
+            open_bus: OpenBus::new(),
+            palette: Palette::default(),
 
             cy: 0
         }
     }
 
+    /// Returns the scanline and dot that the PPU is on at CPU cycle `cy`. If the PPU hasn't been
+    /// stepped up to `cy` yet, the position is extrapolated from where it is.
+    pub fn position(&self, cy: u64) -> (u16, u16) {
+        let dots = self.dot as u64 + (cy - self.cy) * DOTS_PER_CYCLE;
+        let scanline = (self.scanline as u64 + dots / DOTS_PER_SCANLINE as u64) %
+                       (LAST_SCANLINE as u64 + 1);
+        (scanline as u16, (dots % DOTS_PER_SCANLINE as u64) as u16)
+    }
+
+    /// Handles the console's reset button: the write-only registers, the scroll, the write toggle
+    /// and the read buffer are cleared. Memory, PPUSTATUS and the VRAM address are left alone.
+    pub fn reset(&mut self) {
+        self.regs.ctrl = PpuCtrl{val: 0};
+        self.regs.mask = PpuMask{val: 0};
+        self.regs.t = VramAddr { val: 0 };
+        self.regs.x = 0;
+        self.regs.w = false;
+        self.ppudata_buffer = 0;
+    }
+
+    /// Sets where CHR-ROM reads are logged.
+    pub fn set_code_data_log(&mut self, log: Option<Rc<RefCell<CodeDataLog>>>) {
+        self.vram.cdl = log;
+    }
+
+    /// Returns the PPU, including VRAM, palette RAM and OAM, to its power-up state.
+    pub fn power_on(&mut self) {
+        let mut vram = Vram::new(self.vram.mapper.clone());
+        vram.cdl = self.vram.cdl.take();
+        let palette = std::mem::replace(&mut self.palette, Palette::default());
+        *self = Ppu::new(vram, Oam::new());
+        self.palette = palette;
+    }
+
+    /// Sets the colors the screen is drawn with.
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
+
     //
     // Color utilities
     //
 
+    /// Applies PPUMASK's grayscale and color emphasis bits to a palette entry.
     #[inline(always)]
-    fn get_color(&self, palette_index: u8) -> Rgb {
-        Rgb {
-            r: PALETTE[palette_index as usize * 3 + 2],
-            g: PALETTE[palette_index as usize * 3 + 1],
-            b: PALETTE[palette_index as usize * 3 + 0],
-        }
+    fn get_output_color(&self, palette_index: u8) -> Rgb {
+        let mask = self.regs.mask;
+        let palette_index = if mask.grayscale() { palette_index & 0x30 } else { palette_index };
+        let (r, g, b) = self.palette.rgb(palette_index, mask.emphasis());
+        Rgb { r: r, g: g, b: b }
     }
 
     //
@@ -510,65 +787,78 @@ impl Ppu {
     fn update_ppuctrl(&mut self, val: u8) {
         self.regs.ctrl = PpuCtrl{val:val};
 
-        self.scroll_x = (self.scroll_x & 0xff) | self.regs.ctrl.x_scroll_offset();
-        self.scroll_y = (self.scroll_y & 0xff) | self.regs.ctrl.y_scroll_offset();
+        // The nametable select bits go to t.
+        self.regs.t.val = (self.regs.t.val & !0x0c00) | (self.regs.ctrl.nametable_select() << 10);
     }
 
     fn update_ppuscroll(&mut self, val: u8) {
-        match self.regs.scroll.next {
-            PpuScrollDir::XDir => {
-                self.scroll_x = (self.scroll_x & 0xff00) | (val as u16);
-
-                self.regs.scroll.x = val;
-                self.regs.scroll.next = PpuScrollDir::YDir;
-            }
-            PpuScrollDir::YDir => {
-                self.scroll_y = (self.scroll_y & 0xff00) | (val as u16);
-
-                self.regs.scroll.y = val;
-                self.regs.scroll.next = PpuScrollDir::XDir;
-            }
+        if !self.regs.w {
+            // Coarse X to t, fine X to x.
+            self.regs.t.val = (self.regs.t.val & !0x001f) | (val as u16 >> 3);
+            self.regs.x = val & 0x07;
+        } else {
+            // Coarse Y and fine Y to t.
+            self.regs.t.val = (self.regs.t.val & !0x73e0) |
+                              ((val as u16 & 0x07) << 12) |
+                              ((val as u16 & 0xf8) << 2);
         }
+        self.regs.w = !self.regs.w;
     }
 
     fn write_oamdata(&mut self, val: u8) {
-        self.oam.storeb(self.regs.oam_addr as u16, val);
-        self.regs.oam_addr += 1;
+        // While rendering, OAM is busy with sprite evaluation. The write is lost, and OAMADDR is
+        // bumped to the next sprite instead.
+        if self.is_rendering() {
+            self.regs.oam_addr = self.regs.oam_addr.wrapping_add(4);
+            return;
+        }
+
+        // Bits 2-4 of the attribute byte don't exist.
+        let addr = self.regs.oam_addr;
+        let val = if (addr & 3) == 2 { val & 0xe3 } else { val };
+        self.oam.storeb(addr as u16, val);
+        self.regs.oam_addr = addr.wrapping_add(1);
     }
 
+    // Unlike writes, reads don't increment OAMADDR.
     fn read_oamdata(&mut self) -> u8 {
-        let val = self.oam.loadb(self.regs.oam_addr as u16);
-        self.regs.oam_addr += 1;
+        let val = if self.is_rendering() {
+            self.oam_bus
+        } else {
+            self.oam.loadb(self.regs.oam_addr as u16)
+        };
+        let cy = self.cy;
+        self.open_bus.refresh(val, 0xff, cy);
         val
     }
 
     fn update_ppuaddr(&mut self, val: u8) {
-        match self.regs.addr.next {
-            PpuAddrByte::Hi => {
-                self.regs.addr.val = (self.regs.addr.val & 0x00ff) | ((val as u16) << 8);
-                self.regs.addr.next = PpuAddrByte::Lo;
-            }
-            PpuAddrByte::Lo => {
-                self.regs.addr.val = (self.regs.addr.val & 0xff00) | (val as u16);
-                self.regs.addr.next = PpuAddrByte::Hi;
-
-                // Adjust the scroll registers.
-                // TODO: This is pretty much a hack. The right way is to precisely emulate the PPU
-                // internal registers.
-                // TODO: Y scrolling.
-                let addr = self.regs.addr.val & 0x07ff;
-                let xscroll_base = if addr < 0x400 { 0 } else { 256 };
-                self.scroll_x = (self.scroll_x & 0xff) | xscroll_base;
-            }
+        if !self.regs.w {
+            // The high byte goes to t. Bit 14 is cleared, since addresses are only 14 bits.
+            self.regs.t.val = (self.regs.t.val & 0x00ff) | ((val as u16 & 0x3f) << 8);
+        } else {
+            // The low byte goes to t, and then all of t is copied to v.
+            self.regs.t.val = (self.regs.t.val & 0xff00) | (val as u16);
+            self.regs.v = self.regs.t;
         }
+        self.regs.w = !self.regs.w;
     }
 
     fn read_ppustatus(&mut self) -> u8 {
-        // Reset latch.
-        self.regs.scroll.next = PpuScrollDir::XDir;
-        self.regs.addr.next = PpuAddrByte::Hi;
+        // Reset the write toggle.
+        self.regs.w = false;
 
-        let value = self.regs.status.val;
+        // If the VBL flag is about to be set on the next dot, this read sees it clear and stops it
+        // from being set at all this frame.
+        if self.scanline == VBLANK_SCANLINE && self.dot == 1 {
+            self.suppress_vblank = true;
+        }
+
+        // Only the top three bits are driven; the rest come from the I/O latch.
+        let cy = self.cy;
+        let status = self.regs.status.val;
+        self.open_bus.refresh(status, 0xe0, cy);
+        let value = self.open_bus.get(cy);
 
         // Clear bit 7 of PPUSTATUS
         self.regs.status.val &= !(1 << 7);
@@ -577,54 +867,44 @@ impl Ppu {
     }
 
     fn write_ppudata(&mut self, val: u8) {
-        self.vram.storeb(self.regs.addr.val, val);
-        self.regs.addr.val += self.regs.ctrl.vram_addr_increment();
+        self.vram.storeb(*self.regs.v, val);
+        self.increment_ppudata_addr();
     }
 
     fn read_ppudata(&mut self) -> u8 {
-        let addr = self.regs.addr.val;
-        let val = self.vram.loadb(addr);
-        self.regs.addr.val += self.regs.ctrl.vram_addr_increment();
-
-        // Emulate the PPU buffering quirk.
-        if addr < 0x3f00 {
+        let addr = *self.regs.v;
+        let val = self.vram.load_ppudata(addr);
+        self.increment_ppudata_addr();
+
+        // Emulate the PPU buffering quirk. Palette RAM is only 6 bits wide, and the top two bits
+        // come from the I/O latch.
+        let cy = self.cy;
+        if (addr & 0x3fff) < 0x3f00 {
             let buffered_val = self.ppudata_buffer;
             self.ppudata_buffer = val;
-            buffered_val
+            self.open_bus.refresh(buffered_val, 0xff, cy);
         } else {
-            val
+            self.open_bus.refresh(val, 0x3f, cy);
         }
+        self.open_bus.get(cy)
     }
 
-    //
-    // Background rendering helpers
-    //
-
-    fn nametable_addr(&mut self, mut x_index: u16, mut y_index: u16) -> NametableAddr {
-        x_index %= 64;
-        y_index %= 60;
-
-        let nametable_base = match (x_index >= 32, y_index >= 30) {
-            (false, false)  => 0x2000,
-            (true, false)   => 0x2400,
-            (false, true)   => 0x2800,
-            (true, true)    => 0x2c00,
-        };
-
-        NametableAddr {
-            base: nametable_base,
-            x_index: (x_index % 32) as u8,
-            y_index: (y_index % 30) as u8
-        }
+    /// Returns true on the lines the PPU fetches on, if rendering is enabled. VRAM and OAM are in
+    /// use then, so CPU accesses to them misbehave.
+    fn is_rendering(&self) -> bool {
+        let rendering_line = self.scanline < SCREEN_HEIGHT as u16 ||
+                             self.scanline == LAST_SCANLINE;
+        self.regs.mask.rendering_enabled() && rendering_line
     }
 
-    #[inline(always)]
-    fn make_sprite_info(&mut self, index: u16) -> SpriteStruct {
-        SpriteStruct {
-            y: self.oam.loadb(index * 4 + 0) + 1,
-            tile_index_byte: self.oam.loadb(index * 4 + 1),
-            attribute_byte: self.oam.loadb(index * 4 + 2),
-            x: self.oam.loadb(index * 4 + 3),
+    /// Steps v after a PPUDATA access. While the PPU is rendering, v is its position on the
+    /// screen, and the access bumps it both horizontally and vertically instead.
+    fn increment_ppudata_addr(&mut self) {
+        if self.is_rendering() {
+            self.regs.v.increment_x();
+            self.regs.v.increment_y();
+        } else {
+            self.regs.v.val = (self.regs.v.val + self.regs.ctrl.vram_addr_increment()) & 0x7fff;
         }
     }
 
@@ -632,241 +912,347 @@ impl Ppu {
     // Rendering
     //
 
+    // The screen is stored as BGR, which is what the window's texture takes.
     #[inline(always)]
     fn putpixel(&mut self, x: usize, y: usize, color: Rgb) {
-        self.screen[(y * SCREEN_WIDTH + x) * 3 + 0] = color.r;
+        self.screen[(y * SCREEN_WIDTH + x) * 3 + 0] = color.b;
         self.screen[(y * SCREEN_WIDTH + x) * 3 + 1] = color.g;
-        self.screen[(y * SCREEN_WIDTH + x) * 3 + 2] = color.b;
+        self.screen[(y * SCREEN_WIDTH + x) * 3 + 2] = color.r;
     }
 
-    // Returns the color (pre-palette lookup) of pixel (x,y) within the given tile.
+    // Returns the palette RAM offset of the background pixel being drawn, or None if it is
+    // transparent.
     #[inline(always)]
-    fn get_pattern_pixel(&mut self, kind: PatternPixelKind, tile: u16, x: u8, y: u8) -> u8 {
-        // Compute the pattern offset.
-        let mut pattern_offset = (tile << 4) + (y as u16);
-        match kind {
-            PatternPixelKind::Background => pattern_offset += self.regs.ctrl.background_pattern_table_addr(),
-            PatternPixelKind::Sprite     => pattern_offset += self.regs.ctrl.sprite_pattern_table_addr(),
+    fn get_background_pixel(&mut self) -> Option<u8> {
+        let tile_color = self.background.pixel(self.regs.x);
+        if (tile_color & 3) == 0 {
+            return None;    // Transparent.
         }
-
-        // Determine the color of this pixel.
-        let plane0 = self.vram.loadb(pattern_offset);
-        let plane1 = self.vram.loadb(pattern_offset + 8);
-        let bit0 = (plane0 >> ((7 - ((x % 8) as u8)) as usize)) & 1;
-        let bit1 = (plane1 >> ((7 - ((x % 8) as u8)) as usize)) & 1;
-        (bit1 << 1) | bit0
+        Some(tile_color)
     }
 
-    // Returns true if the background was opaque here, false otherwise.
-    #[inline(always)]
-    fn get_background_pixel(&mut self, x: u8) -> Option<Rgb> {
-        // Adjust X and Y to account for scrolling.
-        let x = x as u16 + self.scroll_x;
-        let y = self.scanline as u16 + self.scroll_y;
-
-        // Compute the nametable address, tile index, and pixel offset within that tile.
-        let NametableAddr { base, x_index, y_index } = self.nametable_addr(x / 8, y / 8);
-        let (xsub, ysub) = ((x % 8) as u8, (y % 8) as u8);
+    fn get_sprite_pixel(&mut self, x: u8, background_opaque: bool) -> Option<SpriteColor> {
+        for (slot, sprite) in self.sprites.iter().enumerate() {
+            // If the pattern color was zero, this part of the sprite is transparent.
+            let pattern_color = sprite.pixel(x);
+            if pattern_color == 0 {
+                continue
+            }
 
-        // Compute the nametable address and load the tile number from the nametable.
-        let tile = self.vram.loadb(base + 32 * (y_index as u16) + (x_index as u16));
+            // OK, so we know this pixel is opaque. Now if this is the first sprite and the
+            // background was not transparent, we might have a sprite-0 hit.
+            // (x=255 never triggers a hit. Neither do pixels hidden by left-column clipping,
+            // which never get this far.)
+            if slot == 0 && self.sprite_zero_line && background_opaque && x < 255 {
+                self.regs.status.set_sprite_zero_hit(true);
+            }
 
-        // Fetch the pattern color.
-        let pattern_color = self.get_pattern_pixel(PatternPixelKind::Background, tile as u16, xsub, ysub);
-        if pattern_color == 0 {
-            return None;    // Transparent.
+            // Determine final tile color.
+            let tile_color = (sprite.palette() << 2) | pattern_color;
+            return Some(SpriteColor { priority: sprite.priority(), color: tile_color });
         }
+        None
+    }
 
-        // Now load the attribute bits from the attribute table.
-        let group = y_index / 4 * 8 + x_index / 4;
-        let attr_byte = self.vram.loadb(base + 0x3c0 + (group as u16));
-        let (left, top) = (x_index % 4 < 2, y_index % 4 < 2);
-        let attr_table_color = match (left, top) {
-            (true, true) => attr_byte & 0x3,
-            (false, true) => (attr_byte >> 2) & 0x3,
-            (true, false) => (attr_byte >> 4) & 0x3,
-            (false, false) => (attr_byte >> 6) & 0x3
-        };
-
-        // Determine the final color and fetch the palette from VRAM.
-        let tile_color = (attr_table_color << 2) | pattern_color;
-        let palette_index = self.vram.loadb(0x3f00 + (tile_color as u16)) & 0x3f;
-        return Some(self.get_color(palette_index));
-    }
-
-    fn get_sprite_pixel(&mut self,
-                        visible_sprites: &[Option<u8>; 8],
-                        x: u8,
-                        background_opaque: bool)
-                     -> Option<SpriteColor> {
-        for &visible_sprite_opt in visible_sprites.iter() {
-            match visible_sprite_opt {
-                None => return None,
-                Some(index) => {
-                    let sprite = self.make_sprite_info(index as u16);
-
-                    // Don't need to consider this sprite if we aren't in its bounding box.
-                    if !sprite.in_bounding_box(self, x, self.scanline as u8) {
-                        continue
-                    }
-
-                    let pattern_color;
-                    match sprite.tiles(self) {
-                        // TODO: 8x16 rendering
-                        SpriteTiles8x8(tile) | SpriteTiles8x16(tile, _) => {
-                            let mut x = x - sprite.x;
-                            if sprite.flip_horizontal() { x = 7 - x; }
-
-                            let mut y = self.scanline as u8 - sprite.y;
-                            if sprite.flip_vertical() { y = 7 - y; }
+    /// Performs the sprite work for dot `dot` of a visible or pre-render scanline: clearing
+    /// secondary OAM, evaluating the sprites on the next line, then fetching them.
+    fn step_sprites(&mut self, dot: u16, visible_line: bool) {
+        match dot {
+            1 ... 64 if visible_line => {
+                // Secondary OAM is cleared to $FF, a byte every two dots. OAM reads see $FF.
+                self.oam_bus = 0xff;
+                if dot % 2 == 0 {
+                    self.secondary_oam[(dot / 2 - 1) as usize] = 0xff;
+                }
+            }
+            65 ... 256 if visible_line => {
+                if dot == 65 {
+                    self.sprite_eval = SpriteEval::new();
+                }
+                if dot % 2 == 1 {
+                    let val = self.oam.loadb(self.regs.oam_addr as u16);
+                    self.sprite_eval.latch = val;
+                    self.oam_bus = val;
+                } else {
+                    self.evaluate_sprite(dot == 66);
+                }
+            }
+            257 ... 320 => {
+                // OAMADDR is held at 0 while the sprites are fetched from secondary OAM.
+                self.regs.oam_addr = 0;
+                let slot = ((dot - 257) / 8) as usize;
+                let byte = cmp::min((dot - 257) % 8, 3) as usize;
+                self.oam_bus = self.secondary_oam[slot * 4 + byte];
+                if (dot - 257) % 8 == 0 {
+                    self.load_sprite(slot, visible_line);
+                }
+                if dot == 257 {
+                    self.sprite_zero_line = visible_line && self.sprite_eval.sprite_zero;
+                }
+            }
+            _ => self.oam_bus = self.secondary_oam[0],
+        }
+    }
 
-                            debug_assert!(x < 8, "sprite X miscalculation");
-                            debug_assert!(y < 8, "sprite Y miscalculation");
+    /// Acts on the byte of OAM read on the previous dot during sprite evaluation.
+    fn evaluate_sprite(&mut self, first: bool) {
+        let mut eval = self.sprite_eval;
+        let addr = self.regs.oam_addr;
+        let in_range = self.scanline.wrapping_sub(eval.latch as u16) <
+                       self.regs.ctrl.sprite_height();
+
+        if eval.done {
+            // Idle until hblank, still stepping through OAM.
+            self.regs.oam_addr = addr.wrapping_add(4) & 0xfc;
+        } else if eval.count < 8 {
+            // Every byte examined is written to secondary OAM, but the write pointer only moves
+            // on when a sprite on the line is being copied.
+            self.secondary_oam[eval.secondary_index as usize] = eval.latch;
+            let (next, wrapped) = if eval.copying > 0 {
+                eval.copying -= 1;
+                eval.secondary_index += 1;
+                if eval.copying == 0 {
+                    eval.count += 1;
+                }
+                addr.overflowing_add(1)
+            } else if in_range {
+                eval.sprite_zero |= first;
+                eval.copying = 3;
+                eval.secondary_index += 1;
+                addr.overflowing_add(1)
+            } else {
+                addr.overflowing_add(4)
+            };
+            self.regs.oam_addr = next;
+            eval.done = wrapped && eval.copying == 0;
+        } else if eval.copying > 0 {
+            // Reading the rest of the sprite that set the overflow flag.
+            eval.copying -= 1;
+            let (next, wrapped) = addr.overflowing_add(1);
+            self.regs.oam_addr = next;
+            eval.done = wrapped || eval.copying == 0;
+        } else if in_range {
+            self.regs.status.set_sprite_overflow(true);
+            eval.copying = 3;
+            self.regs.oam_addr = addr.wrapping_add(1);
+        } else {
+            // Secondary OAM is full. Looking for a ninth sprite, the PPU moves to the next sprite
+            // but also to the next byte within it, so it compares tile numbers, attributes and X
+            // positions against the scanline. This gives both false overflows and missed ones.
+            self.regs.oam_addr = (addr.wrapping_add(4) & 0xfc) | (addr.wrapping_add(1) & 3);
+            eval.done = (addr & 0xfc) == 0xfc;
+        }
 
-                            pattern_color = self.get_pattern_pixel(PatternPixelKind::Sprite, tile, x, y);
-                        }
-                    }
+        self.sprite_eval = eval;
+    }
 
-                    // If the pattern color was zero, this part of the sprite is transparent.
-                    if pattern_color == 0 {
-                        continue
-                    }
+    /// Loads sprite unit `slot` from secondary OAM and fetches its pattern for the next line.
+    /// Unused units fetch a transparent pattern.
+    fn load_sprite(&mut self, slot: usize, visible_line: bool) {
+        let mut sprite = SpriteUnit::new();
+        if visible_line && (slot as u8) < self.sprite_eval.count {
+            let y = self.secondary_oam[slot * 4 + 0];
+            let tile = self.secondary_oam[slot * 4 + 1];
+            sprite.attribute = self.secondary_oam[slot * 4 + 2];
+            sprite.x = self.secondary_oam[slot * 4 + 3];
+
+            let row = self.scanline.wrapping_sub(y as u16) as u8;
+            let pattern_offset = self.sprite_pattern_addr(tile, sprite.attribute, row);
+            sprite.pattern_lo = self.vram.loadb(pattern_offset);
+            sprite.pattern_hi = self.vram.loadb(pattern_offset + 8);
+            if (sprite.attribute & 0x40) != 0 {
+                sprite.pattern_lo = sprite.pattern_lo.reverse_bits();
+                sprite.pattern_hi = sprite.pattern_hi.reverse_bits();
+            }
+        }
+        self.sprites[slot] = sprite;
+    }
 
-                    // OK, so we know this pixel is opaque. Now if this is the first sprite and the
-                    // background was not transparent, we might have a sprite-0 hit.
-                    if index == 0 && background_opaque && x < 255 {
-                        // (x=255 never triggers a hit)
+    /// Returns the address of the first plane of row `row` of a sprite, counting from the top as
+    /// drawn.
+    fn sprite_pattern_addr(&self, tile: u8, attribute: u8, row: u8) -> u16 {
+        let height = self.regs.ctrl.sprite_height() as u8;
+        let mut row = row & (height - 1);
+        if (attribute & 0x80) != 0 {
+            row = height - 1 - row;
+        }
 
-                        // If clipping is enabled, x values form 0 to 7 don't trigger a hit
-                        if x > 7 || self.regs.mask.val & 0b110 == 0b110 {
-                            self.regs.status.set_sprite_zero_hit(true);
-                        }
-                    }
+        match self.regs.ctrl.sprite_size() {
+            SpriteSize::SpriteSize8x8 => {
+                self.regs.ctrl.sprite_pattern_table_addr() + ((tile as u16) << 4) + row as u16
+            }
+            SpriteSize::SpriteSize8x16 => {
+                // We ignore the base set in PPUCTRL here. Bit 0 of the tile index selects the
+                // pattern table, and the rest is the index of the top tile. Flipping a tall
+                // sprite vertically also swaps its two tiles.
+                let base = if (tile & 1) != 0 { 0x1000 } else { 0 };
+                let tile = (tile & !1) as u16 + (row / 8) as u16;
+                base + (tile << 4) + (row % 8) as u16
+            }
+        }
+    }
 
-                    // Determine final tile color and do the palette lookup.
-                    let tile_color = (sprite.palette() << 2) | pattern_color;
-                    let palette_index = self.vram.loadb(0x3f00 + (tile_color as u16)) & 0x3f;
-                    let final_color = self.get_color(palette_index);
+    /// Performs the fetch for dot `dot` of a visible or pre-render scanline, and keeps the
+    /// background shift registers and v moving.
+    fn fetch_background(&mut self, dot: u16) {
+        if (dot >= 2 && dot <= 257) || (dot >= 322 && dot <= 337) {
+            self.background.shift();
+        }
+        if dot % 8 == 1 && ((dot >= 9 && dot <= 257) || dot == 329 || dot == 337) {
+            self.background.reload();
+        }
 
-                    return Some(SpriteColor { priority: sprite.priority(), color: final_color });
+        // Tiles 3 to 34 of this line are fetched at dots 1-256, and tiles 1 and 2 of the next
+        // line at dots 321-336.
+        if (dot >= 1 && dot <= 256) || (dot >= 321 && dot <= 336) {
+            let v = self.regs.v;
+            match dot % 8 {
+                1 => self.background.nametable = self.vram.loadb(v.tile_addr()),
+                3 => {
+                    let attr_byte = self.vram.loadb(v.attribute_addr());
+                    self.background.attribute = (attr_byte >> v.attribute_shift()) & 3;
                 }
-            }
-        }
-        return None;
-    }
-
-    fn compute_visible_sprites(&mut self) -> [Option<u8>; 8] {
-        let mut count = 0;
-        let mut result = [None; 8];
-        let mut i = 0;  // Current sprite index
-
-        while i < 64 {
-            let sprite = self.make_sprite_info(i as u16);
-            if sprite.on_scanline(self, self.scanline as u8) {
-                result[count] = Some(i);
-                count += 1;
-
-                if count == 8 {
-                    // All slots filled, this is where the sprite overflow bug happens
-                    // Let's check the next sprite. If it's on the scanline, set the overflow flag.
-                    // If not, we're done (don't emulate the overflow bug)
-                    if i + 1 < 64 {
-                        let sprite = self.make_sprite_info(i as u16 + 1);
-                        if sprite.on_scanline(self, self.scanline as u8) {
-                            self.regs.status.set_sprite_overflow(true);
-                        }
+                5 | 7 => {
+                    let pattern_offset = self.regs.ctrl.background_pattern_table_addr() +
+                                         ((self.background.nametable as u16) << 4) +
+                                         v.fine_y();
+                    if dot % 8 == 5 {
+                        self.background.pattern_lo = self.vram.loadb(pattern_offset);
+                    } else {
+                        self.background.pattern_hi = self.vram.loadb(pattern_offset + 8);
                     }
-
-                    break
                 }
+                0 => self.regs.v.increment_x(),
+                _ => {}
             }
-
-            i += 1;
         }
 
-        result
+        let t = self.regs.t;
+        match dot {
+            256 => self.regs.v.increment_y(),
+            257 => self.regs.v.copy_horizontal(t),
+            280 ... 304 if self.scanline == LAST_SCANLINE => self.regs.v.copy_vertical(t),
+            _ => {}
+        }
     }
 
-    fn render_scanline(&mut self) {
-        // TODO: Scrolling, mirroring
-        let visible_sprites = self.compute_visible_sprites();
+    fn render_pixel(&mut self, x: u8) {
+        // PPUMASK can hide either layer in the leftmost 8 pixels.
+        let mask = self.regs.mask;
+        let mut background_color = None;
+        if mask.show_background() && (x >= 8 || mask.show_background_left()) {
+            background_color = self.get_background_pixel();
+        }
 
-        let backdrop_color_index = self.vram.loadb(0x3f00) & 0x3f;
-        let backdrop_color = self.get_color(backdrop_color_index);
+        let mut sprite_color = None;
+        if mask.show_sprites() && (x >= 8 || mask.show_sprites_left()) {
+            sprite_color = self.get_sprite_pixel(x, background_color.is_some());
+        }
 
-        for x in 0..SCREEN_WIDTH {
-            // FIXME: For performance, we shouldn't be recomputing the tile for every pixel.
-            let mut background_color = None;
-            if self.regs.mask.show_background() {
-                background_color = self.get_background_pixel(x as u8);
-            }
+        // Combine colors using priority.
+        let tile_color = match (background_color, sprite_color) {
+            (None, None) => 0,  // The backdrop color.
+            (Some(color), None) => color,
+            (Some(color), Some(SpriteColor { priority: BelowBg, .. })) => color,
+            (None, Some(SpriteColor { priority: BelowBg, color })) => color,
+            (_, Some(SpriteColor { priority: AboveBg, color })) => color,
+        };
 
-            let mut sprite_color = None;
-            if self.regs.mask.show_sprites() {
-                sprite_color = self.get_sprite_pixel(&visible_sprites,
-                                                     x as u8,
-                                                     background_color.is_some());
-            }
+        let palette_index = self.vram.loadb(0x3f00 + tile_color as u16);
+        let color = self.get_output_color(palette_index);
 
-            // Combine colors using priority.
-            let color = match (background_color, sprite_color) {
-                (None, None) => backdrop_color,
-                (Some(color), None) => color,
-                (Some(color), Some(SpriteColor { priority: BelowBg, .. })) => color,
-                (None, Some(SpriteColor { priority: BelowBg, color })) => color,
-                (_, Some(SpriteColor { priority: AboveBg, color })) => color,
-            };
+        let scanline = self.scanline;
+        self.putpixel(x as usize, scanline as usize, color);
+    }
 
-            let scanline = self.scanline;
-            self.putpixel(x, scanline as usize, color);
+    fn start_vblank(&mut self) {
+        // A PPUSTATUS read on the dot before cancels the flag, and so the NMI, for this frame.
+        if self.suppress_vblank {
+            self.suppress_vblank = false;
+        } else {
+            self.regs.status.set_in_vblank(true);
         }
     }
 
-    fn start_vblank(&mut self, result: &mut StepResult) {
-        self.regs.status.set_in_vblank(true);
-
-        if self.regs.ctrl.vblank_nmi() {
-            result.vblank_nmi = true;
-        }
+    /// Returns true while the PPU is asserting NMI: whenever the VBL flag and the NMI enable bit
+    /// of PPUCTRL are both set. Setting the enable bit during vblank therefore causes another NMI.
+    /// The output follows the flag a few dots late; reading PPUSTATUS in that window clears the
+    /// flag before the CPU sees an NMI.
+    pub fn nmi_output(&self) -> bool {
+        let just_set = self.scanline == VBLANK_SCANLINE && self.dot <= 3;
+        self.regs.status.in_vblank() && self.regs.ctrl.vblank_nmi() && !just_set
     }
 
-    #[inline(never)]
-    pub fn step(&mut self, run_to_cycle: u64) -> StepResult {
-        let mut result = StepResult { new_frame: false, vblank_nmi: false, scanline_irq: false };
-        loop {
-            let next_scanline_cycle: u64 = self.cy + CYCLES_PER_SCANLINE;
-            if next_scanline_cycle > run_to_cycle {
-                break;
-            }
+    /// Runs the PPU for one dot.
+    fn tick(&mut self, result: &mut StepResult) {
+        let rendering = self.regs.mask.rendering_enabled();
+        let visible_line = self.scanline < SCREEN_HEIGHT as u16;
+        let dot = self.dot;
 
-            if self.scanline < (SCREEN_HEIGHT as u16) {
-                self.render_scanline();
+        if visible_line || self.scanline == LAST_SCANLINE {
+            if self.scanline == LAST_SCANLINE && dot == 1 {
+                self.regs.status.set_in_vblank(false);
+                self.regs.status.set_sprite_zero_hit(false);
+                self.regs.status.set_sprite_overflow(false);
+
+                // When rendering starts with OAMADDR past the first two sprites, the row of OAM it
+                // points into is copied over them.
+                let addr = self.regs.oam_addr;
+                if rendering && addr >= 8 {
+                    for i in 0..8 {
+                        let val = self.oam.loadb(((addr & 0xf8) + i) as u16);
+                        self.oam.storeb(i as u16, val);
+                    }
+                }
             }
 
-            self.scanline += 1;
+            if rendering {
+                self.fetch_background(dot);
+                self.step_sprites(dot, visible_line);
+            }
 
-            {
-                let mut mapper = self.vram.mapper.borrow_mut();
-                if mapper.next_scanline() == MapperResult::Irq {
-                    result.scanline_irq = true
-                }
+            if visible_line && dot >= 1 && dot <= SCREEN_WIDTH as u16 {
+                self.render_pixel((dot - 1) as u8);
             }
 
-            if self.scanline == (VBLANK_SCANLINE as u16) {
-                self.start_vblank(&mut result);
-            } else if self.scanline == (LAST_SCANLINE as u16) {
-                result.new_frame = true;
-                self.scanline = 0;
-                self.regs.status.set_in_vblank(false);
-                self.regs.status.set_sprite_zero_hit(false);
-                self.regs.status.set_sprite_overflow(false);
+            // Sprite patterns are fetched from dot 257 on, which is where the MMC3 sees A12 rise.
+            if rendering && dot == 260 {
+                self.vram.mapper.borrow_mut().next_scanline();
             }
+        } else if self.scanline == VBLANK_SCANLINE && dot == 1 {
+            self.start_vblank();
+        }
 
-            self.cy += CYCLES_PER_SCANLINE;
+        // On odd frames the pre-render line skips its last dot if the PPU is rendering.
+        let last_dot = if self.scanline == LAST_SCANLINE && self.odd_frame && rendering {
+            DOTS_PER_SCANLINE - 2
+        } else {
+            DOTS_PER_SCANLINE - 1
+        };
+        if dot < last_dot {
+            self.dot += 1;
+            return;
+        }
 
-            debug_assert!(self.cy % CYCLES_PER_SCANLINE == 0, "at even scanline cycle");
+        self.dot = 0;
+        self.scanline += 1;
+        if self.scanline > LAST_SCANLINE {
+            result.new_frame = true;
+            self.scanline = 0;
+            self.odd_frame = !self.odd_frame;
         }
+    }
 
-        return result;
+    #[inline(never)]
+    pub fn step(&mut self, run_to_cycle: u64) -> StepResult {
+        let mut result = StepResult { new_frame: false, vblank_nmi: false };
+        while self.cy < run_to_cycle {
+            for _ in 0..DOTS_PER_CYCLE {
+                self.tick(&mut result);
+            }
+            self.cy += 1;
+        }
+        result.vblank_nmi = self.nmi_output();
+        result
     }
 }
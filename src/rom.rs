@@ -4,6 +4,7 @@
 // Author: Patrick Walton
 //
 
+use mapper::Mirroring;
 use util;
 
 use std::io::{self, Read};
@@ -25,6 +26,7 @@ impl From<io::Error> for RomLoadError {
 }
 
 /// A ROM image
+#[derive(Clone)]
 pub struct Rom {
     pub header: INesHeader,
     /// PRG-ROM
@@ -73,6 +75,7 @@ impl Rom {
     }
 }
 
+#[derive(Clone)]
 pub struct INesHeader {
     /// 'N' 'E' 'S' '\x1a'
     pub magic: [u8; 4],
@@ -122,6 +125,17 @@ impl INesHeader {
     pub fn trainer(&self) -> bool {
         (self.flags_6 & 0x04) != 0
     }
+
+    /// Returns the nametable layout wired on the board. Mappers that can switch it may override this.
+    pub fn mirroring(&self) -> Mirroring {
+        if (self.flags_6 & 0x08) != 0 {
+            Mirroring::FourScreen
+        } else if (self.flags_6 & 0x01) != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        }
+    }
 }
 
 impl fmt::Display for INesHeader {
@@ -135,3 +149,59 @@ impl fmt::Display for INesHeader {
         )
     }
 }
+
+/// Builds iNES images, so that tests can make cartridges out of programs they assemble themselves.
+pub struct RomBuilder {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    mapper: u8,
+    vertical_mirroring: bool,
+}
+
+impl RomBuilder {
+    /// Starts an NROM image with horizontal mirroring and 8K of blank CHR-ROM. PRG-ROM must come
+    /// in 16K units.
+    pub fn new(prg: Vec<u8>) -> RomBuilder {
+        assert!(prg.len() > 0 && prg.len() % 16384 == 0, "PRG-ROM must be a multiple of 16K");
+        RomBuilder {
+            prg: prg,
+            chr: vec![ 0; 8192 ],
+            mapper: 0,
+            vertical_mirroring: false,
+        }
+    }
+
+    /// Sets the CHR-ROM, which must come in 8K units. Empty CHR-ROM means the board has CHR-RAM.
+    pub fn chr(mut self, chr: Vec<u8>) -> RomBuilder {
+        assert!(chr.len() % 8192 == 0, "CHR-ROM must be a multiple of 8K");
+        self.chr = chr;
+        self
+    }
+
+    pub fn mapper(mut self, mapper: u8) -> RomBuilder {
+        self.mapper = mapper;
+        self
+    }
+
+    pub fn vertical_mirroring(mut self, vertical: bool) -> RomBuilder {
+        self.vertical_mirroring = vertical;
+        self
+    }
+
+    /// Returns the image as it would be stored in a `.nes` file.
+    pub fn to_ines(&self) -> Vec<u8> {
+        let mut image = b"NES\x1a".to_vec();
+        image.push((self.prg.len() / 16384) as u8);
+        image.push((self.chr.len() / 8192) as u8);
+        image.push((self.mapper << 4) | self.vertical_mirroring as u8);
+        image.push(self.mapper & 0xf0);
+        image.extend_from_slice(&[ 0; 8 ]);
+        image.extend_from_slice(&self.prg);
+        image.extend_from_slice(&self.chr);
+        image
+    }
+
+    pub fn build(&self) -> Rom {
+        Rom::load(&mut &self.to_ines()[..]).unwrap()
+    }
+}
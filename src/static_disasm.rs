@@ -0,0 +1,472 @@
+//! Whole-ROM static disassembly.
+//!
+//! Code is found by recursive descent: starting from the reset, NMI and IRQ vectors, every branch,
+//! JMP and JSR target is followed through the mapper's PRG banks. Whatever isn't reached is treated
+//! as data. The result is written as ca65 assembly, together with an ld65 linker configuration that
+//! puts each bank back where it came from, so that assembling and linking it reproduces the PRG-ROM
+//! image byte for byte.
+
+use disasm::{AddressingMode, Disassembler, Instruction, Mnemonic};
+use mapper::{self, PrgBank};
+use mem::{self, Mem};
+use rom::Rom;
+use symbols::{SymbolAddr, SymbolTable};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, Write};
+
+/// The addresses of the NMI, reset and IRQ vectors, and the labels given to their handlers.
+const VECTORS: [(u16, &'static str); 3] = [(0xfffa, "NMI"), (0xfffc, "RESET"), (0xfffe, "IRQ")];
+
+/// The number of data bytes written per `.byte` line.
+const BYTES_PER_LINE: usize = 16;
+
+/// What a byte of PRG-ROM was found to be.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Mark {
+    Data,
+    Opcode,
+    Operand,
+}
+
+/// Serves the bytes of one PRG bank to the disassembler at the address the bank is mapped at.
+struct BankMem<'a> {
+    prg: &'a [u8],
+    bank: PrgBank,
+}
+
+impl<'a> Mem for BankMem<'a> {
+    fn loadb(&mut self, addr: u16) -> u8 {
+        let index = addr.wrapping_sub(self.bank.addr) as usize;
+        if index < self.bank.size { self.prg[self.bank.offset + index] } else { 0 }
+    }
+    fn storeb(&mut self, _: u16, _: u8) {}
+}
+
+pub struct StaticDisassembly<'a> {
+    rom: &'a Rom,
+    pub banks: Vec<PrgBank>,
+    /// One mark per byte of PRG-ROM.
+    pub marks: Vec<Mark>,
+    /// PRG-ROM offsets that are the target of a jump, branch or absolute data reference.
+    referenced: HashSet<usize>,
+    /// Names for offsets that get something better than a generated label.
+    names: HashMap<usize, String>,
+}
+
+impl<'a> StaticDisassembly<'a> {
+    /// Analyzes the ROM, or returns None if its mapper isn't supported.
+    pub fn new(rom: &'a Rom) -> Option<StaticDisassembly<'a>> {
+        let banks = match mapper::prg_banks(rom) {
+            Some(banks) => banks,
+            None => return None,
+        };
+
+        let mut disassembly = StaticDisassembly {
+            rom: rom,
+            banks: banks,
+            marks: vec![Mark::Data; rom.prg.len()],
+            referenced: HashSet::new(),
+            names: HashMap::new(),
+        };
+
+        let mut entry_points = vec![];
+        for &(vector, name) in VECTORS.iter() {
+            let target = match disassembly.loadw(vector) {
+                Some(target) => target,
+                None => continue,
+            };
+            if let Some((bank, offset)) = disassembly.locate(disassembly.last_bank(), target) {
+                disassembly.referenced.insert(offset);
+                disassembly.names.entry(offset).or_insert(name.to_owned());
+                entry_points.push((bank, disassembly.address_of(bank, offset)));
+            }
+        }
+        disassembly.trace(entry_points);
+
+        Some(disassembly)
+    }
+
+    //
+    // Address translation
+    //
+
+    fn last_bank(&self) -> usize {
+        self.banks.len().saturating_sub(1)
+    }
+
+    /// Finds the bank and PRG-ROM offset that a jump from `from` to `addr` lands in. Fixed banks
+    /// win; otherwise an address in a switchable window is assumed to stay in the current bank. If
+    /// that doesn't settle it, the address can't be resolved statically.
+    fn locate(&self, from: usize, addr: u16) -> Option<(usize, usize)> {
+        if addr < 0x8000 || self.banks.len() == 0 {
+            return None;
+        }
+
+        // A lone bank is mirrored across the whole cartridge space.
+        if self.banks.len() == 1 {
+            let bank = &self.banks[0];
+            return Some((0, bank.offset + (addr as usize - 0x8000) % bank.size));
+        }
+
+        let index = if let Some(index) = self.banks.iter().position(|b| b.fixed && b.covers(addr)) {
+            index
+        } else if self.banks[from].covers(addr) {
+            from
+        } else {
+            let mut candidates = (0..self.banks.len()).filter(|&i| self.banks[i].covers(addr));
+            match (candidates.next(), candidates.next()) {
+                (Some(index), None) => index,
+                _ => return None,
+            }
+        };
+
+        let bank = &self.banks[index];
+        Some((index, bank.offset + (addr - bank.addr) as usize))
+    }
+
+    /// Returns the CPU address of a PRG-ROM offset within its bank.
+    fn address_of(&self, bank: usize, offset: usize) -> u16 {
+        let bank = &self.banks[bank];
+        bank.addr.wrapping_add((offset - bank.offset) as u16)
+    }
+
+    fn bank_of(&self, offset: usize) -> usize {
+        self.banks.iter().position(|bank| offset >= bank.offset && offset < bank.offset + bank.size)
+                         .unwrap()
+    }
+
+    fn loadw(&self, addr: u16) -> Option<u16> {
+        let from = self.last_bank();
+        match (self.locate(from, addr), self.locate(from, addr.wrapping_add(1))) {
+            (Some((_, lo)), Some((_, hi))) => {
+                Some(self.rom.prg[lo] as u16 | (self.rom.prg[hi] as u16) << 8)
+            }
+            _ => None,
+        }
+    }
+
+    fn decode(&self, bank: usize, addr: u16) -> Instruction {
+        let mut mem = BankMem { prg: &self.rom.prg, bank: self.banks[bank] };
+        Disassembler { pc: addr, mem: &mut mem }.disassemble()
+    }
+
+    //
+    // Recursive descent
+    //
+
+    /// Follows the flow of control from each (bank, address) entry point, marking the
+    /// instructions that are reached.
+    fn trace(&mut self, mut pending: Vec<(usize, u16)>) {
+        while let Some((bank, mut addr)) = pending.pop() {
+            loop {
+                let offset = match self.locate(bank, addr) {
+                    Some((found, offset)) if found == bank => offset,
+                    _ => break,
+                };
+                if self.marks[offset] != Mark::Data {
+                    // Either we've been here already, or this is the middle of an instruction.
+                    break;
+                }
+
+                // Unofficial opcodes are far more likely to be data than code, and their encodings
+                // aren't unique, so they would not reassemble to the same bytes anyway.
+                let instruction = self.decode(bank, addr);
+                let len = instruction.len as usize;
+                let end = self.banks[bank].offset + self.banks[bank].size;
+                if !instruction.is_official() || offset + len > end ||
+                        self.marks[offset..offset + len].iter().any(|&mark| mark != Mark::Data) {
+                    break;
+                }
+
+                self.marks[offset] = Mark::Opcode;
+                for mark in &mut self.marks[offset + 1..offset + len] {
+                    *mark = Mark::Operand;
+                }
+
+                if let Some(target) = instruction.target {
+                    if let Some((target_bank, target_offset)) = self.locate(bank, target) {
+                        self.referenced.insert(target_offset);
+                        pending.push((target_bank, self.address_of(target_bank, target_offset)));
+                    }
+                } else if is_absolute(instruction.mode) {
+                    if let Some((_, target_offset)) = self.locate(bank, instruction.operand) {
+                        self.referenced.insert(target_offset);
+                    }
+                }
+
+                match instruction.mnemonic {
+                    Mnemonic::Jmp | Mnemonic::Rts | Mnemonic::Rti | Mnemonic::Brk => break,
+                    _ => addr = instruction.next_addr(),
+                }
+            }
+        }
+    }
+
+    //
+    // Labels
+    //
+
+    /// Names labels after symbols. PRG-ROM symbols apply to their offset; symbols given as plain
+    /// CPU addresses apply to fixed banks only. Names that ca65 wouldn't accept, or that are
+    /// already taken by another label or a register, are ignored.
+    pub fn apply_symbols(&mut self, symbols: &SymbolTable) {
+        let mut owners: HashMap<String, usize> = HashMap::new();
+        let registers: HashSet<&str> = (0x2000..0x4020).filter_map(mem::register_name).collect();
+        for (&offset, name) in &self.names {
+            owners.insert(name.clone(), offset);
+        }
+
+        for (index, bank) in self.banks.iter().enumerate() {
+            for offset in bank.offset..bank.offset + bank.size {
+                let addr = self.address_of(index, offset);
+                let name = match symbols.get(SymbolAddr::Prg(offset)) {
+                    Some(name) => name,
+                    None if bank.fixed => match symbols.get(SymbolAddr::Cpu(addr)) {
+                        Some(name) => name,
+                        None => continue,
+                    },
+                    None => continue,
+                };
+                let taken = owners.get(name).map_or(false, |&owner| owner != offset);
+                if taken || registers.contains(name) || !is_identifier(name) {
+                    continue;
+                }
+
+                if let Some(old) = self.names.insert(offset, name.to_owned()) {
+                    owners.remove(&old);
+                }
+                owners.insert(name.to_owned(), offset);
+                self.referenced.insert(offset);
+            }
+        }
+    }
+
+    /// Returns the label for a PRG-ROM offset, if one is emitted there. References into the middle
+    /// of an instruction don't get a label and are written as plain addresses instead.
+    pub fn label(&self, offset: usize) -> Option<String> {
+        if !self.referenced.contains(&offset) || self.marks[offset] == Mark::Operand {
+            return None;
+        }
+        if let Some(name) = self.names.get(&offset) {
+            return Some(name.clone());
+        }
+
+        let bank = self.bank_of(offset);
+        let addr = self.address_of(bank, offset);
+        if self.banks[bank].fixed {
+            Some(format!("L{:04X}", addr))
+        } else {
+            Some(format!("B{:02}_{:04X}", bank, addr))
+        }
+    }
+
+    /// Returns the label for an address referenced from `bank`, if it has one. A mirror of a
+    /// labeled address doesn't count, since the label's value would be different.
+    fn label_at(&self, bank: usize, addr: u16) -> Option<String> {
+        match self.locate(bank, addr) {
+            Some((found, offset)) if self.address_of(found, offset) == addr => self.label(offset),
+            _ => None,
+        }
+    }
+
+    /// Formats an address operand as a label or register name if possible. ca65 would assemble a
+    /// small absolute address as zero page, so those are forced to absolute.
+    fn address_operand(&self, bank: usize, addr: u16) -> String {
+        match self.label_at(bank, addr) {
+            Some(label) => label,
+            None if mem::register_name(addr).is_some() => {
+                mem::register_name(addr).unwrap().to_owned()
+            }
+            None if addr < 0x100 => format!("a:${:04X}", addr),
+            None => format!("${:04X}", addr),
+        }
+    }
+
+    fn instruction_text(&self, bank: usize, instruction: &Instruction) -> String {
+        let name = instruction.mnemonic.name();
+        let operand = instruction.operand;
+        match instruction.mode {
+            AddressingMode::Implied => name.to_owned(),
+            AddressingMode::Accumulator => format!("{} A", name),
+            AddressingMode::Absolute => {
+                format!("{} {}", name, self.address_operand(bank, operand))
+            }
+            AddressingMode::AbsoluteX => {
+                format!("{} {},X", name, self.address_operand(bank, operand))
+            }
+            AddressingMode::AbsoluteY => {
+                format!("{} {},Y", name, self.address_operand(bank, operand))
+            }
+            AddressingMode::Indirect => {
+                let pointer = self.label_at(bank, operand).unwrap_or(format!("${:04X}", operand));
+                format!("{} ({})", name, pointer)
+            }
+            AddressingMode::Relative => {
+                let target = instruction.target.unwrap();
+                match self.label_at(bank, target) {
+                    Some(label) => format!("{} {}", name, label),
+                    None => format!("{} *{:+}", name, operand as u8 as i8 as i32 + 2),
+                }
+            }
+            // The remaining modes print the same way as the disassembler's own output.
+            _ => instruction.to_string(),
+        }
+    }
+
+    //
+    // Output
+    //
+
+    /// Returns the number of PRG-ROM bytes identified as code.
+    pub fn code_size(&self) -> usize {
+        self.marks.iter().filter(|&&mark| mark != Mark::Data).count()
+    }
+
+    /// Writes the ca65 source. Each bank goes in its own `BANKnn` segment.
+    pub fn write_asm(&self, w: &mut Write, config_name: &str) -> io::Result<()> {
+        try!(writeln!(w, "; Generated by nesdisasm. To rebuild the PRG-ROM image:"));
+        try!(writeln!(w, ";"));
+        try!(writeln!(w, ";     ca65 <this file> -o prg.o && ld65 -C {} -o prg.bin prg.o",
+                      config_name));
+        try!(writeln!(w));
+        try!(writeln!(w, ".setcpu \"6502\""));
+
+        let registers = self.registers_used();
+        if !registers.is_empty() {
+            try!(writeln!(w));
+            for (addr, name) in registers {
+                try!(writeln!(w, "{:<16}= ${:04X}", name, addr));
+            }
+        }
+
+        for (index, bank) in self.banks.iter().enumerate() {
+            try!(writeln!(w));
+            try!(writeln!(w, "; PRG-ROM ${:05X}-${:05X}, mapped at ${:04X}{}",
+                          bank.offset,
+                          bank.offset + bank.size - 1,
+                          bank.addr,
+                          if bank.fixed { "" } else { " (switchable)" }));
+            try!(writeln!(w, ".segment \"BANK{:02}\"", index));
+            try!(self.write_bank(w, index));
+        }
+        Ok(())
+    }
+
+    /// Returns the hardware registers that instructions refer to by name, which need equates.
+    fn registers_used(&self) -> BTreeMap<u16, &'static str> {
+        let mut registers = BTreeMap::new();
+        for (bank, prg_bank) in self.banks.iter().enumerate() {
+            for offset in prg_bank.offset..prg_bank.offset + prg_bank.size {
+                if self.marks[offset] != Mark::Opcode {
+                    continue;
+                }
+                let instruction = self.decode(bank, self.address_of(bank, offset));
+                let addr = instruction.operand;
+                match instruction.mode {
+                    AddressingMode::Absolute | AddressingMode::AbsoluteX |
+                    AddressingMode::AbsoluteY if self.label_at(bank, addr).is_none() => {
+                        if let Some(name) = mem::register_name(addr) {
+                            registers.insert(addr, name);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        registers
+    }
+
+    fn write_bank(&self, w: &mut Write, bank: usize) -> io::Result<()> {
+        let start = self.banks[bank].offset;
+        let end = start + self.banks[bank].size;
+        let vectors = self.locate(self.last_bank(), VECTORS[0].0).map(|(_, offset)| offset);
+
+        let mut offset = start;
+        while offset < end {
+            if let Some(label) = self.label(offset) {
+                try!(writeln!(w, "{}:", label));
+            }
+
+            let addr = self.address_of(bank, offset);
+            if self.marks[offset] == Mark::Opcode {
+                let instruction = self.decode(bank, addr);
+                try!(writeln!(w,
+                              "        {:<24}; ${:04X}",
+                              self.instruction_text(bank, &instruction),
+                              addr));
+                offset += instruction.len as usize;
+                continue;
+            }
+
+            if Some(offset) == vectors && self.is_plain_data(offset, 6) {
+                let targets: Vec<String> = VECTORS.iter().map(|&(vector, _)| {
+                    let target = self.loadw(vector).unwrap();
+                    self.label_at(bank, target).unwrap_or(format!("${:04X}", target))
+                }).collect();
+                try!(writeln!(w, "        .addr {}", targets.join(", ")));
+                offset += 6;
+                continue;
+            }
+
+            // A run of data stops at the next label, the next instruction or the vectors.
+            let mut run = 1;
+            while run < BYTES_PER_LINE && offset + run < end && Some(offset + run) != vectors &&
+                    self.marks[offset + run] == Mark::Data && self.label(offset + run).is_none() {
+                run += 1;
+            }
+            let bytes: Vec<String> = self.rom.prg[offset..offset + run]
+                .iter()
+                .map(|b| format!("${:02X}", b))
+                .collect();
+            try!(writeln!(w, "        .byte {}", bytes.join(",")));
+            offset += run;
+        }
+        Ok(())
+    }
+
+    /// Returns true if the bytes are data and, apart from the first, carry no label.
+    fn is_plain_data(&self, offset: usize, len: usize) -> bool {
+        offset + len <= self.marks.len() &&
+            (offset..offset + len).all(|i| self.marks[i] == Mark::Data) &&
+            (offset + 1..offset + len).all(|i| self.label(i).is_none())
+    }
+
+    /// Writes an ld65 configuration that lays the bank segments out back to back.
+    pub fn write_linker_config(&self, w: &mut Write) -> io::Result<()> {
+        try!(writeln!(w, "MEMORY {{"));
+        for (index, bank) in self.banks.iter().enumerate() {
+            try!(writeln!(w,
+                          "    PRG{:02}: start = ${:04X}, size = ${:04X}, file = %O, fill = yes;",
+                          index,
+                          bank.addr,
+                          bank.size));
+        }
+        try!(writeln!(w, "}}"));
+        try!(writeln!(w, "SEGMENTS {{"));
+        for index in 0..self.banks.len() {
+            try!(writeln!(w, "    BANK{:02}: load = PRG{:02}, type = ro;", index, index));
+        }
+        writeln!(w, "}}")
+    }
+}
+
+/// Returns true for the modes whose operand is a full 16-bit address.
+fn is_absolute(mode: AddressingMode) -> bool {
+    match mode {
+        AddressingMode::Absolute | AddressingMode::AbsoluteX | AddressingMode::AbsoluteY |
+        AddressingMode::Indirect => true,
+        _ => false,
+    }
+}
+
+/// Returns true if ca65 would accept the name as a label. Single letters are excluded because A, X
+/// and Y are register names.
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    name.len() > 1 && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
@@ -0,0 +1,358 @@
+//! Symbol files for debugging output.
+//!
+//! Three formats are understood: FCEUX `.nl` files, Mesen `.mlb` label files and ca65 `.dbg` debug
+//! info. Labels in PRG-ROM are keyed by their offset within PRG-ROM rather than by CPU address, so
+//! that the same address can have a different name in each bank; everything else is keyed by CPU
+//! address.
+
+use mapper;
+use mem::{self, Mem};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+/// The size of the iNES header that ld65 output offsets include when linking straight to a `.nes`.
+const INES_HEADER_SIZE: usize = 16;
+
+/// The bank size FCEUX uses to number its `.nl` files.
+const NL_BANK_SIZE: usize = 16384;
+
+#[derive(Debug)]
+pub enum SymbolLoadError {
+    /// IO error while reading the symbol file
+    IoError(io::Error),
+    /// The line with this (1-based) number couldn't be parsed
+    FormatError(usize),
+    /// The file extension isn't one of .nl, .mlb or .dbg
+    UnknownFormat,
+}
+
+impl From<io::Error> for SymbolLoadError {
+    fn from(err: io::Error) -> Self {
+        SymbolLoadError::IoError(err)
+    }
+}
+
+impl fmt::Display for SymbolLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            SymbolLoadError::IoError(ref err) => write!(f, "{}", err),
+            SymbolLoadError::FormatError(line) => write!(f, "can't parse line {}", line),
+            SymbolLoadError::UnknownFormat => write!(f, "unknown symbol file format"),
+        }
+    }
+}
+
+/// Where a symbol lives.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum SymbolAddr {
+    /// A CPU address outside of PRG-ROM: RAM, registers or cartridge RAM
+    Cpu(u16),
+    /// An offset within PRG-ROM
+    Prg(usize),
+}
+
+/// Which parts of PRG-ROM are mapped into the CPU's cartridge space, in 8K windows starting at
+/// $8000. None of the supported mappers switch anything smaller than 8K.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct PrgMap {
+    windows: [Option<usize>; 4],
+}
+
+impl PrgMap {
+    pub fn capture<M: Mem>(mem: &mut M) -> PrgMap {
+        let mut map = PrgMap::default();
+        for (i, window) in map.windows.iter_mut().enumerate() {
+            *window = mem.prg_offset(0x8000 + (i as u16) * 0x2000);
+        }
+        map
+    }
+
+    /// Returns the PRG-ROM offset an address was mapped to.
+    pub fn offset(&self, addr: u16) -> Option<usize> {
+        if addr < 0x8000 {
+            return None;
+        }
+        self.windows[(addr as usize - 0x8000) >> 13].map(|base| base + (addr as usize & 0x1fff))
+    }
+}
+
+pub struct SymbolTable {
+    names: HashMap<SymbolAddr, String>,
+    /// The iNES mapper number of the loaded ROM, for naming its registers.
+    pub mapper: Option<u8>,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable { names: HashMap::new(), mapper: None }
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Whether any symbols are keyed by PRG-ROM offset.
+    pub fn has_prg_names(&self) -> bool {
+        self.names.keys().any(|addr| match *addr {
+            SymbolAddr::Prg(_) => true,
+            SymbolAddr::Cpu(_) => false,
+        })
+    }
+
+    /// Adds a symbol, replacing any earlier name for the same place.
+    pub fn insert(&mut self, addr: SymbolAddr, name: String) {
+        self.names.insert(addr, name);
+    }
+
+    pub fn get(&self, addr: SymbolAddr) -> Option<&str> {
+        self.names.get(&addr).map(|name| &name[..])
+    }
+
+    /// Returns the name of a CPU address, given what was mapped into PRG space at the time.
+    /// Addresses in PRG space also match symbols that were given as plain CPU addresses.
+    pub fn name(&self, addr: u16, map: &PrgMap) -> Option<&str> {
+        map.offset(addr)
+           .and_then(|offset| self.get(SymbolAddr::Prg(offset)))
+           .or_else(|| self.get(SymbolAddr::Cpu(addr)))
+    }
+
+    /// Returns the name of an instruction's operand address. Symbols win over the names of
+    /// hardware registers. Mapper registers share their addresses with PRG-ROM, so they are only
+    /// named when the instruction writes to them.
+    pub fn operand_name(&self, addr: u16, map: &PrgMap, write: bool) -> Option<&str> {
+        self.name(addr, map).or_else(|| mem::register_name(addr)).or_else(|| {
+            match self.mapper {
+                Some(mapper) if write => mapper::register_name(mapper, addr),
+                _ => None,
+            }
+        })
+    }
+
+    /// Returns the name of an address, or the address in hex if it has none.
+    pub fn format_addr(&self, addr: u16, map: &PrgMap) -> String {
+        match self.name(addr, map) {
+            Some(name) => name.to_owned(),
+            None => format!("{:04X}", addr),
+        }
+    }
+
+    //
+    // Loading
+    //
+
+    /// Loads a symbol file, picking the format from its extension. Returns the number of symbols
+    /// read.
+    pub fn load(&mut self, path: &Path) -> Result<usize, SymbolLoadError> {
+        let mut file = BufReader::new(try!(File::open(path)));
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("nl") => {
+                // FCEUX names its files `game.nes.ram.nl` for RAM and `game.nes.<bank>.nl`, with
+                // the bank number in hex, for each 16K PRG bank.
+                let bank = path.file_stem()
+                               .map(Path::new)
+                               .and_then(|stem| stem.extension())
+                               .and_then(|ext| ext.to_str())
+                               .and_then(|ext| usize::from_str_radix(ext, 16).ok());
+                self.load_nl(&mut file, bank)
+            }
+            Some("mlb") => self.load_mlb(&mut file),
+            Some("dbg") => self.load_dbg(&mut file),
+            _ => Err(SymbolLoadError::UnknownFormat),
+        }
+    }
+
+    /// Loads an FCEUX `.nl` file. Lines look like `$C000#Reset#comment`, or `$0300/20#buffer#`
+    /// for arrays. With a bank, addresses in PRG space are taken to be in that 16K bank.
+    pub fn load_nl(&mut self, r: &mut BufRead, bank: Option<usize>)
+                   -> Result<usize, SymbolLoadError> {
+        let mut count = 0;
+        for (index, line) in r.lines().enumerate() {
+            let line = try!(line);
+            // Other lines continue multi-line comments.
+            if !line.starts_with('$') {
+                continue;
+            }
+
+            let mut fields = line[1..].splitn(3, '#');
+            let addr = fields.next().unwrap().split('/').next().unwrap();
+            let addr = try!(parse_hex(addr, index));
+            let name = match fields.next() {
+                Some(name) if name.len() > 0 => name,
+                _ => continue,
+            };
+
+            let key = match bank {
+                Some(bank) if addr >= 0x8000 => {
+                    SymbolAddr::Prg(bank * NL_BANK_SIZE + (addr & 0x3fff))
+                }
+                _ => SymbolAddr::Cpu(addr as u16),
+            };
+            self.insert(key, name.to_owned());
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Loads a Mesen `.mlb` file. Lines look like `P:0C000:Reset:comment`, where the letter is the
+    /// memory type and the address is relative to it. Mesen 2's long memory type names are
+    /// accepted too. Work and save RAM labels past the $6000-$7FFF window are skipped.
+    pub fn load_mlb(&mut self, r: &mut BufRead) -> Result<usize, SymbolLoadError> {
+        let mut count = 0;
+        for (index, line) in r.lines().enumerate() {
+            let line = try!(line);
+            let line = line.trim();
+            if line.len() == 0 {
+                continue;
+            }
+
+            let mut fields = line.splitn(4, ':');
+            let (kind, addr, name) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(kind), Some(addr), Some(name)) => (kind, addr, name),
+                _ => return Err(SymbolLoadError::FormatError(index + 1)),
+            };
+            if name.len() == 0 {
+                // A comment without a label.
+                continue;
+            }
+
+            let addr = try!(parse_hex(addr.split('-').next().unwrap(), index));
+            let key = match kind {
+                "P" | "NesPrgRom" => Some(SymbolAddr::Prg(addr)),
+                "R" | "NesInternalRam" => cpu_addr(0x0000, 0x0800, addr),
+                "W" | "S" | "NesWorkRam" | "NesSaveRam" => cpu_addr(0x6000, 0x2000, addr),
+                "G" | "NesMemory" | "Register" => cpu_addr(0x0000, 0x10000, addr),
+                _ => None,
+            };
+            // CHR, palette and other PPU-side labels never show up in CPU traces, and neither does
+            // RAM that isn't mapped into the CPU's window for it.
+            let key = match key {
+                Some(key) => key,
+                None => continue,
+            };
+            self.insert(key, name.to_owned());
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Loads ca65 debug info, as written by `ld65 --dbgfile`. Only labels are used; equates are
+    /// as often constants as they are addresses.
+    pub fn load_dbg(&mut self, r: &mut BufRead) -> Result<usize, SymbolLoadError> {
+        // The segment table comes before the symbols, but that isn't guaranteed, so symbols are
+        // resolved once the whole file has been read.
+        let mut segments = HashMap::new();
+        let mut labels = vec![];
+        for (index, line) in r.lines().enumerate() {
+            let line = try!(line);
+            let (kind, rest) = match line.find(char::is_whitespace) {
+                Some(space) => (&line[..space], &line[space..]),
+                None => continue,
+            };
+            let attrs = parse_dbg_attrs(rest.trim());
+            let attr = |key: &str| {
+                attrs.iter().find(|&&(ref k, _)| k == key).map(|&(_, ref v)| &v[..])
+            };
+
+            match kind {
+                "seg" => {
+                    let id = try!(parse_dbg_number(attr("id"), index));
+                    let start = try!(parse_dbg_number(attr("start"), index));
+                    // Segments that aren't written to the output file (RAM) have no offset.
+                    let file_offset = match attr("ooffs") {
+                        Some(offs) => {
+                            let offs = try!(parse_dbg_number(Some(offs), index));
+                            let header = match attr("oname") {
+                                Some(name) if name.to_lowercase().ends_with(".nes") => {
+                                    INES_HEADER_SIZE
+                                }
+                                _ => 0,
+                            };
+                            offs.checked_sub(header)
+                        }
+                        None => None,
+                    };
+                    segments.insert(id, (start, file_offset));
+                }
+                "sym" if attr("type") == Some("lab") => {
+                    let name = match attr("name") {
+                        Some(name) => name.to_owned(),
+                        None => return Err(SymbolLoadError::FormatError(index + 1)),
+                    };
+                    let val = try!(parse_dbg_number(attr("val"), index));
+                    let seg = match attr("seg") {
+                        Some(seg) => Some(try!(parse_dbg_number(Some(seg), index))),
+                        None => None,
+                    };
+                    labels.push((name, val, seg));
+                }
+                _ => {}
+            }
+        }
+
+        let count = labels.len();
+        for (name, val, seg) in labels {
+            let prg_offset = seg.and_then(|seg| segments.get(&seg)).and_then(|&(start, offset)| {
+                offset.and_then(|offset| val.checked_sub(start).map(|delta| offset + delta))
+            });
+            let key = match prg_offset {
+                Some(offset) if val >= 0x8000 => SymbolAddr::Prg(offset),
+                _ => SymbolAddr::Cpu(val as u16),
+            };
+            self.insert(key, name);
+        }
+        Ok(count)
+    }
+}
+
+/// Maps an offset into a region of `size` bytes that the CPU sees at `base` to a CPU address.
+/// Returns None if the offset falls outside the region.
+fn cpu_addr(base: u16, size: usize, offset: usize) -> Option<SymbolAddr> {
+    if offset >= size {
+        return None;
+    }
+    base.checked_add(offset as u16).map(SymbolAddr::Cpu)
+}
+
+fn parse_hex(text: &str, index: usize) -> Result<usize, SymbolLoadError> {
+    usize::from_str_radix(text.trim(), 16).map_err(|_| SymbolLoadError::FormatError(index + 1))
+}
+
+/// Parses a number in a `.dbg` file, which is decimal or `0x`-prefixed hex.
+fn parse_dbg_number(text: Option<&str>, index: usize) -> Result<usize, SymbolLoadError> {
+    let result = match text {
+        Some(text) if text.starts_with("0x") => usize::from_str_radix(&text[2..], 16).ok(),
+        Some(text) => text.parse().ok(),
+        None => None,
+    };
+    result.ok_or(SymbolLoadError::FormatError(index + 1))
+}
+
+/// Splits the `key=value,key="value"` list of a `.dbg` line. Quoted values may contain commas.
+fn parse_dbg_attrs(text: &str) -> Vec<(String, String)> {
+    let mut attrs = vec![];
+    let mut chars = text.chars().peekable();
+    loop {
+        let key: String = chars.by_ref().take_while(|&c| c != '=').collect();
+        if key.len() == 0 {
+            return attrs;
+        }
+
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            value.extend(chars.by_ref().take_while(|&c| c != '"'));
+            chars.next();   // The comma, if any.
+        } else {
+            value.extend(chars.by_ref().take_while(|&c| c != ','));
+        }
+        attrs.push((key, value));
+    }
+}
@@ -0,0 +1,228 @@
+//! A ring buffer of the most recently executed instructions.
+//!
+//! Recording is left on all the time, so it only copies the instruction's bytes and the registers;
+//! the instructions are decoded when the buffer is written out, which happens when something goes
+//! wrong: a panic, a KIL opcode or a breakpoint. Instructions can also be streamed to a file as
+//! they execute, optionally only within some PC ranges. Streamed instructions are decoded as they
+//! run, so they also show where their memory operand pointed.
+
+use cpu::{Cpu, Cycles, Regs};
+use disasm::{Disassembler, EffectiveAddress};
+use mem::{Mem, MemMap};
+use symbols::{PrgMap, SymbolTable};
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::iter::Chain;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::slice::Iter;
+use std::thread;
+
+/// One executed instruction, captured just before it ran.
+#[derive(Copy, Clone)]
+pub struct TraceEntry {
+    pub pc: u16,
+    /// The bytes at PC. Only as many as the instruction is long are meaningful.
+    pub bytes: [u8; 3],
+    pub regs: Regs,
+    pub cy: Cycles,
+    pub scanline: u16,
+    /// What was mapped into PRG space, for looking up symbols. Left empty when there are no
+    /// PRG-ROM symbols to look up.
+    pub prg_map: PrgMap,
+    /// Where the memory operand pointed, and what was there. Only found for streamed entries.
+    pub effective: Option<EffectiveAddress>,
+}
+
+impl TraceEntry {
+    /// Captures the instruction the CPU is about to execute. The PRG mapping is only looked up if
+    /// `prg_map` is set.
+    pub fn capture(cpu: &mut Cpu<MemMap>, prg_map: bool) -> TraceEntry {
+        let pc = cpu.regs.pc;
+        let (scanline, _) = cpu.mem.ppu.position(cpu.cy);
+        TraceEntry {
+            pc: pc,
+            bytes: [
+                cpu.mem.peekb(pc).unwrap_or(0xff),
+                cpu.mem.peekb(pc.wrapping_add(1)).unwrap_or(0xff),
+                cpu.mem.peekb(pc.wrapping_add(2)).unwrap_or(0xff),
+            ],
+            regs: cpu.regs,
+            cy: cpu.cy,
+            scanline: scanline,
+            prg_map: if prg_map { PrgMap::capture(&mut cpu.mem) } else { PrgMap::default() },
+            effective: None,
+        }
+    }
+
+    /// Looks up where the instruction's memory operand points. This must happen before the
+    /// instruction runs.
+    pub fn capture_effective_address(&mut self, cpu: &mut Cpu<MemMap>) {
+        // Decode the bytes already peeked rather than loading them again: PC can be in I/O space,
+        // where a load has side effects.
+        let instruction = Disassembler { pc: self.pc, mem: &mut EntryMem(self) }.disassemble();
+        self.effective = instruction.effective_address(cpu.regs.x, cpu.regs.y, &mut cpu.mem);
+    }
+
+    /// Formats the entry, replacing addresses that have symbols with their names.
+    pub fn format(&self, symbols: &SymbolTable) -> String {
+        let mut mem = EntryMem(self);
+        let instruction = Disassembler { pc: self.pc, mem: &mut mem }.disassemble();
+        let write = instruction.writes_memory();
+        let name = |addr| symbols.operand_name(addr, &self.prg_map, write).map(str::to_owned);
+        let mut text = instruction.format_with(&name);
+        if let Some(ref effective) = self.effective {
+            text.push_str(&instruction.format_effective(effective, &name));
+        }
+
+        let bytes: Vec<String> = self.bytes[..instruction.len as usize]
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect();
+        format!("{:4}  {:<9} {:<32} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{} SL:{}",
+                symbols.format_addr(self.pc, &self.prg_map),
+                bytes.join(" "),
+                text,
+                self.regs.a,
+                self.regs.x,
+                self.regs.y,
+                self.regs.flags,
+                self.regs.s,
+                self.cy,
+                self.scanline)
+    }
+}
+
+/// Serves the bytes of an entry to the disassembler.
+struct EntryMem<'a>(&'a TraceEntry);
+
+impl<'a> Mem for EntryMem<'a> {
+    fn loadb(&mut self, addr: u16) -> u8 {
+        let offset = addr.wrapping_sub(self.0.pc) as usize;
+        if offset < self.0.bytes.len() { self.0.bytes[offset] } else { 0 }
+    }
+    fn storeb(&mut self, _: u16, _: u8) {}
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.format(&SymbolTable::new()))
+    }
+}
+
+/// The number of instructions kept by default: a little over one frame's worth.
+pub const DEFAULT_CAPACITY: usize = 16384;
+
+pub struct Tracer {
+    entries: Vec<TraceEntry>,
+    capacity: usize,
+    /// The slot the next entry goes into once the buffer has filled up, which is also the oldest.
+    next: usize,
+
+    /// Where the buffer is written when it is dumped.
+    pub dump_path: PathBuf,
+    /// PCs at which `record` reports a breakpoint.
+    pub breakpoints: Vec<u16>,
+    /// Names substituted for addresses in the output.
+    symbols: Rc<SymbolTable>,
+    /// Whether `symbols` has names for PRG-ROM offsets, which need each entry's PRG mapping.
+    prg_symbols: bool,
+
+    stream: Option<BufWriter<File>>,
+    /// Inclusive PC ranges to stream. Empty means every instruction.
+    stream_ranges: Vec<(u16, u16)>,
+}
+
+impl Tracer {
+    pub fn new(capacity: usize, dump_path: PathBuf) -> Tracer {
+        assert!(capacity > 0, "trace buffer can't be empty");
+        Tracer {
+            entries: Vec::with_capacity(capacity),
+            capacity: capacity,
+            next: 0,
+            dump_path: dump_path,
+            breakpoints: Vec::new(),
+            symbols: Rc::new(SymbolTable::new()),
+            prg_symbols: false,
+            stream: None,
+            stream_ranges: Vec::new(),
+        }
+    }
+
+    /// Sets the names substituted for addresses in the output.
+    pub fn set_symbols(&mut self, symbols: Rc<SymbolTable>) {
+        self.prg_symbols = symbols.has_prg_names();
+        self.symbols = symbols;
+    }
+
+    /// Adds the instruction the CPU is about to execute to the buffer, and to the stream if it
+    /// passes the filter. Returns true if the instruction is at a breakpoint.
+    pub fn record(&mut self, cpu: &mut Cpu<MemMap>) -> bool {
+        let mut entry = TraceEntry::capture(cpu, self.prg_symbols);
+        if self.stream.is_some() && self.in_stream_ranges(entry.pc) {
+            entry.capture_effective_address(cpu);
+            if let Some(ref mut stream) = self.stream {
+                // A failed write shouldn't take the emulator down with it.
+                let _ = writeln!(stream, "{}", entry.format(&self.symbols));
+            }
+        }
+
+        if self.entries.len() < self.capacity {
+            self.entries.push(entry);
+        } else {
+            self.entries[self.next] = entry;
+        }
+        self.next = (self.next + 1) % self.capacity;
+
+        self.breakpoints.contains(&entry.pc)
+    }
+
+    /// Returns the recorded instructions, oldest first.
+    pub fn entries(&self) -> Chain<Iter<'_, TraceEntry>, Iter<'_, TraceEntry>> {
+        self.entries[self.next..].iter().chain(self.entries[..self.next].iter())
+    }
+
+    /// Starts writing every executed instruction whose PC falls within one of `ranges` to `path`.
+    /// An empty list of ranges streams everything.
+    pub fn stream_to(&mut self, path: &Path, ranges: Vec<(u16, u16)>) -> io::Result<()> {
+        self.stream = Some(BufWriter::new(try!(File::create(path))));
+        self.stream_ranges = ranges;
+        Ok(())
+    }
+
+    pub fn stop_streaming(&mut self) {
+        self.stream = None;
+    }
+
+    fn in_stream_ranges(&self, pc: u16) -> bool {
+        self.stream_ranges.is_empty() ||
+            self.stream_ranges.iter().any(|&(start, end)| pc >= start && pc <= end)
+    }
+
+    /// Writes the buffer to `dump_path`, headed by the reason for the dump.
+    pub fn dump(&mut self, reason: &str) -> io::Result<()> {
+        if let Some(ref mut stream) = self.stream {
+            try!(stream.flush());
+        }
+
+        let mut file = BufWriter::new(try!(File::create(&self.dump_path)));
+        try!(writeln!(file, "; {}", reason));
+        for entry in self.entries() {
+            try!(writeln!(file, "{}", entry.format(&self.symbols)));
+        }
+        file.flush()
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        // The emulator is torn down while a panic unwinds, which is our chance to save the trace.
+        if thread::panicking() {
+            if self.dump("panic").is_ok() {
+                println!("Trace written to {}", self.dump_path.display());
+            }
+        }
+    }
+}
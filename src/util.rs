@@ -85,7 +85,7 @@ impl<'a> Save for &'a mut [u8] {
 
 impl Save for bool {
     fn save(&mut self, fd: &mut File) {
-        fd.write(&[ if *self { 0 } else { 1 } ]).unwrap();
+        fd.write(&[ if *self { 1 } else { 0 } ]).unwrap();
     }
     fn load(&mut self, fd: &mut File) {
         let mut val: [u8; 1] = [ 0 ];
@@ -0,0 +1,413 @@
+//! Runs small programs, assembled inline, through the CPU and the rest of the console.
+
+extern crate nes;
+
+use nes::asm::{assemble, Program};
+use nes::cpu::{Cpu, IrqSource, BREAK_FLAG, CARRY_FLAG, U_FLAG, ZERO_FLAG};
+use nes::disasm::Disassembler;
+use nes::mem::{Mem, MemMap};
+use nes::nestest;
+
+use std::thread;
+
+/// A flat 64K of memory holding an assembled program, for the disassembler to read back.
+struct Image(Vec<u8>);
+
+impl Mem for Image {
+    fn loadb(&mut self, addr: u16) -> u8 { self.0[addr as usize] }
+    fn storeb(&mut self, addr: u16, val: u8) { self.0[addr as usize] = val }
+}
+
+/// Assembles `source` into an NROM cartridge and returns a console that has just been reset.
+fn console(source: &str, cycle_accurate: bool) -> (Program, Cpu<MemMap>) {
+    let program = assemble(source).unwrap();
+    let mut cpu = nestest::console(program.to_nrom().build());
+    cpu.cycle_accurate = cycle_accurate;
+    cpu.reset();
+    (program, cpu)
+}
+
+/// Runs a test on a thread with room for a console. Debug builds assemble the APU's sample buffers
+/// on the stack before boxing them, which overflows the test harness's default stack.
+fn with_console_stack<F: FnOnce() + Send + 'static>(test: F) {
+    thread::Builder::new().stack_size(16 << 20).spawn(test).unwrap().join().unwrap();
+}
+
+/// Steps the CPU alone, with no devices driving the interrupt lines, until PC reaches `label`.
+fn run_to(cpu: &mut Cpu<MemMap>, program: &Program, label: &str) {
+    let addr = program.symbol(label).unwrap();
+    for _ in 0..10000 {
+        if cpu.regs.pc == addr {
+            return;
+        }
+        cpu.step();
+    }
+    panic!("never reached {}", label);
+}
+
+/// Returns how many cycles the next instruction takes.
+fn cycles(cpu: &mut Cpu<MemMap>) -> u64 {
+    let start = cpu.cy;
+    cpu.step();
+    cpu.cy - start
+}
+
+//
+// Assembler
+//
+
+#[test]
+fn every_addressing_mode_round_trips_through_the_disassembler() {
+    let cases = [
+        ("inx", "INX"),
+        ("asl a", "ASL"),
+        ("lsr", "LSR"),
+        ("lda #$12", "LDA #$12"),
+        ("lda #<$1234", "LDA #$34"),
+        ("lda #>$1234", "LDA #$12"),
+        ("lda $12", "LDA $12"),
+        ("lda $12,x", "LDA $12,X"),
+        ("ldx $12,y", "LDX $12,Y"),
+        ("lda $1234", "LDA $1234"),
+        ("lda $1234,x", "LDA $1234,X"),
+        ("lda $1234,y", "LDA $1234,Y"),
+        ("lda a:$12", "LDA $0012"),
+        ("sta a:$12,x", "STA $0012,X"),
+        ("jmp ($1234)", "JMP ($1234)"),
+        ("lda ($12,x)", "LDA ($12,X)"),
+        ("lda ($12),y", "LDA ($12),Y"),
+        ("beq * + 4", "BEQ +2"),
+        ("bne *", "BNE -2"),
+        ("lax ($12),y", "LAX ($12),Y"),
+        ("dcp $1234,x", "DCP $1234,X"),
+        ("axs #$01", "AXS #$01"),
+    ];
+
+    for &(source, expected) in &cases {
+        let program = assemble(&format!(".org $8000\n{}", source)).unwrap();
+        let bytes = &program.chunks[0].1;
+        let mut image = Image(program.image(0, 0x10000, 0));
+        let instruction = Disassembler { pc: 0x8000, mem: &mut image }.disassemble();
+        assert_eq!(instruction.to_string().trim_end(), expected, "{}", source);
+        assert_eq!(instruction.len as usize, bytes.len(), "{}", source);
+    }
+}
+
+#[test]
+fn forward_references_are_resolved_on_the_second_pass() {
+    let program = assemble("
+        .org $8000
+        start:  jmp end
+                lda value       ; not defined yet on the first pass, so absolute
+                lda #<ptr
+                lda #>ptr
+        table:  .byte 1, 'A', \"hi\"
+                .word start, end
+        value = $10
+        ptr = $1234
+        end:    rts
+    ").unwrap();
+
+    assert_eq!(program.symbol("table"), Some(0x800a));
+    assert_eq!(program.symbol("end"), Some(0x8012));
+    assert_eq!(program.chunks, vec![(0x8000, vec![
+        0x4c, 0x12, 0x80,
+        0xad, 0x10, 0x00,
+        0xa9, 0x34,
+        0xa9, 0x12,
+        0x01, 0x41, 0x68, 0x69,
+        0x00, 0x80, 0x12, 0x80,
+        0x60,
+    ])]);
+}
+
+#[test]
+fn the_rom_builder_writes_an_ines_header() {
+    let program = assemble(".org $8000\nrts").unwrap();
+    let image = program.to_nrom().mapper(0x12).vertical_mirroring(true).to_ines();
+    assert_eq!(&image[..8], b"NES\x1a\x02\x01\x21\x10");
+    assert_eq!(image.len(), 16 + 0x8000 + 0x2000);
+    assert_eq!(image[16], 0x60);
+    assert_eq!(image[16 + 0x7fff], 0xff);
+}
+
+#[test]
+fn an_assembled_program_runs_from_an_nrom_image() {
+    with_console_stack(|| {
+        let (program, mut cpu) = console("
+            .org $8000
+            reset:  ldx #0
+            copy:   lda data,x
+                    sta $0200,x
+                    inx
+                    cpx #4
+                    bne copy
+            done:   jmp done
+            data:   .byte $de, $ad, $be, $ef
+
+            .org $fffa
+                    .word reset, reset, reset
+        ", false);
+
+        for _ in 0..100 {
+            nes::step(&mut cpu);
+        }
+        assert_eq!(cpu.regs.pc, program.symbol("done").unwrap());
+        assert_eq!(cpu.regs.x, 4);
+        let copied: Vec<u8> = (0x200..0x204).map(|addr| cpu.mem.loadb(addr)).collect();
+        assert_eq!(copied, vec![0xde, 0xad, 0xbe, 0xef]);
+    });
+}
+
+//
+// CPU
+//
+
+#[test]
+fn unofficial_opcodes() {
+    with_console_stack(|| {
+        let (program, mut cpu) = console("
+            .org $8000
+            reset:  lda #$f0
+                    ldx #$3c
+                    sax $10         ; $10 = A & X
+                    lda #0
+                    lax $10         ; A = X = $30
+            lax_done:
+                    lda #5
+                    sta $11
+                    dcp $11         ; $11 = 4, compare 5 with it
+            dcp_done:
+                    isc $11         ; $11 = 5, A = 5 - 5
+            isc_done:
+                    lda #$ff
+                    ldx #$0f
+                    axs #$01        ; X = (A & X) - 1
+            axs_done:
+                    kil
+
+            .org $fffa
+                    .word reset, reset, reset
+        ", false);
+
+        run_to(&mut cpu, &program, "lax_done");
+        assert_eq!(cpu.mem.loadb(0x10), 0x30);
+        assert_eq!((cpu.regs.a, cpu.regs.x), (0x30, 0x30));
+
+        run_to(&mut cpu, &program, "dcp_done");
+        assert_eq!(cpu.mem.loadb(0x11), 4);
+        assert_eq!(cpu.regs.flags & (CARRY_FLAG | ZERO_FLAG), CARRY_FLAG);
+
+        run_to(&mut cpu, &program, "isc_done");
+        assert_eq!(cpu.mem.loadb(0x11), 5);
+        assert_eq!(cpu.regs.a, 0);
+        assert_eq!(cpu.regs.flags & (CARRY_FLAG | ZERO_FLAG), CARRY_FLAG | ZERO_FLAG);
+
+        run_to(&mut cpu, &program, "axs_done");
+        assert_eq!(cpu.regs.x, 0x0e);
+        assert_eq!(cpu.regs.flags & CARRY_FLAG, CARRY_FLAG);
+
+        cpu.step();
+        assert!(cpu.halted());
+    });
+}
+
+#[test]
+fn page_crossing_and_branch_penalties() {
+    with_console_stack(|| {
+        let source = "
+            .org $8000
+            reset:  ldx #1
+                    ldy #1
+                    lda #$ff
+                    sta $10
+                    lda #$12
+                    sta $11         ; ($10) points at $12ff
+            timed:  lda $1200,x
+                    lda $12ff,x
+                    lda ($10),y
+                    sta $12ff,x
+                    lda #0
+                    bne timed
+                    beq same_page
+            same_page:
+                    jmp cross
+
+            .org $80fd
+            cross:  beq * + 4       ; from $80ff to $8101
+
+            .org $fffa
+                    .word reset, reset, reset
+        ";
+
+        for &cycle_accurate in &[false, true] {
+            let (program, mut cpu) = console(source, cycle_accurate);
+            run_to(&mut cpu, &program, "timed");
+            let timings: Vec<u64> = (0..9).map(|_| cycles(&mut cpu)).collect();
+            assert_eq!(timings, vec![4, 5, 6, 5, 2, 2, 3, 3, 4],
+                       "cycle accurate: {}", cycle_accurate);
+        }
+    });
+}
+
+#[test]
+fn cli_lets_one_more_instruction_run_before_a_pending_irq() {
+    with_console_stack(|| {
+        let (program, mut cpu) = console("
+            .org $8000
+            reset:  ldx #0
+                    cli
+                    inx
+                    inx
+            idle:   jmp idle
+            irq:    stx $00
+            irq_idle:
+                    jmp irq_idle
+
+            .org $fffa
+                    .word reset, reset, irq
+        ", false);
+
+        cpu.assert_irq(IrqSource::Mapper);
+        run_to(&mut cpu, &program, "irq_idle");
+        assert_eq!(cpu.mem.loadb(0x00), 1);
+
+        // The pushed flags have the U bit set and, unlike BRK, the B bit clear.
+        let flags = cpu.mem.loadb(0x100 + cpu.regs.s as u16 + 1);
+        assert_eq!(flags & (BREAK_FLAG | U_FLAG), U_FLAG);
+    });
+}
+
+#[test]
+fn the_irq_line_is_level_triggered() {
+    with_console_stack(|| {
+        let (program, mut cpu) = console("
+            .org $8000
+            reset:  cli
+            idle:   jmp idle
+            irq:    inc $00
+                    rti
+
+            .org $fffa
+                    .word reset, reset, irq
+        ", false);
+
+        run_to(&mut cpu, &program, "idle");
+        cpu.assert_irq(IrqSource::Dmc);
+        for _ in 0..9 {
+            cpu.step();
+        }
+        assert_eq!(cpu.mem.loadb(0x00), 3);
+
+        cpu.ack_irq(IrqSource::Dmc);
+        for _ in 0..9 {
+            cpu.step();
+        }
+        assert_eq!(cpu.mem.loadb(0x00), 3);
+    });
+}
+
+#[test]
+fn the_nmi_line_is_edge_triggered() {
+    with_console_stack(|| {
+        let (program, mut cpu) = console("
+            .org $8000
+            reset:  jmp reset
+            nmi:    inc $00
+                    rti
+
+            .org $fffa
+                    .word nmi, reset, reset
+        ", false);
+
+        run_to(&mut cpu, &program, "reset");
+        cpu.set_nmi(true);
+        for _ in 0..10 {
+            cpu.step();
+        }
+        assert_eq!(cpu.mem.loadb(0x00), 1);
+
+        // Holding the line doesn't take the NMI again; releasing it and asserting it again does.
+        cpu.set_nmi(true);
+        for _ in 0..10 {
+            cpu.step();
+        }
+        assert_eq!(cpu.mem.loadb(0x00), 1);
+
+        cpu.set_nmi(false);
+        cpu.set_nmi(true);
+        for _ in 0..10 {
+            cpu.step();
+        }
+        assert_eq!(cpu.mem.loadb(0x00), 2);
+    });
+}
+
+#[test]
+fn a_halted_cpu_ignores_interrupts_until_reset() {
+    with_console_stack(|| {
+        let (program, mut cpu) = console("
+            .org $8000
+            reset:  cli
+            stop:   kil
+            nmi:    inc $00
+                    rti
+
+            .org $fffa
+                    .word nmi, reset, nmi
+        ", false);
+
+        run_to(&mut cpu, &program, "stop");
+        cpu.step();
+        assert!(cpu.halted());
+
+        // With the I flag clear, neither line gets the CPU to leave the KIL.
+        cpu.set_nmi(true);
+        cpu.assert_irq(IrqSource::Dmc);
+        for _ in 0..10 {
+            cpu.step();
+        }
+        assert!(cpu.halted());
+        assert_eq!(cpu.mem.loadb(0x00), 0);
+        assert_eq!(cpu.regs.pc, program.symbol("stop").unwrap());
+
+        cpu.reset();
+        assert!(!cpu.halted());
+    });
+}
+
+#[test]
+fn oam_dma_alignment_follows_the_write_cycle_in_both_timing_modes() {
+    with_console_stack(|| {
+        // The DMA halts the CPU for a cycle after the write, then waits for a get (even) cycle
+        // before the 512 cycles of copying. BIT zp moves the write by an odd number of cycles.
+        let cases = [
+            ("", "sta $4014", 4),
+            ("", "sta $4014,x", 5),
+            ("bit $00", "sta $4014", 4),
+            ("bit $00", "sta $4014,x", 5),
+        ];
+
+        for &(padding, store, store_cycles) in &cases {
+            let source = format!("
+                .org $8000
+                reset:  ldx #0
+                        {}
+                dma:    {}
+                done:   jmp done
+
+                .org $fffa
+                        .word reset, reset, reset
+            ", padding, store);
+
+            for &cycle_accurate in &[false, true] {
+                let (program, mut cpu) = console(&source, cycle_accurate);
+                run_to(&mut cpu, &program, "dma");
+                let halt = cpu.cy + store_cycles;
+                let expected = store_cycles + 1 + (halt + 1) % 2 + 512;
+                assert_eq!(cycles(&mut cpu), expected,
+                           "{} {}, cycle accurate: {}", padding, store, cycle_accurate);
+            }
+        }
+    });
+}